@@ -0,0 +1,454 @@
+use crate::{
+    connector::{self, HttpClient},
+    settings::{AcmeSetting, ConnectionPoolSetting, UpstreamProtocol, UpstreamTlsSetting},
+};
+use anyhow::Context;
+use hyper::{
+    body::{self, Bytes},
+    header::{self, HeaderValue},
+    Body, HeaderMap, Method, Request,
+};
+use ring::{
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use std::{fmt, time::Duration};
+
+const REPLAY_NONCE: &str = "replay-nonce";
+const POLL_INTERVAL: Duration = Duration::from_millis(if cfg!(test) { 10 } else { 2000 });
+const POLL_ATTEMPTS: u32 = 30;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Order {
+    pub status: String,
+    pub authorizations: Vec<String>,
+    pub finalize: String,
+    pub certificate: Option<String>,
+    pub error: Option<Problem>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Authorization {
+    pub status: String,
+    pub challenges: Vec<Challenge>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Challenge {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub url: String,
+    pub token: String,
+    pub error: Option<Problem>,
+}
+
+/// Error document of RFC 7807 sent by ACME servers
+#[derive(Debug, Deserialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    kind: String,
+    detail: Option<String>,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let Some(detail) = &self.detail {
+            write!(f, ": {}", detail)?;
+        }
+        Ok(())
+    }
+}
+
+/// P-256 key of an ACME account and its public JWK
+struct AccountKey {
+    key: EcdsaKeyPair,
+    jwk: Value,
+}
+
+impl AccountKey {
+    fn new(pkcs8: &[u8]) -> anyhow::Result<Self> {
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8)
+            .map_err(|_| anyhow::anyhow!("Invalid ACME account key"))?;
+        // Uncompressed point, 0x04 followed by the coordinates
+        let point = key.public_key().as_ref();
+        let jwk = json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": base64url(&point[1..33]),
+            "y": base64url(&point[33..]),
+        });
+        Ok(Self { key, jwk })
+    }
+
+    fn key_authorization(&self, token: &str) -> String {
+        // Members of the JWK in lexicographic order, as in RFC 7638
+        let thumbprint =
+            ring::digest::digest(&ring::digest::SHA256, self.jwk.to_string().as_bytes());
+        format!("{}.{}", token, base64url(thumbprint.as_ref()))
+    }
+
+    /// Flattened JWS of RFC 7515, with an empty payload for POST-as-GET,
+    /// identified by `kid` or else by the JWK
+    fn sign(
+        &self,
+        url: &str,
+        nonce: &str,
+        kid: Option<&str>,
+        payload: Option<&Value>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match kid {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = self.jwk.clone(),
+        }
+        let protected = base64url(protected.to_string().as_bytes());
+        let payload = payload.map_or_else(String::new, |payload| {
+            base64url(payload.to_string().as_bytes())
+        });
+        let signature = self
+            .key
+            .sign(
+                &SystemRandom::new(),
+                format!("{}.{}", protected, payload).as_bytes(),
+            )
+            .map_err(|_| anyhow::anyhow!("Could not sign ACME request"))?;
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": base64url(signature.as_ref()),
+        })
+        .to_string()
+        .into_bytes())
+    }
+}
+
+/// ACME account whose requests are signed with its P-256 key
+pub struct Account {
+    client: HttpClient,
+    directory: Directory,
+    key: AccountKey,
+    /// Account url, sent instead of the key once the account exists
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl Account {
+    /// Generates a PKCS#8 account key
+    pub fn generate_key() -> anyhow::Result<Vec<u8>> {
+        let pkcs8 =
+            EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &SystemRandom::new())
+                .map_err(|_| anyhow::anyhow!("Could not generate ACME account key"))?;
+        Ok(pkcs8.as_ref().to_vec())
+    }
+
+    /// Finds or creates the account of `key` on the ACME server
+    pub async fn new(setting: &AcmeSetting, key: &[u8]) -> anyhow::Result<Self> {
+        let tls = UpstreamTlsSetting {
+            ca_file: setting.ca_file.clone(),
+            ..Default::default()
+        };
+        let client = connector::client(
+            &ConnectionPoolSetting::default(),
+            Some(&tls),
+            UpstreamProtocol::Http1,
+        )?;
+
+        let directory = Request::get(&setting.directory_url).body(Body::empty())?;
+        let res = client
+            .request(directory)
+            .await
+            .with_context(|| format!("Could not fetch ACME directory {}", setting.directory_url))?;
+        if !res.status().is_success() {
+            anyhow::bail!(
+                "ACME directory {} returned {}",
+                setting.directory_url,
+                res.status()
+            );
+        }
+        let directory = serde_json::from_slice(&body::to_bytes(res.into_body()).await?)
+            .context("Invalid ACME directory")?;
+
+        let mut account = Self {
+            client,
+            directory,
+            key: AccountKey::new(key)?,
+            kid: None,
+            nonce: None,
+        };
+        let new_account = account.directory.new_account.clone();
+        let payload = json!({
+            "termsOfServiceAgreed": setting.terms_of_service_agreed,
+            "contact": setting.contact,
+        });
+        let (headers, _) = account.post(&new_account, Some(&payload)).await?;
+        account.kid = Some(location(&headers)?);
+        Ok(account)
+    }
+
+    /// Key authorization of a challenge token, as served to the CA
+    pub fn key_authorization(&self, token: &str) -> String {
+        self.key.key_authorization(token)
+    }
+
+    /// Creates an order for `domain`, returning its url
+    pub async fn new_order(&mut self, domain: &str) -> anyhow::Result<(String, Order)> {
+        let new_order = self.directory.new_order.clone();
+        let payload = json!({ "identifiers": [{ "type": "dns", "value": domain }] });
+        let (headers, body) = self.post(&new_order, Some(&payload)).await?;
+        Ok((location(&headers)?, parse(&body)?))
+    }
+
+    pub async fn authorization(&mut self, url: &str) -> anyhow::Result<Authorization> {
+        self.get(url).await
+    }
+
+    /// Tells the CA that the challenge is ready, and waits for the
+    /// authorization to be valid
+    pub async fn validate(&mut self, authorization: &str, challenge: &str) -> anyhow::Result<()> {
+        self.post(challenge, Some(&json!({}))).await?;
+        for _ in 0..POLL_ATTEMPTS {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            let authorization: Authorization = self.get(authorization).await?;
+            match authorization.status.as_str() {
+                "pending" | "processing" => {}
+                "valid" => return Ok(()),
+                status => {
+                    let error = authorization
+                        .challenges
+                        .iter()
+                        .find(|c| c.url == challenge)
+                        .and_then(|c| c.error.as_ref());
+                    match error {
+                        Some(error) => anyhow::bail!("Authorization is {}: {}", status, error),
+                        None => anyhow::bail!("Authorization is {}", status),
+                    }
+                }
+            }
+        }
+        anyhow::bail!("Authorization is still pending")
+    }
+
+    /// Sends the CSR and waits for the certificate to be issued, returning
+    /// its url
+    pub async fn finalize(
+        &mut self,
+        order: &str,
+        finalize: &str,
+        csr: &[u8],
+    ) -> anyhow::Result<String> {
+        self.post(finalize, Some(&json!({ "csr": base64url(csr) })))
+            .await?;
+        for _ in 0..POLL_ATTEMPTS {
+            let order: Order = self.get(order).await?;
+            match (order.status.as_str(), order.certificate) {
+                ("valid", Some(certificate)) => return Ok(certificate),
+                ("pending" | "processing" | "ready" | "valid", _) => {}
+                (status, _) => match order.error {
+                    Some(error) => anyhow::bail!("Order is {}: {}", status, error),
+                    None => anyhow::bail!("Order is {}", status),
+                },
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        anyhow::bail!("Certificate was not issued in time")
+    }
+
+    /// Downloads the PEM certificate chain
+    pub async fn certificate(&mut self, url: &str) -> anyhow::Result<String> {
+        let (_, body) = self.post(url, None).await?;
+        Ok(String::from_utf8(body.to_vec())?)
+    }
+
+    /// POST-as-GET of a JSON resource
+    async fn get<T: DeserializeOwned>(&mut self, url: &str) -> anyhow::Result<T> {
+        let (_, body) = self.post(url, None).await?;
+        parse(&body)
+    }
+
+    /// Signed request, retried once if the server rejects the nonce
+    async fn post(
+        &mut self,
+        url: &str,
+        payload: Option<&Value>,
+    ) -> anyhow::Result<(HeaderMap, Bytes)> {
+        let mut retried = false;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce().await?,
+            };
+            let req = Request::post(url)
+                .header(header::CONTENT_TYPE, "application/jose+json")
+                .body(Body::from(self.key.sign(
+                    url,
+                    &nonce,
+                    self.kid.as_deref(),
+                    payload,
+                )?))?;
+            let res = self
+                .client
+                .request(req)
+                .await
+                .with_context(|| format!("ACME request to {} failed", url))?;
+
+            let status = res.status();
+            let headers = res.headers().clone();
+            self.nonce = nonce_of(&headers);
+            let body = body::to_bytes(res.into_body()).await?;
+            if status.is_success() {
+                return Ok((headers, body));
+            }
+
+            let problem: Problem = parse(&body)
+                .with_context(|| format!("ACME request to {} returned {}", url, status))?;
+            if problem.kind.ends_with(":badNonce") && !retried {
+                retried = true;
+                continue;
+            }
+            anyhow::bail!("ACME request to {} returned {}: {}", url, status, problem);
+        }
+    }
+
+    async fn new_nonce(&mut self) -> anyhow::Result<String> {
+        let req = Request::builder()
+            .method(Method::HEAD)
+            .uri(&self.directory.new_nonce)
+            .body(Body::empty())?;
+        let res = self
+            .client
+            .request(req)
+            .await
+            .context("Could not get ACME nonce")?;
+        nonce_of(res.headers()).context("No nonce in ACME response")
+    }
+}
+
+fn parse<T: DeserializeOwned>(body: &[u8]) -> anyhow::Result<T> {
+    serde_json::from_slice(body).context("Invalid ACME response")
+}
+
+fn location(headers: &HeaderMap) -> anyhow::Result<String> {
+    let location = headers
+        .get(header::LOCATION)
+        .and_then(|location| location.to_str().ok())
+        .context("No location in ACME response")?;
+    Ok(location.to_owned())
+}
+
+fn nonce_of(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(REPLAY_NONCE)
+        .and_then(|nonce| HeaderValue::to_str(nonce).ok())
+        .map(str::to_owned)
+}
+
+pub fn base64url(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+
+    fn key() -> AccountKey {
+        AccountKey::new(&Account::generate_key().unwrap()).unwrap()
+    }
+
+    fn decode(data: &Value) -> Vec<u8> {
+        base64::decode_config(data.as_str().unwrap(), base64::URL_SAFE_NO_PAD).unwrap()
+    }
+
+    /// Checks the ES256 signature of a JWS and returns its protected header
+    /// and payload
+    fn verify(key: &AccountKey, jws: &[u8]) -> (Value, Vec<u8>) {
+        let jws: Value = serde_json::from_slice(jws).unwrap();
+        let signing_input = format!(
+            "{}.{}",
+            jws["protected"].as_str().unwrap(),
+            jws["payload"].as_str().unwrap()
+        );
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, key.key.public_key().as_ref())
+            .verify(signing_input.as_bytes(), &decode(&jws["signature"]))
+            .unwrap();
+        let protected = serde_json::from_slice(&decode(&jws["protected"])).unwrap();
+        (protected, decode(&jws["payload"]))
+    }
+
+    #[test]
+    fn sign_with_jwk() {
+        let key = key();
+        let payload = json!({ "termsOfServiceAgreed": true });
+        let jws = key
+            .sign("https://ca/new-account", "nonce", None, Some(&payload))
+            .unwrap();
+
+        let (protected, signed) = verify(&key, &jws);
+        assert_eq!(protected["alg"], "ES256");
+        assert_eq!(protected["nonce"], "nonce");
+        assert_eq!(protected["url"], "https://ca/new-account");
+        assert_eq!(protected["jwk"], key.jwk);
+        assert!(protected.get("kid").is_none());
+        assert_eq!(serde_json::from_slice::<Value>(&signed).unwrap(), payload);
+    }
+
+    #[test]
+    fn sign_post_as_get_with_kid() {
+        let key = key();
+        let jws = key
+            .sign(
+                "https://ca/order/1",
+                "nonce",
+                Some("https://ca/acct/1"),
+                None,
+            )
+            .unwrap();
+
+        let (protected, signed) = verify(&key, &jws);
+        assert_eq!(protected["kid"], "https://ca/acct/1");
+        assert!(protected.get("jwk").is_none());
+        assert!(signed.is_empty());
+    }
+
+    #[test]
+    fn jwk_of_key() {
+        let key = key();
+        let point = key.key.public_key().as_ref();
+        assert_eq!(point.len(), 65);
+        assert_eq!(key.jwk["kty"], "EC");
+        assert_eq!(key.jwk["crv"], "P-256");
+        assert_eq!(decode(&key.jwk["x"]), &point[1..33]);
+        assert_eq!(decode(&key.jwk["y"]), &point[33..]);
+    }
+
+    #[test]
+    fn key_authorization_of_token() {
+        let key = key();
+        let canonical = format!(
+            r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+            key.jwk["x"].as_str().unwrap(),
+            key.jwk["y"].as_str().unwrap()
+        );
+        let thumbprint = ring::digest::digest(&ring::digest::SHA256, canonical.as_bytes());
+        assert_eq!(
+            key.key_authorization("token"),
+            format!("token.{}", base64url(thumbprint.as_ref()))
+        );
+    }
+
+    #[test]
+    fn invalid_key() {
+        assert!(AccountKey::new(b"not a key").is_err());
+    }
+}
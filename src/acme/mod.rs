@@ -0,0 +1,498 @@
+use crate::{
+    certs::KeyPair,
+    host::normalize_host,
+    http3,
+    pem::to_pem,
+    settings::{AcmeChallenge, AcmeSetting, ServerSetting, ServerTlsSetting},
+};
+use anyhow::Context;
+use client::Account;
+use hyper::{
+    server::conn::AddrIncoming,
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use rcgen::{Certificate, CertificateParams, CustomExtension, DistinguishedName};
+use rustls::sign::CertifiedKey;
+use std::{
+    collections::HashMap,
+    convert::{Infallible, TryFrom},
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use tracing::{error, info, warn};
+use x509_parser::{certificate::X509Certificate, prelude::FromDer};
+
+mod client;
+#[cfg(test)]
+pub(crate) mod test_server;
+
+/// ALPN protocol of TLS-ALPN-01 validation handshakes
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+/// Certificates obtained with ACME and the answers to pending challenges,
+/// read by the listeners
+#[derive(Default)]
+pub struct Store {
+    certs: RwLock<HashMap<String, Managed>>,
+    /// TLS-ALPN-01 certificates by domain
+    alpn_challenges: RwLock<HashMap<String, CertifiedKey>>,
+    /// HTTP-01 key authorizations by token
+    http_challenges: RwLock<HashMap<String, String>>,
+}
+
+struct Managed {
+    tls: CertifiedKey,
+    quic: Arc<rustls_quic::sign::CertifiedKey>,
+    not_after: SystemTime,
+}
+
+impl Store {
+    pub fn get(&self, domain: &str) -> Option<CertifiedKey> {
+        let certs = self.certs.read().unwrap();
+        certs.get(domain).map(|managed| managed.tls.clone())
+    }
+
+    pub fn get_quic(&self, domain: &str) -> Option<Arc<rustls_quic::sign::CertifiedKey>> {
+        let certs = self.certs.read().unwrap();
+        certs.get(domain).map(|managed| managed.quic.clone())
+    }
+
+    /// Certificate answering a TLS-ALPN-01 challenge for `domain`
+    pub fn challenge(&self, domain: &str) -> Option<CertifiedKey> {
+        let challenges = self.alpn_challenges.read().unwrap();
+        challenges.get(domain).cloned()
+    }
+
+    fn insert(&self, domain: &str, pair: &KeyPair) -> anyhow::Result<()> {
+        let not_after = not_after(&pair.certs[0].0).context("Invalid certificate validity")?;
+        let managed = Managed {
+            tls: pair.certified_key()?,
+            quic: http3::certified_key(pair)?,
+            not_after,
+        };
+        self.certs
+            .write()
+            .unwrap()
+            .insert(domain.to_owned(), managed);
+        Ok(())
+    }
+
+    fn expires_within(&self, domain: &str, duration: Duration) -> bool {
+        let certs = self.certs.read().unwrap();
+        certs
+            .get(domain)
+            .is_none_or(|managed| managed.not_after <= SystemTime::now() + duration)
+    }
+}
+
+/// Hosts of the servers which can get a certificate, the ones without their
+/// own certificate which are neither wildcards, regexes nor IP addresses
+pub fn domains(servers: &[ServerSetting]) -> Vec<String> {
+    let mut domains: Vec<_> = servers
+        .iter()
+        .filter(|server| server.tls.is_none())
+        .map(|server| normalize_host(&server.host))
+        .filter(|host| {
+            host.parse::<IpAddr>().is_err()
+                && !host.is_empty()
+                && host
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.')
+        })
+        .collect();
+    domains.sort();
+    domains.dedup();
+    domains
+}
+
+/// Loads the stored certificates of `domains`, and spawns the task
+/// obtaining the missing ones and renewing them
+pub fn start(setting: AcmeSetting, domains: Vec<String>) -> anyhow::Result<Arc<Store>> {
+    std::fs::create_dir_all(&setting.storage_dir).with_context(|| {
+        format!(
+            "Could not create ACME storage {}",
+            setting.storage_dir.display()
+        )
+    })?;
+
+    let store = Arc::new(Store::default());
+    for domain in &domains {
+        let paths = cert_paths(&setting.storage_dir, domain);
+        if paths.cert.exists() {
+            match KeyPair::load(&paths) {
+                Ok(pair) => store.insert(domain, &pair)?,
+                Err(e) => warn!("Ignoring stored certificate of {}: {:#}", domain, e),
+            }
+        }
+    }
+
+    tokio::spawn(renew(setting, domains, store.clone()));
+    Ok(store)
+}
+
+async fn renew(setting: AcmeSetting, domains: Vec<String>, store: Arc<Store>) {
+    let renew_before = Duration::from_millis(setting.renew_before_ms);
+    loop {
+        let due: Vec<_> = domains
+            .iter()
+            .filter(|domain| store.expires_within(domain, renew_before))
+            .collect();
+
+        let mut failed = false;
+        if !due.is_empty() {
+            match account(&setting).await {
+                Ok(mut account) => {
+                    for domain in due {
+                        info!("Obtaining certificate for {}", domain);
+                        match issue(&setting, &mut account, &store, domain).await {
+                            Ok(()) => info!("Obtained certificate for {}", domain),
+                            Err(e) => {
+                                error!("Could not obtain certificate for {}: {:#}", domain, e);
+                                failed = true;
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("Could not use ACME account: {:#}", e);
+                    failed = true;
+                }
+            }
+        }
+
+        let wait = if failed {
+            setting.retry_interval_ms
+        } else {
+            setting.check_interval_ms
+        };
+        tokio::time::sleep(Duration::from_millis(wait)).await;
+    }
+}
+
+/// Account of the stored key, generating the key on first use
+async fn account(setting: &AcmeSetting) -> anyhow::Result<Account> {
+    let path = setting.storage_dir.join("account.key");
+    let key = if path.exists() {
+        crate::pem::load_private_key(&path)?.0
+    } else {
+        let key = Account::generate_key()?;
+        write_private(&path, &to_pem("PRIVATE KEY", &key))?;
+        key
+    };
+    Account::new(setting, &key).await
+}
+
+async fn issue(
+    setting: &AcmeSetting,
+    account: &mut Account,
+    store: &Store,
+    domain: &str,
+) -> anyhow::Result<()> {
+    let (order_url, order) = account.new_order(domain).await?;
+
+    for url in &order.authorizations {
+        let authorization = account.authorization(url).await?;
+        if authorization.status == "valid" {
+            continue;
+        }
+        let kind = match setting.challenge {
+            AcmeChallenge::Http01 => "http-01",
+            AcmeChallenge::TlsAlpn01 => "tls-alpn-01",
+        };
+        let challenge = authorization
+            .challenges
+            .iter()
+            .find(|challenge| challenge.kind == kind)
+            .with_context(|| format!("CA offered no {} challenge", kind))?;
+
+        let key_authorization = account.key_authorization(&challenge.token);
+        match setting.challenge {
+            AcmeChallenge::Http01 => {
+                store
+                    .http_challenges
+                    .write()
+                    .unwrap()
+                    .insert(challenge.token.clone(), key_authorization);
+            }
+            AcmeChallenge::TlsAlpn01 => {
+                let cert = alpn_challenge_cert(domain, &key_authorization)?;
+                store
+                    .alpn_challenges
+                    .write()
+                    .unwrap()
+                    .insert(domain.to_owned(), cert);
+            }
+        }
+
+        let validated = account.validate(url, &challenge.url).await;
+        store
+            .http_challenges
+            .write()
+            .unwrap()
+            .remove(&challenge.token);
+        store.alpn_challenges.write().unwrap().remove(domain);
+        validated?;
+    }
+
+    let mut params = CertificateParams::new(vec![domain.to_owned()]);
+    params.distinguished_name = DistinguishedName::new();
+    let key = Certificate::from_params(params)?;
+    let certificate = account
+        .finalize(&order_url, &order.finalize, &key.serialize_request_der()?)
+        .await?;
+    let chain = account.certificate(&certificate).await?;
+
+    let paths = cert_paths(&setting.storage_dir, domain);
+    write_private(&paths.key, &key.serialize_private_key_pem())?;
+    std::fs::write(&paths.cert, chain)
+        .with_context(|| format!("Could not write {}", paths.cert.display()))?;
+    store.insert(domain, &KeyPair::load(&paths)?)
+}
+
+/// Self-signed certificate for `domain` with the digest of the key
+/// authorization, as in RFC 8737
+fn alpn_challenge_cert(domain: &str, key_authorization: &str) -> anyhow::Result<CertifiedKey> {
+    let digest = ring::digest::digest(&ring::digest::SHA256, key_authorization.as_bytes());
+    let mut params = CertificateParams::new(vec![domain.to_owned()]);
+    params.distinguished_name = DistinguishedName::new();
+    params.custom_extensions = vec![CustomExtension::new_acme_identifier(digest.as_ref())];
+    let cert = Certificate::from_params(params)?;
+    let pair = KeyPair {
+        certs: vec![rustls::Certificate(cert.serialize_der()?)],
+        key: rustls::PrivateKey(cert.serialize_private_key_der()),
+    };
+    pair.certified_key()
+}
+
+/// Starts the plain listener on `http_port` answering HTTP-01 challenges
+pub fn serve_http(store: Arc<Store>, http_port: u16) -> anyhow::Result<()> {
+    let addr = SocketAddr::from(([127, 0, 0, 1], http_port));
+    let incoming = AddrIncoming::bind(&addr)
+        .with_context(|| format!("Could not bind ACME http listener on {}", addr))?;
+    let make_service = make_service_fn(move |_| {
+        let store = store.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let res = http_response(&store, &req);
+                async { Ok::<_, Infallible>(res) }
+            }))
+        }
+    });
+    let server = Server::builder(incoming).serve(make_service);
+    info!("Starting ACME http server on port {}", http_port);
+    tokio::spawn(async {
+        server_await!(server);
+    });
+    Ok(())
+}
+
+fn http_response(store: &Store, req: &Request<Body>) -> Response<Body> {
+    let challenges = store.http_challenges.read().unwrap();
+    let key_authorization = req
+        .uri()
+        .path()
+        .strip_prefix(CHALLENGE_PATH)
+        .and_then(|token| challenges.get(token));
+    match key_authorization {
+        Some(key_authorization) => Response::new(Body::from(key_authorization.clone())),
+        None => status_response(StatusCode::NOT_FOUND),
+    }
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    let mut res = Response::new(Body::empty());
+    *res.status_mut() = status;
+    res
+}
+
+fn cert_paths(storage_dir: &Path, domain: &str) -> ServerTlsSetting {
+    ServerTlsSetting {
+        cert: storage_dir.join(format!("{}.pem", domain)),
+        key: storage_dir.join(format!("{}.key", domain)),
+    }
+}
+
+/// Writes a file only readable by the owner
+fn write_private(path: &Path, contents: &str) -> anyhow::Result<()> {
+    use std::{io::Write, os::unix::fs::OpenOptionsExt};
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("Could not write {}", path.display()))?;
+    file.write_all(contents.as_bytes())
+        .with_context(|| format!("Could not write {}", path.display()))
+}
+
+/// End of the validity of a DER certificate
+fn not_after(cert: &[u8]) -> Option<SystemTime> {
+    let (_, cert) = X509Certificate::from_der(cert).ok()?;
+    let secs = u64::try_from(cert.validity().not_after.timestamp()).ok()?;
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{certs::CertificateFiles, settings::TlsSetting, tls::CertResolver};
+    use tempfile::TempDir;
+    use test_server::{handshake, serve_tls, TestCa, Validation};
+    use x509_parser::{der_parser::oid, extensions::GeneralName};
+
+    const DOMAIN: &str = "acme.localhost";
+
+    /// Obtains a certificate from the stand-in CA with `challenge`, answered
+    /// by `serve_http` or by the TLS listener, and checks that it is stored
+    /// and served
+    async fn issue_with(challenge: AcmeChallenge) {
+        let dir = TempDir::new().unwrap();
+        let store = Arc::new(Store::default());
+        let certificates = CertificateFiles::new(&TlsSetting::default(), &[])
+            .unwrap()
+            .load()
+            .unwrap()
+            .try_map(KeyPair::certified_key)
+            .unwrap();
+        let tls = serve_tls(CertResolver::new(certificates, Some(store.clone()), None)).await;
+        let http_port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let validation = match challenge {
+            AcmeChallenge::Http01 => {
+                serve_http(store.clone(), http_port).unwrap();
+                Validation::Http(SocketAddr::from(([127, 0, 0, 1], http_port)))
+            }
+            AcmeChallenge::TlsAlpn01 => Validation::TlsAlpn(tls),
+        };
+        let ca = TestCa::start(validation);
+        let setting = AcmeSetting {
+            directory_url: ca.directory_url.clone(),
+            terms_of_service_agreed: true,
+            challenge,
+            http_port,
+            storage_dir: dir.path().to_owned(),
+            ..Default::default()
+        };
+
+        let mut account = account(&setting).await.unwrap();
+        assert!(handshake(tls, DOMAIN, None).await.is_none());
+        issue(&setting, &mut account, &store, DOMAIN).await.unwrap();
+
+        let leaf = ca.issued().unwrap();
+        assert_eq!(store.get(DOMAIN).unwrap().cert[0].0, leaf);
+        let (served, _) = handshake(tls, DOMAIN, None).await.unwrap();
+        assert_eq!(served, leaf);
+        let stored = KeyPair::load(&cert_paths(dir.path(), DOMAIN)).unwrap();
+        assert_eq!(stored.certs[0].0, leaf);
+        assert_eq!(stored.certs.len(), 2);
+
+        // The challenges are only answered during the validation
+        assert!(store.challenge(DOMAIN).is_none());
+        assert!(store.http_challenges.read().unwrap().is_empty());
+        assert!(handshake(tls, DOMAIN, Some(ACME_TLS_ALPN)).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn issue_with_http_challenge() {
+        issue_with(AcmeChallenge::Http01).await;
+    }
+
+    #[tokio::test]
+    async fn issue_with_tls_alpn_challenge() {
+        issue_with(AcmeChallenge::TlsAlpn01).await;
+    }
+
+    #[tokio::test]
+    async fn failed_validation() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::default();
+        // Nothing answers the challenge there
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let ca = TestCa::start(Validation::Http(addr));
+        let setting = AcmeSetting {
+            directory_url: ca.directory_url.clone(),
+            challenge: AcmeChallenge::Http01,
+            storage_dir: dir.path().to_owned(),
+            ..Default::default()
+        };
+
+        let mut account = account(&setting).await.unwrap();
+        let e = issue(&setting, &mut account, &store, DOMAIN)
+            .await
+            .unwrap_err();
+        assert_eq!(e.to_string(), "Authorization is invalid");
+        assert!(store.get(DOMAIN).is_none());
+        assert!(ca.issued().is_none());
+        assert!(store.http_challenges.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn not_after_of_certificate() {
+        let mut params = CertificateParams::new(vec!["example.com".to_owned()]);
+        params.not_after = rcgen::date_time_ymd(2030, 1, 2);
+        let cert = Certificate::from_params(params).unwrap();
+        assert_eq!(
+            not_after(&cert.serialize_der().unwrap()),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_893_542_400))
+        );
+
+        assert_eq!(not_after(b"\x30\x03\x02\x01\x01"), None);
+        assert_eq!(not_after(b""), None);
+    }
+
+    #[test]
+    fn alpn_challenge_certificate() {
+        let key = alpn_challenge_cert("example.com", "token.thumbprint").unwrap();
+        let (_, cert) = X509Certificate::from_der(&key.cert[0].0).unwrap();
+
+        let names = &cert.subject_alternative_name().unwrap().unwrap().value;
+        assert_eq!(names.general_names, [GeneralName::DNSName("example.com")]);
+
+        let identifier = cert
+            .get_extension_unique(&oid!(1.3.6 .1 .5 .5 .7 .1 .31))
+            .unwrap()
+            .unwrap();
+        assert!(identifier.critical);
+        let digest = ring::digest::digest(&ring::digest::SHA256, b"token.thumbprint");
+        assert_eq!(identifier.value, [&[0x04, 0x20], digest.as_ref()].concat());
+    }
+
+    #[test]
+    fn http_challenges() {
+        let store = Store::default();
+        store
+            .http_challenges
+            .write()
+            .unwrap()
+            .insert("token".to_owned(), "token.thumbprint".to_owned());
+        let get = |path: &str| {
+            let req = Request::get(path).body(Body::empty()).unwrap();
+            http_response(&store, &req)
+        };
+
+        let res = get("/.well-known/acme-challenge/token");
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = futures::executor::block_on(hyper::body::to_bytes(res.into_body())).unwrap();
+        assert_eq!(body, "token.thumbprint");
+
+        assert_eq!(
+            get("/.well-known/acme-challenge/other").status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(get("/index.html").status(), StatusCode::NOT_FOUND);
+        assert!(get("/index.html").headers().is_empty());
+    }
+}
@@ -0,0 +1,463 @@
+//! Stand-in ACME CA for a single order, validating its challenges like a
+//! real CA and issuing certificates signed by its own root
+
+use super::{client::base64url, ACME_TLS_ALPN};
+use crate::{pem::to_pem, tls::CertResolver};
+use hyper::{
+    header::{self, HeaderValue},
+    service::{make_service_fn, service_fn},
+    Body, Client, Method, Request, Response, Server, StatusCode,
+};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, IsCa, KeyPair, RcgenError, RemoteKeyPair,
+    SignatureAlgorithm, PKCS_ECDSA_P256_SHA256,
+};
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+use rustls::{
+    internal::msgs::handshake::DigitallySignedStruct, Certificate as TlsCertificate, ClientConfig,
+    HandshakeSignatureValid, RootCertStore, ServerCertVerified, ServerCertVerifier, ServerConfig,
+    Session, TLSError,
+};
+use serde_json::{json, Value};
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use webpki::DNSNameRef;
+use x509_parser::{
+    certification_request::X509CertificationRequest,
+    extensions::{GeneralName, ParsedExtension},
+    prelude::FromDer,
+};
+
+const TOKEN: &str = "evaGxfADs6pSRb2LAv9IZf17Dt3juxGJ-PCt92wr-oA";
+
+/// Listener the CA connects to for validating the challenge
+#[derive(Clone, Copy)]
+pub enum Validation {
+    /// Plain listener answering HTTP-01 challenges
+    Http(SocketAddr),
+    /// TLS listener answering TLS-ALPN-01 challenges
+    TlsAlpn(SocketAddr),
+}
+
+pub struct TestCa {
+    pub directory_url: String,
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    base: String,
+    validation: Validation,
+    root: Certificate,
+    nonce: u64,
+    jwk: Option<Value>,
+    domain: Option<String>,
+    /// Status of the authorization, `pending`, `valid` or `invalid`
+    authorization: &'static str,
+    /// DER leaf and PEM chain of the issued certificate
+    issued: Option<(Vec<u8>, String)>,
+}
+
+impl TestCa {
+    pub fn start(validation: Validation) -> Self {
+        let mut params = CertificateParams::new(vec![]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let root = Certificate::from_params(params).unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(State {
+            base: base.clone(),
+            validation,
+            root,
+            nonce: 0,
+            jwk: None,
+            domain: None,
+            authorization: "pending",
+            issued: None,
+        }));
+
+        let shared = state.clone();
+        let make_service = make_service_fn(move |_| {
+            let state = shared.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let state = state.clone();
+                    async move { Ok::<_, Infallible>(handle(&state, req).await) }
+                }))
+            }
+        });
+        tokio::spawn(Server::from_tcp(listener).unwrap().serve(make_service));
+
+        Self {
+            directory_url: format!("{}/directory", base),
+            state,
+        }
+    }
+
+    /// DER leaf of the issued certificate
+    pub fn issued(&self) -> Option<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        state.issued.as_ref().map(|(leaf, _)| leaf.clone())
+    }
+}
+
+/// Starts a TLS listener picking certificates with `resolver`
+pub async fn serve_tls(resolver: CertResolver) -> SocketAddr {
+    let mut config = ServerConfig::new(rustls::NoClientAuth::new());
+    config.cert_resolver = Arc::new(resolver);
+    config.alpn_protocols = vec![b"http/1.1".to_vec(), ACME_TLS_ALPN.to_vec()];
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move { acceptor.accept(stream).await });
+        }
+    });
+    addr
+}
+
+/// Leaf certificate sent by the listener at `addr` for `domain`, and the
+/// negotiated protocol
+pub async fn handshake(
+    addr: SocketAddr,
+    domain: &str,
+    alpn: Option<&[u8]>,
+) -> Option<(Vec<u8>, Option<Vec<u8>>)> {
+    let mut config = ClientConfig::new();
+    config
+        .dangerous()
+        .set_certificate_verifier(Arc::new(AcceptAll));
+    config.alpn_protocols = alpn.into_iter().map(<[u8]>::to_vec).collect();
+
+    let stream = TcpStream::connect(addr).await.ok()?;
+    let name = DNSNameRef::try_from_ascii_str(domain).ok()?;
+    let stream = TlsConnector::from(Arc::new(config))
+        .connect(name, stream)
+        .await
+        .ok()?;
+    let session = stream.get_ref().1;
+    let leaf = session.get_peer_certificates()?.first()?.0.clone();
+    Some((leaf, session.get_alpn_protocol().map(<[u8]>::to_vec)))
+}
+
+/// Validation certificates are self-signed, and their critical
+/// acmeIdentifier extension is rejected by webpki
+struct AcceptAll;
+
+impl ServerCertVerifier for AcceptAll {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        _presented_certs: &[TlsCertificate],
+        _dns_name: DNSNameRef<'_>,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &TlsCertificate,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TLSError> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        _message: &[u8],
+        _cert: &TlsCertificate,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TLSError> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+}
+
+async fn handle(state: &Mutex<State>, req: Request<Body>) -> Response<Body> {
+    let path = req.uri().path().to_owned();
+    let (base, nonce) = {
+        let mut state = state.lock().unwrap();
+        state.nonce += 1;
+        (state.base.clone(), format!("nonce-{}", state.nonce))
+    };
+
+    let mut res = match (req.method(), path.as_str()) {
+        (&Method::GET, "/directory") => json_response(json!({
+            "newNonce": format!("{}/nonce", base),
+            "newAccount": format!("{}/account", base),
+            "newOrder": format!("{}/order", base),
+        })),
+        (&Method::HEAD, "/nonce") => Response::new(Body::empty()),
+        (&Method::POST, _) => {
+            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+            match verify(state, &format!("{}{}", base, path), &body) {
+                Some(payload) => post(state, &base, &path, payload).await,
+                None => problem(StatusCode::BAD_REQUEST, "malformed"),
+            }
+        }
+        _ => problem(StatusCode::NOT_FOUND, "malformed"),
+    };
+    res.headers_mut()
+        .insert("replay-nonce", HeaderValue::from_str(&nonce).unwrap());
+    res
+}
+
+/// Payload of a JWS for `url` signed by the account key, `Null` for a
+/// POST-as-GET
+fn verify(state: &Mutex<State>, url: &str, body: &[u8]) -> Option<Value> {
+    let jws: Value = serde_json::from_slice(body).ok()?;
+    let protected = jws["protected"].as_str()?;
+    let payload = jws["payload"].as_str()?;
+    let header: Value = serde_json::from_slice(&decode(protected)?).ok()?;
+    if header["url"] != url || header["alg"] != "ES256" || !header["nonce"].is_string() {
+        return None;
+    }
+
+    let mut state = state.lock().unwrap();
+    let account = format!("{}/account/1", state.base);
+    let jwk = match (&header["jwk"], &header["kid"]) {
+        (jwk @ Value::Object(_), Value::Null) => {
+            state.jwk = Some(jwk.clone());
+            jwk.clone()
+        }
+        (Value::Null, kid) if *kid == account => state.jwk.clone()?,
+        _ => return None,
+    };
+    let point = [
+        &[0x04][..],
+        &decode(jwk["x"].as_str()?)?,
+        &decode(jwk["y"].as_str()?)?,
+    ]
+    .concat();
+    UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, point)
+        .verify(
+            format!("{}.{}", protected, payload).as_bytes(),
+            &decode(jws["signature"].as_str()?)?,
+        )
+        .ok()?;
+
+    if payload.is_empty() {
+        Some(Value::Null)
+    } else {
+        serde_json::from_slice(&decode(payload)?).ok()
+    }
+}
+
+async fn post(state: &Mutex<State>, base: &str, path: &str, payload: Value) -> Response<Body> {
+    match path {
+        "/account" => created(json!({ "status": "valid" }), &format!("{}/account/1", base)),
+        "/order" => {
+            let domain = payload["identifiers"][0]["value"].as_str();
+            state.lock().unwrap().domain = domain.map(str::to_owned);
+            created(order(state, base), &format!("{}/order/1", base))
+        }
+        "/order/1" => json_response(order(state, base)),
+        "/authorization/1" => {
+            let state = state.lock().unwrap();
+            let challenges: Vec<_> = ["http-01", "tls-alpn-01"]
+                .iter()
+                .map(|kind| {
+                    json!({
+                        "type": kind,
+                        "url": format!("{}/challenge/{}", base, kind),
+                        "token": TOKEN,
+                    })
+                })
+                .collect();
+            json_response(json!({
+                "status": state.authorization,
+                "identifier": { "type": "dns", "value": state.domain },
+                "challenges": challenges,
+            }))
+        }
+        "/challenge/http-01" | "/challenge/tls-alpn-01" => {
+            let (validation, domain, key_authorization) = {
+                let state = state.lock().unwrap();
+                let jwk = state.jwk.as_ref().unwrap().to_string();
+                let thumbprint = ring::digest::digest(&ring::digest::SHA256, jwk.as_bytes());
+                (
+                    state.validation,
+                    state.domain.clone().unwrap(),
+                    format!("{}.{}", TOKEN, base64url(thumbprint.as_ref())),
+                )
+            };
+            let valid = match (path, validation) {
+                ("/challenge/http-01", Validation::Http(addr)) => {
+                    http_answer(addr, &domain).await == Some(key_authorization)
+                }
+                ("/challenge/tls-alpn-01", Validation::TlsAlpn(addr)) => {
+                    let digest =
+                        ring::digest::digest(&ring::digest::SHA256, key_authorization.as_bytes());
+                    match handshake(addr, &domain, Some(ACME_TLS_ALPN)).await {
+                        Some((leaf, Some(alpn))) if alpn == ACME_TLS_ALPN => {
+                            acme_identifier(&leaf).as_deref() == Some(digest.as_ref())
+                        }
+                        _ => false,
+                    }
+                }
+                _ => false,
+            };
+            state.lock().unwrap().authorization = if valid { "valid" } else { "invalid" };
+            json_response(json!({
+                "type": &path["/challenge/".len()..],
+                "url": format!("{}{}", base, path),
+                "token": TOKEN,
+                "status": "processing",
+            }))
+        }
+        "/finalize/1" => {
+            let mut guard = state.lock().unwrap();
+            if guard.authorization != "valid" {
+                return problem(StatusCode::FORBIDDEN, "orderNotReady");
+            }
+            let domain = guard.domain.clone().unwrap();
+            let csr = payload["csr"].as_str().and_then(decode);
+            match csr.and_then(|csr| sign_csr(&guard.root, &csr, &domain)) {
+                Some(leaf) => {
+                    let chain = format!(
+                        "{}{}",
+                        to_pem("CERTIFICATE", &leaf),
+                        guard.root.serialize_pem().unwrap()
+                    );
+                    guard.issued = Some((leaf, chain));
+                }
+                None => return problem(StatusCode::BAD_REQUEST, "badCSR"),
+            }
+            drop(guard);
+            json_response(order(state, base))
+        }
+        "/certificate/1" => match &state.lock().unwrap().issued {
+            Some((_, chain)) => Response::new(Body::from(chain.clone())),
+            None => problem(StatusCode::NOT_FOUND, "malformed"),
+        },
+        _ => problem(StatusCode::NOT_FOUND, "malformed"),
+    }
+}
+
+fn order(state: &Mutex<State>, base: &str) -> Value {
+    let state = state.lock().unwrap();
+    let status = match (state.authorization, &state.issued) {
+        (_, Some(_)) => "valid",
+        ("valid", None) => "ready",
+        ("invalid", None) => "invalid",
+        _ => "pending",
+    };
+    let mut order = json!({
+        "status": status,
+        "identifiers": [{ "type": "dns", "value": state.domain }],
+        "authorizations": [format!("{}/authorization/1", base)],
+        "finalize": format!("{}/finalize/1", base),
+    });
+    if state.issued.is_some() {
+        order["certificate"] = json!(format!("{}/certificate/1", base));
+    }
+    order
+}
+
+/// Key authorization served for the token over plain HTTP
+async fn http_answer(addr: SocketAddr, domain: &str) -> Option<String> {
+    let req = Request::get(format!(
+        "http://{}/.well-known/acme-challenge/{}",
+        addr, TOKEN
+    ))
+    .header(header::HOST, domain)
+    .body(Body::empty())
+    .unwrap();
+    let res = Client::new().request(req).await.ok()?;
+    if res.status() != StatusCode::OK {
+        return None;
+    }
+    let body = hyper::body::to_bytes(res.into_body()).await.ok()?;
+    String::from_utf8(body.to_vec()).ok()
+}
+
+/// Digest in the critical acmeIdentifier extension of RFC 8737
+fn acme_identifier(cert: &[u8]) -> Option<Vec<u8>> {
+    let (_, cert) = x509_parser::certificate::X509Certificate::from_der(cert).ok()?;
+    let extension = cert
+        .get_extension_unique(&x509_parser::der_parser::oid!(1.3.6 .1 .5 .5 .7 .1 .31))
+        .ok()??;
+    if !extension.critical {
+        return None;
+    }
+    extension
+        .value
+        .strip_prefix(&[0x04, 0x20][..])
+        .map(<[u8]>::to_vec)
+}
+
+/// Certificate for the key of a CSR which only asks for `domain`
+fn sign_csr(root: &Certificate, csr: &[u8], domain: &str) -> Option<Vec<u8>> {
+    let (_, csr) = X509CertificationRequest::from_der(csr).ok()?;
+    csr.verify_signature().ok()?;
+    let names: Vec<_> = csr
+        .requested_extensions()?
+        .filter_map(|extension| match extension {
+            ParsedExtension::SubjectAlternativeName(names) => Some(&names.general_names),
+            _ => None,
+        })
+        .flatten()
+        .collect();
+    if names != [&GeneralName::DNSName(domain)] {
+        return None;
+    }
+
+    let public_key = &csr
+        .certification_request_info
+        .subject_pki
+        .subject_public_key;
+    let mut params = CertificateParams::new(vec![domain.to_owned()]);
+    params.key_pair = Some(KeyPair::from_remote(Box::new(CsrKey(public_key.data.to_vec()))).ok()?);
+    let cert = Certificate::from_params(params).ok()?;
+    cert.serialize_der_with_signer(root).ok()
+}
+
+/// Public key of a CSR, the certificate being signed by the root
+struct CsrKey(Vec<u8>);
+
+impl RemoteKeyPair for CsrKey {
+    fn public_key(&self) -> &[u8] {
+        &self.0
+    }
+
+    fn sign(&self, _msg: &[u8]) -> Result<Vec<u8>, RcgenError> {
+        Err(RcgenError::RemoteKeyError)
+    }
+
+    fn algorithm(&self) -> &'static SignatureAlgorithm {
+        &PKCS_ECDSA_P256_SHA256
+    }
+}
+
+fn created(body: Value, location: &str) -> Response<Body> {
+    let mut res = json_response(body);
+    *res.status_mut() = StatusCode::CREATED;
+    res.headers_mut()
+        .insert(header::LOCATION, HeaderValue::from_str(location).unwrap());
+    res
+}
+
+fn json_response(body: Value) -> Response<Body> {
+    Response::new(Body::from(body.to_string()))
+}
+
+fn problem(status: StatusCode, kind: &str) -> Response<Body> {
+    let mut res = json_response(json!({
+        "type": format!("urn:ietf:params:acme:error:{}", kind),
+    }));
+    *res.status_mut() = status;
+    res
+}
+
+fn decode(data: &str) -> Option<Vec<u8>> {
+    base64::decode_config(data, base64::URL_SAFE_NO_PAD).ok()
+}
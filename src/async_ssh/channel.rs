@@ -88,7 +88,7 @@ impl AsyncRead for AsyncStream {
             let session = self.session.clone();
             let mut guard = ready!(session.poll_read_ready(cx))?;
             let unfilled: &mut [u8] = buf.initialize_unfilled();
-            match guard.try_io(|_| self.inner.read(unfilled).map_err(Into::into)) {
+            match guard.try_io(|_| self.inner.read(unfilled)) {
                 Ok(result) => return Poll::Ready(result.map(|n| buf.advance(n))),
                 Err(_would_block) => continue,
             }
@@ -105,7 +105,7 @@ impl AsyncWrite for AsyncStream {
         loop {
             let session = self.session.clone();
             let mut guard = ready!(session.poll_write_ready(cx))?;
-            match guard.try_io(|_| self.inner.write(buf).map_err(Into::into)) {
+            match guard.try_io(|_| self.inner.write(buf)) {
                 Ok(result) => return Poll::Ready(result),
                 Err(_would_block) => continue,
             }
@@ -119,7 +119,7 @@ impl AsyncWrite for AsyncStream {
         loop {
             let session = self.session.clone();
             let mut guard = ready!(session.poll_write_ready(cx))?;
-            match guard.try_io(|_| self.inner.flush().map_err(Into::into)) {
+            match guard.try_io(|_| self.inner.flush()) {
                 Ok(result) => return Poll::Ready(result),
                 Err(_would_block) => continue,
             }
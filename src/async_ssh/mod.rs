@@ -1,4 +1,3 @@
-pub use listener::AsyncListener;
 pub use session::{AsyncSession, HandshakenAsyncSession};
 use std::net::TcpStream;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -26,8 +25,9 @@ pub async fn main() -> anyhow::Result<()> {
         match listener.accept().await {
             Ok(mut channel) => {
                 let mut buf = vec![0; 64];
-                channel.read(&mut buf).await?;
-                info!("channel receive {:?}", std::str::from_utf8(&buf));
+                let n = channel.read(&mut buf).await?;
+                let buf = &buf[..n];
+                info!("channel receive {:?}", std::str::from_utf8(buf));
                 if buf.starts_with(b"GET / HTTP/1.1\r\n") {
                     channel.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await?;
                 } else {
@@ -0,0 +1,349 @@
+use crate::{settings::BufferBodySetting, timeout::RequestTimeout, upstream::InFlightGuard};
+use futures::stream;
+use hyper::{
+    body::{Bytes, HttpBody, SizeHint},
+    Body, HeaderMap,
+};
+use std::{
+    error::Error,
+    fs::File,
+    future::Future,
+    io::{self, Write},
+    os::unix::fs::FileExt,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::time::{Instant, Sleep};
+
+/// Size of the chunks a spilled body is read in
+const SPILL_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Request body sent to the upstream, which can be sent again for retries
+/// unless it is streamed
+pub enum RequestBody {
+    /// Body held in memory
+    Buffered(Bytes),
+    /// Body written to an unnamed temporary file
+    Spilled { file: Arc<File>, len: u64 },
+    /// Body streamed from the client, `None` once it has been sent
+    Streaming(Option<Body>),
+}
+
+impl RequestBody {
+    /// Reads the body into memory if its length `len` is known and at most
+    /// `max_bytes`, otherwise streams it
+    pub async fn for_retry(body: Body, len: Option<u64>, max_bytes: usize) -> anyhow::Result<Self> {
+        match len {
+            Some(len) if len <= max_bytes as u64 => {
+                Ok(RequestBody::Buffered(hyper::body::to_bytes(body).await?))
+            }
+            _ => Ok(RequestBody::Streaming(Some(body))),
+        }
+    }
+
+    /// Reads the whole body, keeping up to `memory_bytes` in memory and
+    /// spilling larger bodies to disk. Returns `None` if the body is larger
+    /// than `max_bytes`, checking the known length `len` before reading.
+    pub async fn buffer(
+        mut body: Body,
+        len: Option<u64>,
+        setting: &BufferBodySetting,
+    ) -> anyhow::Result<Option<Self>> {
+        if len.is_some_and(|len| len > setting.max_bytes) {
+            return Ok(None);
+        }
+
+        let mut buffer = Vec::new();
+        let mut spill: Option<(File, u64)> = None;
+
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk?;
+            let len = spill.as_ref().map_or(buffer.len() as u64, |(_, len)| *len);
+            if len + chunk.len() as u64 > setting.max_bytes {
+                return Ok(None);
+            }
+
+            spill = match spill {
+                None if buffer.len() + chunk.len() <= setting.memory_bytes => {
+                    buffer.extend_from_slice(&chunk);
+                    None
+                }
+                None => {
+                    let spill_dir = setting.spill_dir.clone();
+                    let buffered = std::mem::take(&mut buffer);
+                    Some(
+                        tokio::task::spawn_blocking(move || -> io::Result<_> {
+                            let mut file = tempfile::tempfile_in(spill_dir)?;
+                            file.write_all(&buffered)?;
+                            file.write_all(&chunk)?;
+                            Ok((file, (buffered.len() + chunk.len()) as u64))
+                        })
+                        .await??,
+                    )
+                }
+                Some((mut file, len)) => Some(
+                    tokio::task::spawn_blocking(move || -> io::Result<_> {
+                        file.write_all(&chunk)?;
+                        Ok((file, len + chunk.len() as u64))
+                    })
+                    .await??,
+                ),
+            };
+        }
+
+        Ok(Some(match spill {
+            Some((file, len)) => RequestBody::Spilled {
+                file: Arc::new(file),
+                len,
+            },
+            None => RequestBody::Buffered(buffer.into()),
+        }))
+    }
+
+    /// Length of the body if it is not streamed
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            RequestBody::Buffered(bytes) => Some(bytes.len() as u64),
+            RequestBody::Spilled { len, .. } => Some(*len),
+            RequestBody::Streaming(_) => None,
+        }
+    }
+
+    pub fn is_replayable(&self) -> bool {
+        !matches!(self, RequestBody::Streaming(_))
+    }
+
+    /// Body for one request to the upstream, `None` if a streamed body has
+    /// already been sent
+    pub fn take(&mut self) -> Option<Body> {
+        match self {
+            RequestBody::Buffered(bytes) => Some(Body::from(bytes.clone())),
+            RequestBody::Spilled { file, len } => Some(spilled_body(file.clone(), *len)),
+            RequestBody::Streaming(body) => body.take(),
+        }
+    }
+}
+
+/// Response body sent to the client, keeping the request to the upstream
+/// in flight until the body has been sent or the client went away
+pub struct ResponseBody {
+    body: Body,
+    _in_flight: Option<InFlightGuard>,
+    /// End of the request, failing the body with `RequestTimeout` if it is
+    /// still being sent
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl ResponseBody {
+    pub fn new(body: Body, in_flight: InFlightGuard) -> Self {
+        Self {
+            body,
+            _in_flight: Some(in_flight),
+            deadline: None,
+        }
+    }
+
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        if !self.body.is_end_stream() {
+            self.deadline = Some(Box::pin(tokio::time::sleep_until(deadline)));
+        }
+        self
+    }
+
+    fn poll_deadline(&mut self, cx: &mut Context<'_>) -> Result<(), RequestTimeout> {
+        match self
+            .deadline
+            .as_mut()
+            .map(|deadline| deadline.as_mut().poll(cx))
+        {
+            Some(Poll::Ready(())) => Err(RequestTimeout),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl From<Body> for ResponseBody {
+    fn from(body: Body) -> Self {
+        Self {
+            body,
+            _in_flight: None,
+            deadline: None,
+        }
+    }
+}
+
+impl HttpBody for ResponseBody {
+    type Data = Bytes;
+    type Error = Box<dyn Error + Send + Sync>;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        if let Poll::Ready(data) = Pin::new(&mut self.body).poll_data(cx) {
+            return Poll::Ready(data.map(|data| data.map_err(Into::into)));
+        }
+        self.poll_deadline(cx)?;
+        Poll::Pending
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        if let Poll::Ready(trailers) = Pin::new(&mut self.body).poll_trailers(cx) {
+            return Poll::Ready(trailers.map_err(Into::into));
+        }
+        self.poll_deadline(cx)?;
+        Poll::Pending
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+/// Streams the file from the start without touching its offset, so the same
+/// file can be sent by several attempts
+fn spilled_body(file: Arc<File>, len: u64) -> Body {
+    Body::wrap_stream(stream::try_unfold(0, move |offset| {
+        let file = file.clone();
+        async move {
+            if offset >= len {
+                return Ok(None);
+            }
+            let chunk = tokio::task::spawn_blocking(move || -> io::Result<_> {
+                let mut chunk = vec![0; SPILL_CHUNK_SIZE.min((len - offset) as usize)];
+                file.read_exact_at(&mut chunk, offset)?;
+                Ok(chunk)
+            })
+            .await??;
+            let next = offset + chunk.len() as u64;
+            Ok::<_, io::Error>(Some((Bytes::from(chunk), next)))
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Body of unknown length sent in chunks of `chunk_size`
+    fn chunked(data: &[u8], chunk_size: usize) -> Body {
+        let chunks: Vec<_> = data
+            .chunks(chunk_size)
+            .map(|chunk| Ok::<_, io::Error>(Bytes::copy_from_slice(chunk)))
+            .collect();
+        Body::wrap_stream(stream::iter(chunks))
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn setting(dir: &TempDir, max_bytes: u64, memory_bytes: usize) -> BufferBodySetting {
+        BufferBodySetting {
+            max_bytes,
+            memory_bytes,
+            spill_dir: dir.path().to_owned(),
+        }
+    }
+
+    async fn take(body: &mut RequestBody) -> Bytes {
+        hyper::body::to_bytes(body.take().unwrap()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn buffer_in_memory() {
+        let dir = TempDir::new().unwrap();
+        let data = data(1000);
+        let mut body = RequestBody::buffer(chunked(&data, 100), None, &setting(&dir, 1000, 1000))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(body, RequestBody::Buffered(_)));
+        assert_eq!(body.len(), Some(1000));
+        assert!(body.is_replayable());
+        assert_eq!(take(&mut body).await, data);
+        assert_eq!(take(&mut body).await, data);
+    }
+
+    #[tokio::test]
+    async fn spill_to_file() {
+        let dir = TempDir::new().unwrap();
+        // Read back in several chunks
+        let data = data(2 * SPILL_CHUNK_SIZE + 1000);
+        let setting = setting(&dir, data.len() as u64, 1000);
+        let mut body = RequestBody::buffer(chunked(&data, 300), None, &setting)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(body, RequestBody::Spilled { .. }));
+        assert_eq!(body.len(), Some(data.len() as u64));
+        assert!(body.is_replayable());
+
+        // Each attempt gets the whole body
+        let first = body.take().unwrap();
+        let second = body.take().unwrap();
+        assert_eq!(hyper::body::to_bytes(second).await.unwrap(), data);
+        assert_eq!(hyper::body::to_bytes(first).await.unwrap(), data);
+        assert_eq!(take(&mut body).await, data);
+        // The file is unnamed
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn larger_than_max_bytes() {
+        let dir = TempDir::new().unwrap();
+        let data = data(5000);
+
+        // Rejected by its length before reading it, in memory or spilled
+        let setting = setting(&dir, 4999, 1000);
+        let body = Body::from(data.clone());
+        let len = body.size_hint().exact();
+        assert!(RequestBody::buffer(body, len, &setting)
+            .await
+            .unwrap()
+            .is_none());
+        for memory_bytes in [5000, 1000] {
+            let setting = BufferBodySetting {
+                memory_bytes,
+                ..setting.clone()
+            };
+            assert!(RequestBody::buffer(chunked(&data, 300), None, &setting)
+                .await
+                .unwrap()
+                .is_none());
+        }
+
+        let setting = self::setting(&dir, 5000, 1000);
+        assert!(RequestBody::buffer(chunked(&data, 300), None, &setting)
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn streaming_is_sent_once() {
+        let mut body = RequestBody::for_retry(chunked(b"abc", 1), None, 10)
+            .await
+            .unwrap();
+        assert!(!body.is_replayable());
+        assert_eq!(body.len(), None);
+        assert_eq!(take(&mut body).await, "abc");
+        assert!(body.take().is_none());
+
+        let mut body = RequestBody::for_retry(Body::from("abc"), Some(3), 10)
+            .await
+            .unwrap();
+        assert!(body.is_replayable());
+        assert_eq!(take(&mut body).await, "abc");
+        assert_eq!(take(&mut body).await, "abc");
+    }
+}
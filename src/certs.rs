@@ -0,0 +1,192 @@
+use crate::{
+    host::{normalize_host, HostMap},
+    pem::{load_certs, load_private_key},
+    settings::{ServerSetting, ServerTlsSetting, TlsSetting},
+};
+use rustls::{
+    sign::{self, CertifiedKey, SigningKey},
+    Certificate, PrivateKey, SignatureScheme,
+};
+use std::{path::Path, sync::Arc};
+
+/// Certificates of the servers, picked by the SNI of a handshake with the
+/// same host matching as the router
+#[derive(Debug)]
+pub struct Certificates<K> {
+    hosts: HostMap<K>,
+    /// Sent when there is no SNI or no server with a certificate matches it
+    default: Option<K>,
+}
+
+/// Certificate and key files of the listener, kept to load them again when
+/// they change
+pub struct CertificateFiles {
+    servers: Vec<(String, ServerTlsSetting)>,
+    default: Option<ServerTlsSetting>,
+}
+
+impl CertificateFiles {
+    pub fn new(tls: &TlsSetting, servers: &[ServerSetting]) -> anyhow::Result<Self> {
+        Ok(Self {
+            servers: servers
+                .iter()
+                .filter_map(|server| Some((server.host.clone(), server.tls.clone()?)))
+                .collect(),
+            default: tls.default_cert()?,
+        })
+    }
+
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.servers
+            .iter()
+            .map(|(_, setting)| setting)
+            .chain(&self.default)
+            .flat_map(|setting| [setting.cert.as_path(), setting.key.as_path()])
+    }
+
+    /// Reads the certificates of the servers having one, the default one
+    /// being used for all other hosts
+    pub fn load(&self) -> anyhow::Result<Certificates<KeyPair>> {
+        let mut certificates = Certificates {
+            hosts: HostMap::default(),
+            default: self.default.as_ref().map(KeyPair::load).transpose()?,
+        };
+        for (host, setting) in &self.servers {
+            certificates.hosts.insert(host, KeyPair::load(setting)?)?;
+        }
+        Ok(certificates)
+    }
+}
+
+/// Certificate chain and private key read from PEM files
+pub struct KeyPair {
+    pub certs: Vec<Certificate>,
+    pub key: PrivateKey,
+}
+
+impl KeyPair {
+    pub fn load(setting: &ServerTlsSetting) -> anyhow::Result<Self> {
+        let pair = Self {
+            certs: load_certs(&setting.cert)?,
+            key: load_private_key(&setting.key)?,
+        };
+        let key = sign::any_supported_type(&pair.key).map_err(|_| {
+            anyhow::anyhow!("Unsupported private key type in {}", setting.key.display())
+        })?;
+        if !signs_for(&*key, &pair.certs[0]) {
+            anyhow::bail!(
+                "Private key {} does not match the first certificate of {}, which must be the \
+                 leaf followed by its chain",
+                setting.key.display(),
+                setting.cert.display()
+            );
+        }
+        Ok(pair)
+    }
+
+    pub fn certified_key(&self) -> anyhow::Result<CertifiedKey> {
+        let key = sign::any_supported_type(&self.key)
+            .map_err(|_| anyhow::anyhow!("Unsupported private key type"))?;
+        Ok(CertifiedKey::new(self.certs.clone(), Arc::new(key)))
+    }
+}
+
+/// Whether a signature made with `key` is verified by the public key of
+/// `cert`, assuming it is when this cannot be checked
+fn signs_for(key: &dyn SigningKey, cert: &Certificate) -> bool {
+    const MESSAGE: &[u8] = b"revprox key check";
+
+    let cert = match webpki::EndEntityCert::from(&cert.0) {
+        Ok(cert) => cert,
+        Err(_) => return true,
+    };
+    let signer = match key.choose_scheme(&[
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+        SignatureScheme::ED25519,
+        SignatureScheme::RSA_PKCS1_SHA256,
+    ]) {
+        Some(signer) => signer,
+        None => return true,
+    };
+    let algorithm = match signer.get_scheme() {
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+        SignatureScheme::ED25519 => &webpki::ED25519,
+        _ => &webpki::RSA_PKCS1_2048_8192_SHA256,
+    };
+    let signature = match signer.sign(MESSAGE) {
+        Ok(signature) => signature,
+        Err(_) => return true,
+    };
+    !matches!(
+        cert.verify_signature(algorithm, MESSAGE, &signature),
+        Err(webpki::Error::InvalidSignatureForPublicKey)
+            | Err(webpki::Error::UnsupportedSignatureAlgorithmForPublicKey)
+    )
+}
+
+impl<K> Certificates<K> {
+    pub fn is_empty(&self) -> bool {
+        self.default.is_none() && self.hosts.is_empty()
+    }
+
+    pub fn get(&self, server_name: Option<&str>) -> Option<&K> {
+        let name = match server_name {
+            Some(name) => normalize_host(name),
+            None => return self.default.as_ref(),
+        };
+        self.hosts
+            .get(&name)
+            .map(|(key, _)| key)
+            .or(self.default.as_ref())
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &K> {
+        self.hosts.values().chain(&self.default)
+    }
+
+    /// Converts every certificate, keeping how they are picked
+    pub fn try_map<L>(
+        &self,
+        f: impl Fn(&K) -> anyhow::Result<L>,
+    ) -> anyhow::Result<Certificates<L>> {
+        Ok(Certificates {
+            hosts: self.hosts.try_map(&f)?,
+            default: self.default.as_ref().map(f).transpose()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::write_file;
+    use tempfile::TempDir;
+
+    #[test]
+    fn key_must_match_certificate() {
+        let dir = TempDir::new().unwrap();
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let other = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let write = |name, contents| write_file(dir.path(), name, contents);
+        let setting = |key| ServerTlsSetting {
+            cert: write("cert.pem", cert.serialize_pem().unwrap()),
+            key,
+        };
+
+        let key = write("key.pem", cert.serialize_private_key_pem());
+        assert!(KeyPair::load(&setting(key)).is_ok());
+
+        let key = write("other.pem", other.serialize_private_key_pem());
+        let err = match KeyPair::load(&setting(key)) {
+            Ok(_) => panic!("loaded a key not matching its certificate"),
+            Err(e) => e.to_string(),
+        };
+        assert!(
+            err.contains("does not match the first certificate"),
+            "{}",
+            err
+        );
+    }
+}
@@ -0,0 +1,166 @@
+use crate::{settings::ClientCertHeaders, utils::hex};
+use hyper::{
+    header::{HeaderName, HeaderValue},
+    HeaderMap,
+};
+use std::{
+    convert::TryFrom,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+use tracing::warn;
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer};
+
+/// Identity of the certificate verified in the handshake of a client, kept
+/// in the extensions of its requests
+#[derive(Debug)]
+pub struct ClientCert {
+    subject: String,
+    san: String,
+    fingerprint: String,
+}
+
+impl ClientCert {
+    /// Reads the leaf certificate sent by the client
+    pub fn new(der: &[u8]) -> Self {
+        let fingerprint = hex(ring::digest::digest(&ring::digest::SHA256, der).as_ref());
+        let (subject, san) = match X509Certificate::from_der(der) {
+            Ok((_, cert)) => {
+                let san = match cert.subject_alternative_name() {
+                    Ok(Some(san)) => san
+                        .value
+                        .general_names
+                        .iter()
+                        .filter_map(general_name)
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    _ => String::new(),
+                };
+                (cert.subject().to_string(), san)
+            }
+            Err(e) => {
+                warn!("Could not parse client certificate: {}", e);
+                (String::new(), String::new())
+            }
+        };
+        Self {
+            subject,
+            san,
+            fingerprint,
+        }
+    }
+}
+
+fn general_name(name: &GeneralName) -> Option<String> {
+    Some(match name {
+        GeneralName::DNSName(name) => format!("DNS:{}", name),
+        GeneralName::RFC822Name(email) => format!("email:{}", email),
+        GeneralName::URI(uri) => format!("URI:{}", uri),
+        GeneralName::IPAddress(ip) => {
+            let ip = match ip.len() {
+                4 => IpAddr::from(Ipv4Addr::from(<[u8; 4]>::try_from(*ip).ok()?)),
+                16 => IpAddr::from(Ipv6Addr::from(<[u8; 16]>::try_from(*ip).ok()?)),
+                _ => return None,
+            };
+            format!("IP:{}", ip)
+        }
+        _ => return None,
+    })
+}
+
+/// Request headers forwarding the client certificate to upstreams
+pub struct CertHeaders {
+    subject: Option<HeaderName>,
+    san: Option<HeaderName>,
+    fingerprint: Option<HeaderName>,
+}
+
+impl CertHeaders {
+    pub fn new(setting: &ClientCertHeaders) -> anyhow::Result<Self> {
+        let name = |name: &Option<String>| {
+            name.as_deref()
+                .map(|name| {
+                    HeaderName::try_from(name).map_err(|_| {
+                        anyhow::anyhow!("Invalid client certificate header {:?}", name)
+                    })
+                })
+                .transpose()
+        };
+        Ok(Self {
+            subject: name(&setting.subject)?,
+            san: name(&setting.san)?,
+            fingerprint: name(&setting.fingerprint)?,
+        })
+    }
+
+    /// Replaces the headers sent by the client, which could pretend to have
+    /// a certificate, with the ones of `cert`
+    pub fn insert(&self, headers: &mut HeaderMap, cert: Option<&ClientCert>) {
+        let fields = [
+            (&self.subject, cert.map(|cert| &cert.subject)),
+            (&self.san, cert.map(|cert| &cert.san)),
+            (&self.fingerprint, cert.map(|cert| &cert.fingerprint)),
+        ];
+        for (name, value) in fields {
+            let name = match name {
+                Some(name) => name,
+                None => continue,
+            };
+            headers.remove(name);
+            match value.map(|value| HeaderValue::from_str(value)) {
+                Some(Ok(value)) if !value.is_empty() => {
+                    headers.insert(name, value);
+                }
+                Some(Err(_)) => warn!("Client certificate {} is not a valid header value", name),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spoofed_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for name in [
+            "x-client-cert-subject",
+            "x-client-cert-san",
+            "x-client-cert-fingerprint",
+        ] {
+            headers.insert(name, HeaderValue::from_static("CN=admin"));
+        }
+        headers.insert("x-other", HeaderValue::from_static("kept"));
+        headers
+    }
+
+    #[test]
+    fn headers_of_client_without_certificate_are_removed() {
+        let cert_headers = CertHeaders::new(&ClientCertHeaders::default()).unwrap();
+        let mut headers = spoofed_headers();
+        cert_headers.insert(&mut headers, None);
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers["x-other"], "kept");
+    }
+
+    #[test]
+    fn headers_of_client_certificate() {
+        let cert = rcgen::generate_simple_self_signed(vec!["admin.localhost".to_owned()]).unwrap();
+        let cert = ClientCert::new(&cert.serialize_der().unwrap());
+        let cert_headers = CertHeaders::new(&ClientCertHeaders {
+            fingerprint: None,
+            ..ClientCertHeaders::default()
+        })
+        .unwrap();
+
+        let mut headers = spoofed_headers();
+        cert_headers.insert(&mut headers, Some(&cert));
+        assert_eq!(
+            headers["x-client-cert-subject"],
+            "CN=rcgen self signed cert"
+        );
+        assert_eq!(headers["x-client-cert-san"], "DNS:admin.localhost");
+        // Not configured, so left to the client
+        assert_eq!(headers["x-client-cert-fingerprint"], "CN=admin");
+    }
+}
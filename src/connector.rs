@@ -0,0 +1,494 @@
+use crate::{
+    pem::{load_certs, load_private_key},
+    settings::{ConnectionPoolSetting, UpstreamProtocol, UpstreamTlsSetting},
+    utils::{decode_hex, hex},
+};
+use anyhow::Context as _;
+use hyper::{
+    client::{
+        connect::{Connected, Connection},
+        HttpConnector,
+    },
+    http::uri::Scheme,
+    service::Service,
+    Body, Client, Uri,
+};
+use rustls::{
+    Certificate, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError,
+    WebPKIVerifier,
+};
+use std::{
+    error::Error,
+    ffi::OsString,
+    fs::File,
+    future::Future,
+    io::{self, BufReader},
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpStream, UnixStream},
+};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+use webpki::DNSNameRef;
+
+pub type HttpClient = Client<Connector, Body>;
+
+/// Scheme of unix socket upstream uris, whose authority is the hex encoded
+/// socket path so connections to each socket are pooled separately
+pub const UNIX_SCHEME: &str = "unix";
+
+pub fn unix_authority(socket: &Path) -> String {
+    hex(socket.as_os_str().as_bytes())
+}
+
+fn unix_socket(authority: &str) -> Option<PathBuf> {
+    decode_hex(authority).map(|bytes| PathBuf::from(OsString::from_vec(bytes)))
+}
+
+/// Client keeping idle upstream connections for reuse as configured, and
+/// connecting to `https://` upstreams with `tls`
+pub fn client(
+    setting: &ConnectionPoolSetting,
+    tls: Option<&UpstreamTlsSetting>,
+    protocol: UpstreamProtocol,
+) -> anyhow::Result<HttpClient> {
+    build_client(setting, tls, protocol, None)
+}
+
+/// Client connecting to `upstream` whatever the authority of the requests,
+/// which HTTP/2 sends as `:authority`
+pub fn upstream_client(
+    setting: &ConnectionPoolSetting,
+    tls: Option<&UpstreamTlsSetting>,
+    protocol: UpstreamProtocol,
+    upstream: Uri,
+) -> anyhow::Result<HttpClient> {
+    build_client(setting, tls, protocol, Some(upstream))
+}
+
+fn build_client(
+    setting: &ConnectionPoolSetting,
+    tls: Option<&UpstreamTlsSetting>,
+    protocol: UpstreamProtocol,
+    destination: Option<Uri>,
+) -> anyhow::Result<HttpClient> {
+    let connect_timeout = Duration::from_millis(setting.connect_timeout_ms);
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    http.set_connect_timeout(Some(connect_timeout));
+    http.set_keepalive(setting.tcp_keepalive_ms.map(Duration::from_millis));
+    http.set_nodelay(setting.tcp_nodelay);
+
+    let default_tls = UpstreamTlsSetting::default();
+    let tls = tls.unwrap_or(&default_tls);
+    let connector = Connector {
+        http,
+        connect_timeout,
+        tls: TlsConnector::from(Arc::new(tls_config(tls, protocol)?)),
+        server_name: tls.server_name.as_deref().map(Arc::from),
+        destination,
+    };
+
+    Ok(Client::builder()
+        .pool_max_idle_per_host(setting.max_idle_per_host)
+        .pool_idle_timeout(Duration::from_millis(setting.idle_timeout_ms))
+        .http2_only(protocol != UpstreamProtocol::Http1)
+        .build(connector))
+}
+
+fn tls_config(
+    setting: &UpstreamTlsSetting,
+    protocol: UpstreamProtocol,
+) -> anyhow::Result<ClientConfig> {
+    let mut config = ClientConfig::new();
+    config.alpn_protocols = match protocol {
+        UpstreamProtocol::H2 => vec![b"h2".to_vec()],
+        UpstreamProtocol::Http1 | UpstreamProtocol::H2c => vec![b"http/1.1".to_vec()],
+    };
+    match &setting.ca_file {
+        Some(path) => {
+            let file = File::open(path)
+                .with_context(|| format!("Could not open CA file {}", path.display()))?;
+            match config.root_store.add_pem_file(&mut BufReader::new(file)) {
+                Ok((valid, _)) if valid > 0 => {}
+                _ => anyhow::bail!("No valid certificates in CA file {}", path.display()),
+            }
+        }
+        None => config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS),
+    }
+
+    match (&setting.cert_file, &setting.key_file) {
+        (Some(cert_file), Some(key_file)) => config
+            .set_single_client_cert(load_certs(cert_file)?, load_private_key(key_file)?)
+            .context("Invalid client certificate or key")?,
+        (None, None) => {}
+        _ => anyhow::bail!("Both cert_file and key_file are needed for a client certificate"),
+    }
+
+    if setting.insecure_skip_verify || !setting.pinned_fingerprints.is_empty() {
+        let pins = setting
+            .pinned_fingerprints
+            .iter()
+            .map(|pin| parse_fingerprint(pin))
+            .collect::<anyhow::Result<_>>()?;
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(Verifier {
+                webpki: (!setting.insecure_skip_verify).then(WebPKIVerifier::new),
+                pins,
+            }));
+    }
+    Ok(config)
+}
+
+/// Parses a hex SHA-256 fingerprint, optionally separated by colons
+fn parse_fingerprint(pin: &str) -> anyhow::Result<Vec<u8>> {
+    let fingerprint =
+        decode_hex(&pin.replace(':', "")).filter(|fingerprint| fingerprint.len() == 32);
+    fingerprint.with_context(|| format!("Invalid SHA-256 fingerprint {:?}", pin))
+}
+
+/// Checks the upstream certificate against the pinned fingerprints, after
+/// the usual verification unless it is skipped
+struct Verifier {
+    webpki: Option<WebPKIVerifier>,
+    pins: Vec<Vec<u8>>,
+}
+
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        dns_name: DNSNameRef<'_>,
+        ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        if let Some(webpki) = &self.webpki {
+            webpki.verify_server_cert(roots, presented_certs, dns_name, ocsp_response)?;
+        }
+        if self.pins.is_empty() {
+            return Ok(ServerCertVerified::assertion());
+        }
+        let cert = presented_certs
+            .first()
+            .ok_or(TLSError::NoCertificatesPresented)?;
+        let fingerprint = ring::digest::digest(&ring::digest::SHA256, &cert.0);
+        if self.pins.iter().any(|pin| pin[..] == *fingerprint.as_ref()) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(TLSError::General(
+                "Certificate does not match any pinned fingerprint".to_owned(),
+            ))
+        }
+    }
+}
+
+/// Connects to `http://` upstreams over TCP, to `https://` upstreams over
+/// TLS and to `unix://` upstreams over their socket
+#[derive(Clone)]
+pub struct Connector {
+    http: HttpConnector,
+    /// Also applied by `http` to TCP connections
+    connect_timeout: Duration,
+    tls: TlsConnector,
+    /// Overrides the upstream host as the TLS server name
+    server_name: Option<Arc<str>>,
+    /// Upstream connected to instead of the authority of the requests
+    destination: Option<Uri>,
+}
+
+impl Service<Uri> for Connector {
+    type Response = UpstreamStream;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let uri = self.destination.clone().unwrap_or(uri);
+        if uri.scheme_str() == Some(UNIX_SCHEME) {
+            let socket = uri.host().and_then(unix_socket);
+            let connect_timeout = self.connect_timeout;
+            return Box::pin(async move {
+                let socket = socket.ok_or("Invalid unix socket upstream")?;
+                let stream = tokio::time::timeout(connect_timeout, UnixStream::connect(socket))
+                    .await
+                    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connect timeout"))??;
+                Ok(UpstreamStream::Unix(stream))
+            });
+        }
+
+        let https = uri.scheme() == Some(&Scheme::HTTPS);
+        let server_name = self
+            .server_name
+            .clone()
+            .unwrap_or_else(|| Arc::from(uri.host().unwrap_or_default()));
+        let tls = self.tls.clone();
+        let connecting = self.http.call(uri);
+
+        Box::pin(async move {
+            let stream = connecting.await?;
+            if !https {
+                return Ok(UpstreamStream::Plain(stream));
+            }
+            let dns_name = DNSNameRef::try_from_ascii_str(&server_name).map_err(|_| {
+                format!(
+                    "Invalid TLS server name {:?}, set tls.server_name for IP upstreams",
+                    server_name
+                )
+            })?;
+            let stream = tls.connect(dns_name, stream).await?;
+            Ok(UpstreamStream::Tls(Box::new(stream)))
+        })
+    }
+}
+
+pub enum UpstreamStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+    Unix(UnixStream),
+}
+
+impl Connection for UpstreamStream {
+    fn connected(&self) -> Connected {
+        match self {
+            UpstreamStream::Plain(stream) => stream.connected(),
+            UpstreamStream::Tls(stream) => stream.get_ref().0.connected(),
+            UpstreamStream::Unix(_) => Connected::new(),
+        }
+    }
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa};
+
+    /// Certificate for localhost signed by a CA, and a root store trusting
+    /// that CA
+    fn trusted() -> (Certificate, RootCertStore) {
+        let mut params = CertificateParams::new(vec![]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = rcgen::Certificate::from_params(params).unwrap();
+        let leaf =
+            rcgen::Certificate::from_params(CertificateParams::new(vec!["localhost".to_owned()]))
+                .unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(&Certificate(ca.serialize_der().unwrap()))
+            .unwrap();
+        (
+            Certificate(leaf.serialize_der_with_signer(&ca).unwrap()),
+            roots,
+        )
+    }
+
+    fn self_signed() -> Certificate {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        Certificate(cert.serialize_der().unwrap())
+    }
+
+    fn fingerprint(cert: &Certificate) -> Vec<u8> {
+        ring::digest::digest(&ring::digest::SHA256, &cert.0)
+            .as_ref()
+            .to_vec()
+    }
+
+    fn verify(
+        insecure_skip_verify: bool,
+        pins: &[&Certificate],
+        roots: &RootCertStore,
+        cert: &Certificate,
+    ) -> bool {
+        let verifier = Verifier {
+            webpki: (!insecure_skip_verify).then(WebPKIVerifier::new),
+            pins: pins.iter().map(|cert| fingerprint(cert)).collect(),
+        };
+        let name = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        verifier
+            .verify_server_cert(roots, std::slice::from_ref(cert), name, &[])
+            .is_ok()
+    }
+
+    #[test]
+    fn fingerprints() {
+        let fingerprint = (0..32).collect::<Vec<u8>>();
+        let plain = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+        assert_eq!(parse_fingerprint(plain).unwrap(), fingerprint);
+        assert_eq!(
+            parse_fingerprint(&plain.to_uppercase()).unwrap(),
+            fingerprint
+        );
+        let colons = (0..32)
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<_>>()
+            .join(":");
+        assert_eq!(parse_fingerprint(&colons).unwrap(), fingerprint);
+
+        for invalid in [
+            "",
+            // 31 and 33 bytes
+            &plain[2..],
+            &format!("{}20", plain),
+            // Odd length
+            &plain[1..],
+            &format!("{}2", plain),
+            &plain.replace('a', "g"),
+            &format!("{}-", &plain[..63]),
+        ] {
+            let e = parse_fingerprint(invalid).unwrap_err().to_string();
+            assert_eq!(e, format!("Invalid SHA-256 fingerprint {:?}", invalid));
+        }
+    }
+
+    #[test]
+    fn invalid_pins_are_rejected() {
+        let setting = UpstreamTlsSetting {
+            pinned_fingerprints: vec!["00:01".to_owned()],
+            ..UpstreamTlsSetting::default()
+        };
+        assert!(tls_config(&setting, UpstreamProtocol::Http1).is_err());
+    }
+
+    #[test]
+    fn pinned_and_verified() {
+        let (leaf, roots) = trusted();
+        let other = self_signed();
+
+        assert!(verify(false, &[], &roots, &leaf));
+        assert!(verify(false, &[&leaf], &roots, &leaf));
+        assert!(verify(false, &[&other, &leaf], &roots, &leaf));
+        assert!(!verify(false, &[&other], &roots, &leaf));
+        // Pinning does not replace the verification
+        assert!(!verify(false, &[&other], &roots, &other));
+        assert!(!verify(false, &[], &roots, &other));
+    }
+
+    #[test]
+    fn pinned_without_verification() {
+        let (leaf, _) = trusted();
+        let roots = RootCertStore::empty();
+        let other = self_signed();
+
+        assert!(verify(true, &[&other], &roots, &other));
+        assert!(verify(true, &[&leaf], &roots, &leaf));
+        assert!(!verify(true, &[&leaf], &roots, &other));
+        assert!(!verify(true, &[&other], &roots, &leaf));
+        // Anything goes without pins
+        assert!(verify(true, &[], &roots, &other));
+
+        let verifier = Verifier {
+            webpki: None,
+            pins: vec![fingerprint(&other)],
+        };
+        let name = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        assert!(matches!(
+            verifier.verify_server_cert(&roots, &[], name, &[]),
+            Err(TLSError::NoCertificatesPresented)
+        ));
+    }
+
+    fn unix_uri(socket: &Path, path: &str) -> Uri {
+        format!("{}://{}{}", UNIX_SCHEME, unix_authority(socket), path)
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn unix_authorities() {
+        // Paths need not be UTF-8
+        let socket = Path::new(std::ffi::OsStr::from_bytes(b"/run/app \xff.sock"));
+        let authority = unix_authority(socket);
+        assert!(authority.bytes().all(|b| b.is_ascii_hexdigit()));
+        assert_eq!(unix_socket(&authority).as_deref(), Some(socket));
+        assert_eq!(unix_socket("2f7"), None);
+        assert_eq!(unix_socket("2fzz"), None);
+    }
+
+    #[tokio::test]
+    async fn unix_connect_failures() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let setting = ConnectionPoolSetting {
+            connect_timeout_ms: 200,
+            ..ConnectionPoolSetting::default()
+        };
+        let client = client(&setting, None, UpstreamProtocol::Http1).unwrap();
+        let get = |socket: &Path| {
+            let req = hyper::Request::get(unix_uri(socket, "/"))
+                .body(Body::empty())
+                .unwrap();
+            tokio::time::timeout(Duration::from_secs(5), client.request(req))
+        };
+
+        let e = get(&dir.path().join("missing.sock"))
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert!(e.is_connect(), "{:?}", e);
+
+        // A socket whose backlog is full and never accepts
+        let socket = dir.path().join("full.sock");
+        let _listener = tokio::net::UnixListener::bind(&socket).unwrap();
+        let mut streams = vec![];
+        while let Ok(stream) = UnixStream::connect(&socket).await {
+            streams.push(stream);
+        }
+        let e = get(&socket).await.unwrap().unwrap_err();
+        assert!(e.is_connect(), "{:?}", e);
+    }
+}
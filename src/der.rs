@@ -0,0 +1,66 @@
+/// Tag, contents and remaining input of the first DER value of `input`
+pub fn read(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    let (&len, mut input) = input.split_first()?;
+    let len = if len < 0x80 {
+        len as usize
+    } else {
+        let (len_bytes, rest) = input.split_at_checked(usize::from(len & 0x7f))?;
+        input = rest;
+        len_bytes.iter().try_fold(0usize, |len, &b| {
+            len.checked_mul(256)?.checked_add(b as usize)
+        })?
+    };
+    let (contents, rest) = input.split_at_checked(len)?;
+    Some((tag, contents, rest))
+}
+
+/// DER encoding of a value with `tag`
+pub fn encode(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let len = contents.len().to_be_bytes();
+    let len = &len[len.iter().take_while(|&&b| b == 0).count()..];
+    let mut out = vec![tag];
+    match len {
+        [] => out.push(0),
+        [short] if *short < 0x80 => out.push(*short),
+        _ => {
+            out.push(0x80 | len.len() as u8);
+            out.extend_from_slice(len);
+        }
+    }
+    out.extend_from_slice(contents);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_lengths() {
+        assert_eq!(read(b"\x04\x00"), Some((0x04, &b""[..], &b""[..])));
+        assert_eq!(
+            read(b"\x04\x02ab\x05\x00"),
+            Some((0x04, &b"ab"[..], &b"\x05\x00"[..]))
+        );
+
+        let long = vec![7; 300];
+        let encoded = encode(0x30, &long);
+        assert_eq!(&encoded[..4], b"\x30\x82\x01\x2c");
+        assert_eq!(read(&encoded), Some((0x30, &long[..], &b""[..])));
+
+        let short = vec![7; 127];
+        assert_eq!(encode(0x30, &short)[1], 127);
+        assert_eq!(&encode(0x30, &[7; 128])[1..3], b"\x81\x80");
+    }
+
+    #[test]
+    fn read_truncated() {
+        assert_eq!(read(b""), None);
+        assert_eq!(read(b"\x04"), None);
+        assert_eq!(read(b"\x04\x03ab"), None);
+        assert_eq!(read(b"\x04\x82\x01"), None);
+        // Length overflowing usize
+        assert_eq!(read(b"\x04\x89\x01\x00\x00\x00\x00\x00\x00\x00\x00"), None);
+    }
+}
@@ -0,0 +1,325 @@
+use crate::settings::ErrorPagesSetting;
+use anyhow::Context;
+use hyper::{
+    header::{self, HeaderValue},
+    Body, HeaderMap, Response, StatusCode,
+};
+use std::{cmp::Reverse, convert::TryFrom, path::Path};
+
+pub const X_REQUEST_ID: &str = "x-request-id";
+
+const GRPC_STATUS: &str = "grpc-status";
+const GRPC_MESSAGE: &str = "grpc-message";
+
+const DEFAULT_HTML: &str = "<!DOCTYPE html>
+<html>
+<head><title>{status} {reason}</title></head>
+<body>
+<h1>{status} {reason}</h1>
+<p>Request ID: {request_id}</p>
+</body>
+</html>
+";
+
+const DEFAULT_JSON: &str =
+    "{\"status\":{status},\"error\":\"{reason}\",\"request_id\":\"{request_id}\"}\n";
+
+/// Templates of the responses sent when a request to the upstream fails
+#[derive(Default)]
+pub struct ErrorPages {
+    html: Option<String>,
+    json: Option<String>,
+}
+
+impl ErrorPages {
+    pub fn new(setting: &ErrorPagesSetting) -> anyhow::Result<Self> {
+        Ok(Self {
+            html: setting.html.as_deref().map(read_template).transpose()?,
+            json: setting.json.as_deref().map(read_template).transpose()?,
+        })
+    }
+
+    /// Renders the HTML or JSON template depending on the request's `accept`
+    /// header. Templates missing here are taken from `fallback`, and then
+    /// from the built-in ones.
+    pub fn response(
+        &self,
+        fallback: &ErrorPages,
+        status: StatusCode,
+        accept: Option<&HeaderValue>,
+        request_id: &str,
+    ) -> Response<Body> {
+        let (content_type, template) = if prefers_json(accept) {
+            let template = self.json.as_ref().or(fallback.json.as_ref());
+            (
+                "application/json",
+                template.map_or(DEFAULT_JSON, String::as_str),
+            )
+        } else {
+            let template = self.html.as_ref().or(fallback.html.as_ref());
+            (
+                "text/html; charset=utf-8",
+                template.map_or(DEFAULT_HTML, String::as_str),
+            )
+        };
+
+        let body = template
+            .replace("{status}", status.as_str())
+            .replace("{reason}", status.canonical_reason().unwrap_or(""))
+            .replace("{request_id}", request_id);
+
+        let mut res = Response::new(Body::from(body));
+        *res.status_mut() = status;
+        let headers = res.headers_mut();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+        if let Ok(request_id) = HeaderValue::try_from(request_id) {
+            headers.insert(X_REQUEST_ID, request_id);
+        }
+        res
+    }
+}
+
+/// gRPC error for a failed request, sent as a trailers-only response since
+/// gRPC clients ignore the HTTP status
+pub fn grpc_response(status: StatusCode, request_id: &str) -> Response<Body> {
+    let message = format!(
+        "{} {}, request id {}",
+        status.as_str(),
+        status.canonical_reason().unwrap_or(""),
+        request_id
+    );
+
+    let mut res = Response::new(Body::empty());
+    let headers = res.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/grpc"),
+    );
+    headers.insert(GRPC_STATUS, grpc_status(status).into());
+    if let Ok(message) = HeaderValue::try_from(message) {
+        headers.insert(GRPC_MESSAGE, message);
+    }
+    if let Ok(request_id) = HeaderValue::try_from(request_id) {
+        headers.insert(X_REQUEST_ID, request_id);
+    }
+    res
+}
+
+/// gRPC status code of an error, so that clients only retry the ones which
+/// may succeed later
+fn grpc_status(status: StatusCode) -> u16 {
+    match status {
+        // INTERNAL
+        StatusCode::BAD_REQUEST => 13,
+        // UNAUTHENTICATED
+        StatusCode::UNAUTHORIZED => 16,
+        // PERMISSION_DENIED
+        StatusCode::FORBIDDEN => 7,
+        // UNIMPLEMENTED
+        StatusCode::NOT_FOUND | StatusCode::NOT_IMPLEMENTED => 12,
+        // DEADLINE_EXCEEDED
+        StatusCode::REQUEST_TIMEOUT | StatusCode::GATEWAY_TIMEOUT => 4,
+        // RESOURCE_EXHAUSTED
+        StatusCode::PAYLOAD_TOO_LARGE => 8,
+        // UNAVAILABLE
+        StatusCode::TOO_MANY_REQUESTS
+        | StatusCode::BAD_GATEWAY
+        | StatusCode::SERVICE_UNAVAILABLE => 14,
+        // UNKNOWN
+        _ => 2,
+    }
+}
+
+fn read_template(path: &Path) -> anyhow::Result<String> {
+    std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read error page {}", path.display()))
+}
+
+/// Whether the `Accept` header gives JSON a higher quality than HTML, or
+/// the same quality through a more specific or an earlier media range
+fn prefers_json(accept: Option<&HeaderValue>) -> bool {
+    let accept = match accept.and_then(|v| v.to_str().ok()) {
+        Some(accept) => accept,
+        None => return false,
+    };
+    let json = quality(accept, "application", "json");
+    json.0 > 0 && json > quality(accept, "text", "html")
+}
+
+/// Quality in thousandths given to a media type by the most specific media
+/// range of an `Accept` header matching it, with the specificity of the
+/// range, 2 for `type/subtype`, 1 for `type/*` and 0 for `*/*`, and its
+/// position
+fn quality(accept: &str, kind: &str, subtype: &str) -> (u16, u8, Reverse<usize>) {
+    accept
+        .split(',')
+        .enumerate()
+        .filter_map(|(position, range)| {
+            let mut params = range.split(';');
+            let (range_kind, range_subtype) = params.next()?.trim().split_once('/')?;
+            let specificity = match (range_kind.trim(), range_subtype.trim()) {
+                (k, s) if k.eq_ignore_ascii_case(kind) && s.eq_ignore_ascii_case(subtype) => 2,
+                (k, "*") if k.eq_ignore_ascii_case(kind) => 1,
+                ("*", "*") => 0,
+                _ => return None,
+            };
+            let q = params
+                .filter_map(|param| param.split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+                .map_or(Some(1.0), |(_, q)| q.trim().parse::<f32>().ok())?;
+            let q = (q.clamp(0.0, 1.0) * 1000.0).round() as u16;
+            Some((q, specificity, Reverse(position)))
+        })
+        // The first of the most specific ranges
+        .max_by_key(|&(_, specificity, position)| (specificity, position))
+        .unwrap_or((0, 0, Reverse(usize::MAX)))
+}
+
+/// Uses the client's `x-request-id` if it is a short token, so it can be
+/// put in templates as is, and generates one otherwise
+pub fn request_id(headers: &HeaderMap) -> String {
+    headers
+        .get(X_REQUEST_ID)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= 128
+                && id
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b))
+        })
+        .map_or_else(|| format!("{:016x}", rand::random::<u64>()), str::to_owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn accept_negotiation() {
+        for (accept, json) in [
+            ("application/json", true),
+            ("text/html", false),
+            ("application/json, text/html", true),
+            ("text/html, application/json", false),
+            ("text/html;q=0.5, application/json", true),
+            ("application/json;q=0.5, text/html", false),
+            ("application/json;q=0", false),
+            ("application/json; q=0.000", false),
+            ("Application/JSON;Q=0.9, text/*;q=0.8", true),
+            // More specific ranges win at the same quality
+            ("application/json, text/*", true),
+            ("text/html, application/*", false),
+            ("*/*", false),
+            ("*/*;q=0.1, application/json;q=0.2", true),
+            ("application/*;q=0.9, text/html;q=0.9", false),
+            // Specific ranges override wider ones
+            ("application/json;q=0, */*", false),
+            ("application/*, application/json;q=0", false),
+            // Only JSON itself
+            ("application/problem+json", false),
+            ("text/jsonl", false),
+            ("", false),
+            ("garbage", false),
+            ("application/json;q=abc", false),
+        ] {
+            let accept = HeaderValue::from_static(accept);
+            assert_eq!(prefers_json(Some(&accept)), json, "{:?}", accept);
+        }
+        assert!(!prefers_json(None));
+    }
+
+    fn body(res: Response<Body>) -> String {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let body = runtime
+            .block_on(hyper::body::to_bytes(res.into_body()))
+            .unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn templates() {
+        let dir = TempDir::new().unwrap();
+        let html = dir.path().join("error.html");
+        std::fs::write(&html, "<p>{status} {reason} ({request_id})</p>").unwrap();
+        let pages = ErrorPages::new(&ErrorPagesSetting {
+            html: Some(html),
+            json: None,
+        })
+        .unwrap();
+        let json = HeaderValue::from_static("application/json");
+
+        let res = pages.response(
+            &ErrorPages::default(),
+            StatusCode::BAD_GATEWAY,
+            None,
+            "abc-1",
+        );
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(res.headers()[X_REQUEST_ID], "abc-1");
+        assert_eq!(
+            res.headers()[header::CONTENT_TYPE],
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(body(res), "<p>502 Bad Gateway (abc-1)</p>");
+
+        // Built-in page for the format without a template
+        let res = pages.response(
+            &ErrorPages::default(),
+            StatusCode::GATEWAY_TIMEOUT,
+            Some(&json),
+            "abc-2",
+        );
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(
+            body(res),
+            "{\"status\":504,\"error\":\"Gateway Timeout\",\"request_id\":\"abc-2\"}\n"
+        );
+    }
+
+    #[test]
+    fn routes_without_templates() {
+        let dir = TempDir::new().unwrap();
+        let json = dir.path().join("error.json");
+        std::fs::write(&json, "{\"id\":\"{request_id}\"}").unwrap();
+        let global = ErrorPages::new(&ErrorPagesSetting {
+            html: None,
+            json: Some(json),
+        })
+        .unwrap();
+        let route = ErrorPages::default();
+        let accept = HeaderValue::from_static("application/json");
+
+        // Global template, then the built-in one
+        let res = route.response(&global, StatusCode::BAD_GATEWAY, Some(&accept), "abc");
+        assert_eq!(body(res), "{\"id\":\"abc\"}");
+        let res = route.response(&global, StatusCode::BAD_GATEWAY, None, "abc");
+        assert!(body(res).contains("<h1>502 Bad Gateway</h1>"));
+
+        assert!(ErrorPages::new(&ErrorPagesSetting {
+            html: Some(dir.path().join("missing.html")),
+            json: None,
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn grpc_statuses() {
+        for (status, grpc_status) in [
+            (StatusCode::FORBIDDEN, "7"),
+            (StatusCode::NOT_IMPLEMENTED, "12"),
+            (StatusCode::GATEWAY_TIMEOUT, "4"),
+            (StatusCode::BAD_GATEWAY, "14"),
+            (StatusCode::SERVICE_UNAVAILABLE, "14"),
+            (StatusCode::PAYLOAD_TOO_LARGE, "8"),
+        ] {
+            let res = grpc_response(status, "abc");
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers()[GRPC_STATUS], grpc_status, "{}", status);
+            assert_eq!(res.headers()[X_REQUEST_ID], "abc");
+        }
+    }
+}
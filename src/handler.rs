@@ -1,49 +1,345 @@
+use crate::{
+    body::{RequestBody, ResponseBody},
+    client_cert::{CertHeaders, ClientCert},
+    connector::HttpClient,
+    error_page::{self, ErrorPages},
+    router::{Route, Router},
+    settings::{RetryOn, RetrySetting, TimeoutSetting, UnmatchedSetting},
+    timeout::{self, ClientBodyTimeout},
+    upstream::Upstream,
+};
+use anyhow::Context;
 use hyper::{
+    body::{Bytes, HttpBody},
     header::{self, HeaderValue},
-    http::uri,
-    Body, Client, HeaderMap, Request, Response, StatusCode, Uri,
+    Body, HeaderMap, Request, Response, StatusCode, Version,
 };
 use lazy_static::lazy_static;
-use std::{collections::HashMap, convert::TryInto, net::SocketAddr, sync::Arc};
-use tracing::{error, info};
+use std::{convert::TryInto, io, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::time::{error::Elapsed, Instant};
+use tracing::{error, info, warn};
 
 pub struct Handler {
-    servers_map: HashMap<String, String>,
+    router: Router,
+    unmatched: UnmatchedResponse,
+    /// Error pages of the routes without their own
+    error_pages: ErrorPages,
+    /// Client shared by the upstream pools without their own
+    client: HttpClient,
+    timeouts: TimeoutSetting,
     tls: bool,
+    /// Advertises HTTP/3 on the responses of the TCP listeners
+    alt_svc: Option<HeaderValue>,
+    /// Removed from every request, and set from the certificate of clients
+    /// sending one when the listener asks for them
+    cert_headers: CertHeaders,
 }
 
 impl Handler {
-    pub fn new(servers_map: HashMap<String, String>, tls: bool) -> Self {
-        Self { servers_map, tls }
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        router: Router,
+        unmatched: UnmatchedResponse,
+        error_pages: ErrorPages,
+        client: HttpClient,
+        timeouts: TimeoutSetting,
+        tls: bool,
+        alt_svc: Option<HeaderValue>,
+        cert_headers: CertHeaders,
+    ) -> Self {
+        Self {
+            router,
+            unmatched,
+            error_pages,
+            client,
+            timeouts,
+            tls,
+            alt_svc,
+            cert_headers,
+        }
     }
 
     pub async fn handle_client(
         self: Arc<Self>,
         addr: SocketAddr,
+        req: Request<Body>,
+    ) -> anyhow::Result<Response<ResponseBody>> {
+        let http3 = req.version() == Version::HTTP_3;
+        let mut res = self.handle(addr, req).await?;
+        if let (Some(alt_svc), false) = (&self.alt_svc, http3) {
+            res.headers_mut().insert(header::ALT_SVC, alt_svc.clone());
+        }
+        Ok(res)
+    }
+
+    async fn handle(
+        &self,
+        addr: SocketAddr,
         mut req: Request<Body>,
-    ) -> anyhow::Result<Response<Body>> {
+    ) -> anyhow::Result<Response<ResponseBody>> {
         info!("{:?}", &req);
 
-        let client = Client::new();
-        let host = req.headers().get(header::HOST);
+        let host = client_host(&req).map(str::to_owned);
 
         // TODO: Decide if host or authority from uri is to be used
-        let uri: Uri = host
-            .and_then(|host| host.to_str().ok())
-            .and_then(|host| self.servers_map.get(host))
-            .and_then(|uri| uri.parse().ok())
-            .unwrap_or("http://127.0.0.1:8000/".parse()?);
-
-        let uri_parts = uri.into_parts();
-        let uri_builder = Uri::builder()
-            .scheme(uri_parts.scheme.unwrap_or(uri::Scheme::HTTP))
-            .authority(uri_parts.authority.unwrap())
-            .path_and_query((req.uri().path_and_query().unwrap()).clone());
+        let route = match self.router.route(host.as_deref(), req.uri().path()) {
+            Some(route) => route,
+            None => {
+                info!("No route for host {:?}", host);
+                return Ok(self.unmatched.response().map(Into::into));
+            }
+        };
+
+        let request_id = error_page::request_id(req.headers());
+        req.headers_mut()
+            .insert(error_page::X_REQUEST_ID, request_id.parse()?);
+        let accept = req.headers().get(header::ACCEPT).cloned();
+        let grpc = is_grpc(req.headers());
+
+        let has_client_cert = req.extensions().get::<Arc<ClientCert>>().is_some();
+        if route.options.require_client_cert == Some(true) && !has_client_cert {
+            info!(
+                "No client certificate for request {} from {}",
+                request_id, addr
+            );
+            return Ok(self
+                .error_response(
+                    &route,
+                    StatusCode::FORBIDDEN,
+                    grpc,
+                    accept.as_ref(),
+                    &request_id,
+                )
+                .map(Into::into));
+        }
+
+        let started = Instant::now();
+        let deadline = |timeout: Option<u64>| timeout.map(|ms| started + Duration::from_millis(ms));
+        let total_deadline = deadline(self.timeouts.total_ms);
+        let headers_deadline = deadline(self.timeouts.response_headers_ms)
+            .into_iter()
+            .chain(total_deadline)
+            .min();
+
+        let proxy = self.proxy(addr, req, &route, grpc, &request_id);
+        let res = match headers_deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, proxy).await {
+                Ok(res) => res,
+                Err(elapsed) => Err(elapsed.into()),
+            },
+            None => proxy.await,
+        };
+
+        let status = match res {
+            Ok(res) => {
+                return Ok(match total_deadline {
+                    Some(deadline) => res.map(|body| body.with_deadline(deadline)),
+                    None => res,
+                })
+            }
+            Err(e) if e.chain().any(|e| e.is::<ClientBodyTimeout>()) => {
+                warn!("Timed out reading request body from {}", addr);
+                return Ok(status_response(StatusCode::REQUEST_TIMEOUT).map(Into::into));
+            }
+            Err(e) if is_timeout(&e) => {
+                warn!("Request {} timed out: {:#}", request_id, e);
+                StatusCode::GATEWAY_TIMEOUT
+            }
+            Err(e) => {
+                error!("Request {} failed: {:#}", request_id, e);
+                StatusCode::BAD_GATEWAY
+            }
+        };
+        Ok(self
+            .error_response(&route, status, grpc, accept.as_ref(), &request_id)
+            .map(Into::into))
+    }
+
+    async fn proxy(
+        &self,
+        addr: SocketAddr,
+        mut req: Request<Body>,
+        route: &Route,
+        grpc: bool,
+        request_id: &str,
+    ) -> anyhow::Result<Response<ResponseBody>> {
+        let unavailable = |req: &Request<Body>| {
+            let accept = req.headers().get(header::ACCEPT);
+            self.error_response(
+                route,
+                StatusCode::SERVICE_UNAVAILABLE,
+                grpc,
+                accept,
+                request_id,
+            )
+            .map(Into::into)
+        };
+
+        // HTTP/1 responses have no trailers to carry the gRPC status
+        if grpc && route.pool.version() != Version::HTTP_2 {
+            error!(
+                "gRPC request {} is proxied to an HTTP/1 upstream pool",
+                request_id
+            );
+            return Ok(self
+                .error_response(
+                    route,
+                    StatusCode::NOT_IMPLEMENTED,
+                    grpc,
+                    req.headers().get(header::ACCEPT),
+                    request_id,
+                )
+                .map(Into::into));
+        }
+
+        let retry = route
+            .options
+            .retry
+            .as_ref()
+            .filter(|retry| retry.allows(req.method()));
+
+        let body = std::mem::take(req.body_mut());
+        let len = body.size_hint().exact();
+        let mut body = match (&route.options.buffer_request_body, retry) {
+            // Streaming calls can stay quiet for long and must not be buffered
+            _ if grpc => RequestBody::Streaming(Some(body)),
+            (Some(setting), _) => match RequestBody::buffer(self.body(body), len, setting).await? {
+                Some(body) => body,
+                None => return Ok(status_response(StatusCode::PAYLOAD_TOO_LARGE).map(Into::into)),
+            },
+            (None, Some(retry)) => {
+                RequestBody::for_retry(self.body(body), len, retry.max_body_bytes).await?
+            }
+            (None, None) => RequestBody::Streaming(Some(self.body(body))),
+        };
+
+        let retry = retry.filter(|_| body.is_replayable());
+        let max_attempts = retry.map_or(1, |retry| retry.max_attempts.max(1));
+        let mut tried = vec![];
+        let mut attempt = 1;
+
+        let (mut res, in_flight) = loop {
+            let upstream = match route.pool.select(addr.ip(), req.headers(), &tried) {
+                Some(upstream) => upstream,
+                None => {
+                    error!("No upstream available for request {}", request_id);
+                    return Ok(unavailable(&req));
+                }
+            };
+
+            let mut new_req =
+                self.upstream_request(&req, &upstream, route.pool.version(), addr, &mut body)?;
+            let (new_body, sent) = timeout::body_sent(std::mem::take(new_req.body_mut()));
+            *new_req.body_mut() = new_body;
+
+            info!("{:?}", &new_req);
+
+            let in_flight = match upstream.start_request().await {
+                Some(in_flight) => in_flight,
+                None => {
+                    warn!("Circuit breaker of upstream {} is open", upstream);
+                    return Ok(unavailable(&req));
+                }
+            };
+            let client = upstream
+                .client()
+                .or_else(|| route.pool.client())
+                .unwrap_or(&self.client);
+            let request = client.request(new_req);
+            let response_timeout = Duration::from_millis(self.timeouts.upstream_response_ms);
+            let response_timeout = retry
+                .and_then(RetrySetting::per_try_timeout)
+                .map_or(response_timeout, |timeout| timeout.min(response_timeout));
+            tokio::pin!(request);
+            // Sending the body is only bounded by the client body timeout,
+            // an upstream may also answer before reading all of it
+            let res = tokio::select! {
+                res = &mut request => Ok(res),
+                () = sent => tokio::time::timeout(response_timeout, &mut request).await,
+            };
+            let res = match res {
+                Ok(res) => res.map_err(anyhow::Error::from),
+                Err(elapsed) => Err(elapsed.into()),
+            };
+            match &res {
+                Ok(res) if res.status().is_server_error() => upstream.record_failure(),
+                Ok(_) => upstream.record_success(),
+                Err(e) if is_connect_error(e) => upstream.record_failure(),
+                // Timeouts and failures of the client's body are not the
+                // upstream's fault
+                Err(_) => {}
+            }
+
+            let retry_on = match &res {
+                Ok(res) => RetryOn::from_status(res.status()),
+                Err(e) if is_connect_error(e) => Some(RetryOn::ConnectFailure),
+                Err(e) if e.is::<Elapsed>() => Some(RetryOn::GatewayTimeout),
+                Err(_) => None,
+            };
+            match (retry, retry_on) {
+                (Some(retry), Some(retry_on))
+                    if attempt < max_attempts && retry.retry_on.contains(&retry_on) =>
+                {
+                    warn!("Retrying after {:?} from upstream {}", retry_on, upstream);
+                    tokio::time::sleep(retry.backoff(attempt)).await;
+                    tried.push(upstream);
+                    attempt += 1;
+                }
+                _ => break (res?, in_flight),
+            }
+        };
+
+        info!("{:?}", &res);
+
+        let res = if res.status() == StatusCode::SWITCHING_PROTOCOLS {
+            handle_upgrade(req, res).await?
+        } else {
+            strip_connection_and_hop_headers(res.headers_mut());
+            res
+        };
+        Ok(res.map(|body| ResponseBody::new(body, in_flight)))
+    }
+}
+
+impl Handler {
+    /// Client request body failing if the client stalls
+    fn body(&self, body: Body) -> Body {
+        timeout::body(body, Duration::from_millis(self.timeouts.client_body_ms))
+    }
+
+    /// Response for a request which could not be proxied
+    fn error_response(
+        &self,
+        route: &Route,
+        status: StatusCode,
+        grpc: bool,
+        accept: Option<&HeaderValue>,
+        request_id: &str,
+    ) -> Response<Body> {
+        if grpc {
+            error_page::grpc_response(status, request_id)
+        } else {
+            route
+                .error_pages
+                .response(&self.error_pages, status, accept, request_id)
+        }
+    }
+
+    /// Copies the client request for sending to `upstream` as `version`
+    fn upstream_request(
+        &self,
+        req: &Request<Body>,
+        upstream: &Upstream,
+        version: Version,
+        addr: SocketAddr,
+        body: &mut RequestBody,
+    ) -> anyhow::Result<Request<Body>> {
+        let path_and_query = req.uri().path_and_query().map_or("/", |pq| pq.as_str());
 
         let mut new_req_builder = Request::builder()
             .method(req.method())
-            .uri(uri_builder.build()?)
-            .version(req.version());
+            .uri(upstream.uri(client_host(req), path_and_query)?)
+            .version(version);
 
         for (key, value) in req.headers() {
             new_req_builder = new_req_builder.header(key, value);
@@ -53,6 +349,27 @@ impl Handler {
 
         strip_connection_and_hop_headers(new_headers_mut);
 
+        // The only transfer coding allowed in HTTP/2, needed by gRPC
+        if accepts_trailers(req.headers()) {
+            new_headers_mut.insert(header::TE, HeaderValue::from_static("trailers"));
+        }
+
+        if version == Version::HTTP_2 {
+            // The client's host is sent as `:authority` instead
+            new_headers_mut.remove(header::HOST);
+        } else if !new_headers_mut.contains_key(header::HOST) {
+            match req.uri().authority() {
+                Some(authority) => {
+                    new_headers_mut.insert(header::HOST, authority.as_str().try_into()?);
+                }
+                // Instead of the hex encoded socket path hyper would send
+                None if upstream.is_unix() => {
+                    new_headers_mut.insert(header::HOST, HeaderValue::from_static("localhost"));
+                }
+                None => {}
+            }
+        }
+
         let upgrade_type = find_upgrade_type(req.headers());
         if let Some(upgrade) = upgrade_type {
             new_headers_mut.insert(header::CONNECTION, header::UPGRADE.into());
@@ -60,26 +377,79 @@ impl Handler {
         }
 
         insert_forwarded_headers(new_headers_mut, addr, self.tls);
+        let client_cert = req.extensions().get::<Arc<ClientCert>>();
+        self.cert_headers
+            .insert(new_headers_mut, client_cert.map(|cert| &**cert));
 
-        // TODO: This creates a copy of req body in memory. any way to avoid it?
-        let body = hyper::body::to_bytes(req.body_mut()).await?;
-        let new_req = new_req_builder.body(hyper::Body::from(body))?;
+        // Bodies read in full are no longer chunked, but requests without a
+        // body stay without a length
+        let framed = req.headers().contains_key(header::CONTENT_LENGTH)
+            || req.headers().contains_key(header::TRANSFER_ENCODING);
+        if let Some(len) = body.len().filter(|&len| len > 0 || framed) {
+            new_headers_mut.insert(header::CONTENT_LENGTH, len.into());
+        }
 
-        info!("{:?}", &new_req);
+        let body = body.take().context("Request body was already sent")?;
+        Ok(new_req_builder.body(body)?)
+    }
+}
 
-        let mut res = client.request(new_req).await?;
+/// Response sent when the router finds no backend for a request
+pub struct UnmatchedResponse {
+    status: StatusCode,
+    page: Option<Bytes>,
+}
 
-        info!("{:?}", &res);
+impl UnmatchedResponse {
+    pub fn new(setting: UnmatchedSetting) -> anyhow::Result<Self> {
+        let status = match setting.status {
+            404 => StatusCode::NOT_FOUND,
+            421 => StatusCode::MISDIRECTED_REQUEST,
+            status => anyhow::bail!("Unmatched status must be 404 or 421, got {}", status),
+        };
+        let page = setting
+            .page
+            .map(|path| {
+                std::fs::read(&path)
+                    .with_context(|| format!("Could not read unmatched page {}", path.display()))
+            })
+            .transpose()?
+            .map(Bytes::from);
+        Ok(Self { status, page })
+    }
 
-        Ok(if res.status() == StatusCode::SWITCHING_PROTOCOLS {
-            handle_upgrade(req, res).await?
-        } else {
-            strip_connection_and_hop_headers(res.headers_mut());
-            res
-        })
+    fn response(&self) -> Response<Body> {
+        let (content_type, body) = match &self.page {
+            Some(page) => ("text/html; charset=utf-8", Body::from(page.clone())),
+            None => (
+                "text/plain; charset=utf-8",
+                Body::from(format!("{}\n", self.status)),
+            ),
+        };
+        let mut res = Response::new(body);
+        *res.status_mut() = self.status;
+        res.headers_mut()
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+        res
     }
 }
 
+fn status_response(status: StatusCode) -> Response<Body> {
+    let mut res = Response::new(Body::empty());
+    *res.status_mut() = status;
+    res
+}
+
+/// Whether the error comes from an elapsed timeout or an upstream connect
+/// timeout
+fn is_timeout(e: &anyhow::Error) -> bool {
+    e.chain().any(|e| {
+        e.is::<Elapsed>()
+            || e.downcast_ref::<io::Error>()
+                .is_some_and(|e| e.kind() == io::ErrorKind::TimedOut)
+    })
+}
+
 async fn handle_upgrade(
     mut req: Request<Body>,
     mut res: Response<Body>,
@@ -109,6 +479,34 @@ async fn handle_upgrade(
     Ok(res)
 }
 
+/// Host asked for by the client, which HTTP/2 and HTTP/3 clients send as
+/// the uri authority
+fn client_host(req: &Request<Body>) -> Option<&str> {
+    req.headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| req.uri().authority().map(|authority| authority.as_str()))
+}
+
+fn is_connect_error(e: &anyhow::Error) -> bool {
+    e.downcast_ref().is_some_and(hyper::Error::is_connect)
+}
+
+fn is_grpc(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type.as_bytes().starts_with(b"application/grpc"))
+}
+
+fn accepts_trailers(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::TE)
+        .iter()
+        .filter_map(|te| te.to_str().ok())
+        .flat_map(|te| te.split(','))
+        .any(|coding| coding.split(';').next().unwrap_or("").trim() == "trailers")
+}
+
 fn find_upgrade_type(headers: &HeaderMap<HeaderValue>) -> Option<&[u8]> {
     let is_upgrade = headers
         .get(header::CONNECTION)
@@ -156,8 +554,8 @@ fn strip_connection_and_hop_headers(headers_mut: &mut HeaderMap<HeaderValue>) {
 }
 
 fn insert_forwarded_headers(headers_mut: &mut HeaderMap<HeaderValue>, addr: SocketAddr, tls: bool) {
-    const X_FORWARDED_FOR: &'static str = "X-Forwarded-For";
-    const X_FORWARDED_PROTO: &'static str = "X-Forwarded-Proto";
+    const X_FORWARDED_FOR: &str = "X-Forwarded-For";
+    const X_FORWARDED_PROTO: &str = "X-Forwarded-Proto";
 
     let client_ip = addr.ip();
 
@@ -181,3 +579,323 @@ fn insert_forwarded_headers(headers_mut: &mut HeaderMap<HeaderValue>, addr: Sock
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        connector,
+        settings::{Settings, UpstreamProtocol},
+        upstream::Upstreams,
+    };
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Server,
+    };
+    use std::convert::Infallible;
+
+    /// Upstream answering every request with its path
+    fn upstream() -> SocketAddr {
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                Ok::<_, Infallible>(Response::new(Body::from(req.uri().path().to_owned())))
+            }))
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    fn handler(settings: serde_json::Value) -> Arc<Handler> {
+        let settings: Settings = serde_json::from_value(settings).unwrap();
+        let mut upstreams = Upstreams::new(settings.upstreams, &settings.connection_pool).unwrap();
+        let router =
+            Router::new(settings.servers, settings.default_server, &mut upstreams).unwrap();
+        let client =
+            connector::client(&settings.connection_pool, None, UpstreamProtocol::Http1).unwrap();
+        Arc::new(Handler::new(
+            router,
+            UnmatchedResponse::new(settings.unmatched).unwrap(),
+            ErrorPages::new(&settings.error_pages).unwrap(),
+            client,
+            settings.timeouts,
+            true,
+            None,
+            CertHeaders::new(&settings.tls.client_auth.headers).unwrap(),
+        ))
+    }
+
+    fn client_addr() -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 50000))
+    }
+
+    #[tokio::test]
+    async fn require_client_cert() {
+        let upstream = upstream();
+        let handler = handler(serde_json::json!({
+            "servers": [{
+                "host": "localhost",
+                "proxy_pass": format!("http://{}", upstream),
+                "locations": [{
+                    "path": "/admin/",
+                    "proxy_pass": format!("http://{}", upstream),
+                    "require_client_cert": true,
+                }],
+            }],
+        }));
+        let request = |grpc: bool, cert: bool| {
+            let mut req = Request::post("/admin/users").header(header::HOST, "localhost");
+            if grpc {
+                req = req.header(header::CONTENT_TYPE, "application/grpc");
+            }
+            let mut req = req.body(Body::empty()).unwrap();
+            if cert {
+                let cert = rcgen::generate_simple_self_signed(vec!["admin".to_owned()]).unwrap();
+                let cert = ClientCert::new(&cert.serialize_der().unwrap());
+                req.extensions_mut().insert(Arc::new(cert));
+            }
+            handler.clone().handle_client(client_addr(), req)
+        };
+
+        let res = request(false, false).await.unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            res.headers()[header::CONTENT_TYPE],
+            "text/html; charset=utf-8"
+        );
+
+        // Trailers-only, with a status clients do not retry
+        let res = request(true, false).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["grpc-status"], "7");
+
+        let res = request(false, true).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(body, "/admin/users");
+
+        // Past the certificate check, but the upstream only speaks HTTP/1
+        let res = request(true, true).await.unwrap();
+        assert_eq!(res.headers()["grpc-status"], "12");
+
+        // Other locations do not need one
+        let req = Request::get("/public")
+            .header(header::HOST, "localhost")
+            .body(Body::empty())
+            .unwrap();
+        let res = handler
+            .clone()
+            .handle_client(client_addr(), req)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn buffered_body_limit() {
+        let upstream = upstream();
+        let dir = tempfile::TempDir::new().unwrap();
+        let handler = handler(serde_json::json!({
+            "servers": [{
+                "host": "localhost",
+                "proxy_pass": format!("http://{}", upstream),
+                "buffer_request_body": {
+                    "max_bytes": 100,
+                    "memory_bytes": 10,
+                    "spill_dir": dir.path(),
+                },
+            }],
+        }));
+        let request = |body: Body| {
+            let req = Request::post("/upload")
+                .header(header::HOST, "localhost")
+                .body(body)
+                .unwrap();
+            handler.clone().handle_client(client_addr(), req)
+        };
+        let chunked = |len: usize| {
+            let chunk = vec![b'a'; len / 2];
+            let chunks = [chunk.clone(), chunk].map(Ok::<_, std::io::Error>);
+            Body::wrap_stream(futures::stream::iter(chunks))
+        };
+
+        // Spilled to disk
+        let res = request(chunked(100)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = request(Body::from(vec![b'a'; 101])).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let res = request(chunked(102)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn unix_socket_upstream() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let socket = dir.path().join("app.sock");
+        let listener = tokio::net::UnixListener::bind(&socket).unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let service = service_fn(|req: Request<Body>| async move {
+                    let host = req.headers()[header::HOST].to_str().unwrap().to_owned();
+                    let body = format!("{} {}", host, req.uri());
+                    Ok::<_, Infallible>(Response::new(Body::from(body)))
+                });
+                tokio::spawn(hyper::server::conn::Http::new().serve_connection(stream, service));
+            }
+        });
+        let proxy_pass = format!("unix:{}:/app", socket.display());
+        let handler = handler(serde_json::json!({
+            "servers": [{ "host": "localhost", "proxy_pass": proxy_pass }],
+            "default_server": { "proxy_pass": proxy_pass },
+        }));
+        let request = |req: Request<Body>| handler.clone().handle_client(client_addr(), req);
+
+        let req = Request::get("/path?query")
+            .header(header::HOST, "localhost:8080")
+            .body(Body::empty())
+            .unwrap();
+        let res = request(req).await.unwrap();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(body, "localhost:8080 /app/path?query");
+
+        // HTTP/1.0 clients may not send a host
+        let req = Request::get("/path")
+            .version(Version::HTTP_10)
+            .body(Body::empty())
+            .unwrap();
+        let res = request(req).await.unwrap();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(body, "localhost /app/path");
+    }
+
+    #[tokio::test]
+    async fn total_timeout() {
+        // Sends the headers after 300ms, or a body over 300ms
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                if req.uri().path() == "/slow-headers" {
+                    tokio::time::sleep(Duration::from_millis(300)).await;
+                    return Ok(Response::new(Body::empty()));
+                }
+                let (mut sender, body) = Body::channel();
+                tokio::spawn(async move {
+                    for _ in 0..10 {
+                        tokio::time::sleep(Duration::from_millis(30)).await;
+                        if sender.send_data("chunk".into()).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+                Ok::<_, Infallible>(Response::new(body))
+            }))
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let upstream = server.local_addr();
+        tokio::spawn(server);
+        let handler = |timeouts: serde_json::Value| {
+            handler(serde_json::json!({
+                "servers": [{ "host": "localhost", "proxy_pass": format!("http://{}", upstream) }],
+                "timeouts": timeouts,
+            }))
+        };
+        let request = |handler: &Arc<Handler>, path: &str| {
+            let req = Request::get(path)
+                .header(header::HOST, "localhost")
+                .body(Body::empty())
+                .unwrap();
+            handler.clone().handle_client(client_addr(), req)
+        };
+
+        let handler_total = handler(serde_json::json!({ "total_ms": 150 }));
+        let res = request(&handler_total, "/slow-headers").await.unwrap();
+        assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+
+        let started = Instant::now();
+        let res = request(&handler_total, "/trickle").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let err = hyper::body::to_bytes(res.into_body()).await.unwrap_err();
+        assert!(err.is::<timeout::RequestTimeout>());
+        assert!(started.elapsed() < Duration::from_millis(250));
+
+        // Only bounds the wait for the headers
+        let handler_headers = handler(serde_json::json!({ "response_headers_ms": 150 }));
+        let res = request(&handler_headers, "/slow-headers").await.unwrap();
+        assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+        let res = request(&handler_headers, "/trickle").await.unwrap();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(body, "chunk".repeat(10));
+    }
+
+    #[tokio::test]
+    async fn grpc_trailers() {
+        // h2c upstream echoing the request body, with the gRPC status in
+        // the trailers if the client accepts them
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                let te = req.headers().get(header::TE).cloned();
+                let mut body = req.into_body();
+                let (mut sender, res_body) = Body::channel();
+                tokio::spawn(async move {
+                    while let Some(chunk) = body.data().await {
+                        sender.send_data(chunk?).await?;
+                    }
+                    let mut trailers = HeaderMap::new();
+                    if te.is_some_and(|te| te == "trailers") {
+                        trailers.insert("grpc-status", HeaderValue::from_static("0"));
+                    }
+                    sender.send_trailers(trailers).await
+                });
+                let mut res = Response::new(res_body);
+                res.headers_mut().insert(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static("application/grpc"),
+                );
+                Ok::<_, Infallible>(res)
+            }))
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .http2_only(true)
+            .serve(make_service);
+        let upstream = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        let handler = handler(serde_json::json!({
+            "upstreams": [
+                { "name": "h2c", "protocol": "h2c", "servers": [{ "address": upstream }] },
+                { "name": "http1", "servers": [{ "address": upstream }] },
+            ],
+            "servers": [
+                { "host": "h2c.localhost", "proxy_pass": "http://h2c" },
+                { "host": "http1.localhost", "proxy_pass": "http://http1" },
+            ],
+        }));
+        let request = |host: &str| {
+            let req = Request::post("/helloworld.Greeter/SayHello")
+                .version(Version::HTTP_2)
+                .header(header::HOST, host)
+                .header(header::CONTENT_TYPE, "application/grpc")
+                .header(header::TE, "trailers")
+                .body(Body::from("message"))
+                .unwrap();
+            handler.clone().handle_client(client_addr(), req)
+        };
+
+        let res = request("h2c.localhost").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "application/grpc");
+        let mut body = res.into_body();
+        let mut data = vec![];
+        while let Some(chunk) = body.data().await {
+            data.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(data, b"message");
+        let trailers = body.trailers().await.unwrap().unwrap();
+        assert_eq!(trailers["grpc-status"], "0");
+
+        // Trailers-only UNIMPLEMENTED instead of losing the trailers
+        let res = request("http1.localhost").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["grpc-status"], "12");
+        assert!(res.into_body().is_end_stream());
+    }
+}
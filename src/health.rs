@@ -0,0 +1,259 @@
+use crate::{
+    connector::HttpClient,
+    settings::HealthCheckSetting,
+    upstream::{Upstream, Upstreams},
+};
+use anyhow::Context;
+use hyper::{header, Body, Request};
+use std::{sync::Arc, time::Duration};
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, info, warn};
+
+/// Spawns a probe task for every upstream of the pools with a health check.
+/// Probes use the upstream's or the pool's own client if there is one, else
+/// `client`.
+pub fn start(upstreams: &Upstreams, client: &HttpClient) {
+    for pool in upstreams.pools() {
+        if let Some(setting) = pool.health_check() {
+            for upstream in pool.upstreams() {
+                tokio::spawn(check_upstream(
+                    upstream
+                        .client()
+                        .or_else(|| pool.client())
+                        .unwrap_or(client)
+                        .clone(),
+                    upstream.clone(),
+                    setting.clone(),
+                ));
+            }
+        }
+    }
+}
+
+async fn check_upstream(client: HttpClient, upstream: Arc<Upstream>, setting: HealthCheckSetting) {
+    let mut interval = tokio::time::interval(Duration::from_millis(setting.interval_ms));
+    // Probes slower than the interval are not followed by a burst of others
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut streak = Streak::default();
+
+    loop {
+        interval.tick().await;
+        let result = probe(&client, &upstream, &setting).await;
+        streak.record(&upstream, &setting, result);
+    }
+}
+
+/// Consecutive probe results of an upstream, changing its health once
+/// `rise` succeed or `fall` fail
+#[derive(Default)]
+struct Streak {
+    successes: u32,
+    failures: u32,
+}
+
+impl Streak {
+    fn record(
+        &mut self,
+        upstream: &Upstream,
+        setting: &HealthCheckSetting,
+        result: anyhow::Result<()>,
+    ) {
+        match result {
+            Ok(()) => {
+                self.failures = 0;
+                self.successes += 1;
+                if !upstream.is_healthy() && self.successes >= setting.rise {
+                    upstream.set_healthy(true);
+                    info!("Upstream {} is healthy", upstream);
+                }
+            }
+            Err(e) => {
+                self.successes = 0;
+                self.failures += 1;
+                debug!("Health check of upstream {} failed: {:#}", upstream, e);
+                if upstream.is_healthy() && self.failures >= setting.fall {
+                    upstream.set_healthy(false);
+                    warn!("Upstream {} is unhealthy: {:#}", upstream, e);
+                }
+            }
+        }
+    }
+}
+
+async fn probe(
+    client: &HttpClient,
+    upstream: &Upstream,
+    setting: &HealthCheckSetting,
+) -> anyhow::Result<()> {
+    let mut req = Request::get(upstream.uri(None, &setting.path)?);
+    if upstream.is_unix() {
+        // The authority of unix socket upstreams is not a host name
+        req = req.header(header::HOST, "localhost");
+    }
+    let req = req.body(Body::empty())?;
+    let res = tokio::time::timeout(
+        Duration::from_millis(setting.timeout_ms),
+        client.request(req),
+    )
+    .await
+    .context("Timed out")??;
+
+    let (min, max) = setting.status_range;
+    let status = res.status().as_u16();
+    if status < min || status > max {
+        anyhow::bail!("Unexpected status {}", status);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        connector,
+        settings::{ConnectionPoolSetting, UpstreamPoolSetting, UpstreamProtocol},
+    };
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Response, Server, StatusCode,
+    };
+    use serde_json::json;
+    use std::{
+        convert::Infallible,
+        net::SocketAddr,
+        sync::atomic::{AtomicU16, Ordering},
+        time::Instant,
+    };
+
+    /// Upstream answering `/health` with the status in `status`
+    fn upstream(status: Arc<AtomicU16>) -> SocketAddr {
+        let make_service = make_service_fn(move |_| {
+            let status = status.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let mut res = Response::new(Body::empty());
+                    *res.status_mut() = match req.uri().path() {
+                        "/health" => StatusCode::from_u16(status.load(Ordering::SeqCst)).unwrap(),
+                        _ => StatusCode::NOT_FOUND,
+                    };
+                    async { Ok::<_, Infallible>(res) }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    fn pool_upstreams(addr: SocketAddr, health_check: serde_json::Value) -> Upstreams {
+        let pool: UpstreamPoolSetting = serde_json::from_value(json!({
+            "name": "app",
+            "servers": [{ "address": format!("http://{}", addr) }],
+            "health_check": health_check,
+        }))
+        .unwrap();
+        Upstreams::new(vec![pool], &ConnectionPoolSetting::default()).unwrap()
+    }
+
+    fn client() -> HttpClient {
+        connector::client(
+            &ConnectionPoolSetting::default(),
+            None,
+            UpstreamProtocol::Http1,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn rise_and_fall() {
+        let status = Arc::new(AtomicU16::new(200));
+        let upstreams = pool_upstreams(
+            upstream(status.clone()),
+            json!({ "path": "/health", "rise": 2, "fall": 3 }),
+        );
+        let pool = upstreams.pools().next().unwrap();
+        let (upstream, setting) = (&pool.upstreams()[0], pool.health_check().unwrap());
+        let client = client();
+        let mut streak = Streak::default();
+
+        for (i, &(code, healthy)) in [
+            (503, true),
+            (503, true),
+            // A success resets the failures
+            (200, true),
+            (503, true),
+            (503, true),
+            (503, false),
+            (200, false),
+            // A failure resets the successes
+            (500, false),
+            (200, false),
+            (200, true),
+            (404, true),
+        ]
+        .iter()
+        .enumerate()
+        {
+            status.store(code, Ordering::SeqCst);
+            let result = probe(&client, upstream, setting).await;
+            assert_eq!(result.is_ok(), code == 200, "probe {}", i);
+            streak.record(upstream, setting, result);
+            assert_eq!(upstream.is_healthy(), healthy, "probe {}", i);
+        }
+    }
+
+    #[tokio::test]
+    async fn probe_failures() {
+        let status = Arc::new(AtomicU16::new(302));
+        let addr = upstream(status.clone());
+        let client = client();
+
+        let upstreams = pool_upstreams(addr, json!({ "path": "/health" }));
+        let pool = upstreams.pools().next().unwrap();
+        let (upstream, setting) = (&pool.upstreams()[0], pool.health_check().unwrap());
+        assert!(probe(&client, upstream, setting).await.is_ok());
+        status.store(400, Ordering::SeqCst);
+        let err = probe(&client, upstream, setting).await.unwrap_err();
+        assert_eq!(err.to_string(), "Unexpected status 400");
+
+        // Accepted but never answered
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstreams = pool_upstreams(
+            listener.local_addr().unwrap(),
+            json!({ "path": "/health", "timeout_ms": 50 }),
+        );
+        let pool = upstreams.pools().next().unwrap();
+        let (upstream, setting) = (&pool.upstreams()[0], pool.health_check().unwrap());
+        let started = Instant::now();
+        let err = probe(&client, upstream, setting).await.unwrap_err();
+        assert_eq!(err.to_string(), "Timed out");
+        assert!(started.elapsed() < Duration::from_secs(2));
+        drop(listener);
+    }
+
+    #[tokio::test]
+    async fn periodic_checks() {
+        let status = Arc::new(AtomicU16::new(503));
+        let upstreams = pool_upstreams(
+            upstream(status.clone()),
+            json!({ "path": "/health", "interval_ms": 10, "rise": 2, "fall": 3 }),
+        );
+        let upstream = upstreams.pools().next().unwrap().upstreams()[0].clone();
+        start(&upstreams, &client());
+
+        let wait = |healthy: bool| {
+            let upstream = upstream.clone();
+            async move {
+                let deadline = Instant::now() + Duration::from_secs(5);
+                while upstream.is_healthy() != healthy {
+                    assert!(Instant::now() < deadline, "still healthy: {}", !healthy);
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            }
+        };
+        wait(false).await;
+        status.store(200, Ordering::SeqCst);
+        wait(true).await;
+    }
+}
@@ -0,0 +1,189 @@
+use regex::{Captures, Regex};
+use std::collections::HashMap;
+
+/// Values keyed by server host, shared by the router and the certificate
+/// selection so both pick the same server for a host
+#[derive(Debug)]
+pub struct HostMap<T> {
+    exact: HashMap<String, T>,
+    /// Keyed by the parent domain of the hosts they match
+    wildcards: HashMap<String, T>,
+    regexes: Vec<(Regex, T)>,
+}
+
+impl<T> Default for HostMap<T> {
+    fn default() -> Self {
+        Self {
+            exact: HashMap::new(),
+            wildcards: HashMap::new(),
+            regexes: vec![],
+        }
+    }
+}
+
+impl<T> HostMap<T> {
+    /// Hosts are matched case and port insensitively. A host starting with
+    /// `*.` matches a single more label, so `*.example.com` matches
+    /// `a.example.com` but neither `example.com` nor `a.b.example.com`, and
+    /// a host starting with `~` is a regex.
+    pub fn insert(&mut self, host: &str, value: T) -> anyhow::Result<()> {
+        if let Some(pattern) = host.strip_prefix('~') {
+            let regex = Regex::new(pattern)
+                .map_err(|e| anyhow::anyhow!("Invalid host regex {:?}: {}", pattern, e))?;
+            self.regexes.push((regex, value));
+        } else if let Some(parent) = host.strip_prefix("*.") {
+            self.wildcards.insert(normalize_host(parent), value);
+        } else {
+            self.exact.insert(normalize_host(host), value);
+        }
+        Ok(())
+    }
+
+    /// Value of the exact host, else of the wildcard, else of the first
+    /// regex in insertion order matching `host`, which must be normalized,
+    /// with the capture groups of the regex
+    pub fn get<'h>(&self, host: &'h str) -> Option<(&T, Option<Captures<'h>>)> {
+        if let Some(value) = self.exact.get(host) {
+            return Some((value, None));
+        }
+        let wildcard = host
+            .split_once('.')
+            .filter(|(label, _)| !label.is_empty())
+            .and_then(|(_, parent)| self.wildcards.get(parent));
+        if let Some(value) = wildcard {
+            return Some((value, None));
+        }
+        self.regexes
+            .iter()
+            .find_map(|(regex, value)| regex.captures(host).map(|captures| (value, Some(captures))))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.wildcards.is_empty() && self.regexes.is_empty()
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &T> {
+        self.exact
+            .values()
+            .chain(self.wildcards.values())
+            .chain(self.regexes.iter().map(|(_, value)| value))
+    }
+
+    /// Converts every value, keeping how they are matched
+    pub fn try_map<U>(&self, f: impl Fn(&T) -> anyhow::Result<U>) -> anyhow::Result<HostMap<U>> {
+        Ok(HostMap {
+            exact: self
+                .exact
+                .iter()
+                .map(|(host, value)| Ok((host.clone(), f(value)?)))
+                .collect::<anyhow::Result<_>>()?,
+            wildcards: self
+                .wildcards
+                .iter()
+                .map(|(parent, value)| Ok((parent.clone(), f(value)?)))
+                .collect::<anyhow::Result<_>>()?,
+            regexes: self
+                .regexes
+                .iter()
+                .map(|(regex, value)| Ok((regex.clone(), f(value)?)))
+                .collect::<anyhow::Result<_>>()?,
+        })
+    }
+}
+
+/// Lowercases the host and strips the port and any trailing dot
+pub fn normalize_host(host: &str) -> String {
+    let host = if host.starts_with('[') {
+        // IPv6 literal, the port comes after the closing bracket
+        host.find(']').map_or(host, |end| &host[..=end])
+    } else {
+        match host.rsplit_once(':') {
+            Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+            _ => host,
+        }
+    };
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hosts() -> HostMap<&'static str> {
+        let mut hosts = HostMap::default();
+        for host in [
+            "app.localhost:9000",
+            "*.apps.localhost",
+            "*.localhost",
+            "~^(?P<branch>[a-z0-9-]+)\\.preview\\.localhost$",
+            "~preview",
+        ] {
+            hosts.insert(host, host).unwrap();
+        }
+        hosts
+    }
+
+    fn get(hosts: &HostMap<&'static str>, host: &str) -> Option<&'static str> {
+        hosts.get(&normalize_host(host)).map(|(value, _)| *value)
+    }
+
+    #[test]
+    fn exact_hosts() {
+        let hosts = hosts();
+        assert_eq!(get(&hosts, "APP.localhost."), Some("app.localhost:9000"));
+        assert_eq!(get(&hosts, "app.localhost:443"), Some("app.localhost:9000"));
+    }
+
+    #[test]
+    fn wildcards_match_one_label() {
+        let hosts = hosts();
+        assert_eq!(get(&hosts, "a.apps.localhost"), Some("*.apps.localhost"));
+        assert_eq!(
+            get(&hosts, "A.Apps.Localhost:8443"),
+            Some("*.apps.localhost")
+        );
+        assert_eq!(get(&hosts, "apps.localhost"), Some("*.localhost"));
+        assert_eq!(get(&hosts, "a.b.apps.localhost"), None);
+        assert_eq!(get(&hosts, ".apps.localhost"), None);
+        assert_eq!(get(&hosts, "localhost"), None);
+    }
+
+    #[test]
+    fn regexes_in_order_after_wildcards() {
+        let hosts = hosts();
+        let host = normalize_host("Fix-1.preview.localhost");
+        let (value, captures) = hosts.get(&host).unwrap();
+        assert_eq!(*value, "~^(?P<branch>[a-z0-9-]+)\\.preview\\.localhost$");
+        assert_eq!(&captures.unwrap()["branch"], "fix-1");
+
+        assert_eq!(get(&hosts, "a.b.preview.localhost"), Some("~preview"));
+        // The wildcard wins over the regexes
+        assert_eq!(get(&hosts, "preview.localhost"), Some("*.localhost"));
+    }
+
+    #[test]
+    fn invalid_regex() {
+        let mut hosts = HostMap::default();
+        assert!(hosts.insert("~(", ()).is_err());
+    }
+
+    #[test]
+    fn normalized_hosts() {
+        for (host, expected) in [
+            ("Example.COM", "example.com"),
+            ("example.com:443", "example.com"),
+            ("example.com:8080", "example.com"),
+            ("example.com.", "example.com"),
+            ("Example.com.:8080", "example.com"),
+            ("127.0.0.1:80", "127.0.0.1"),
+            ("[::1]", "[::1]"),
+            ("[::1]:8080", "[::1]"),
+            ("[2001:DB8::1]:443", "[2001:db8::1]"),
+            // Not a port, kept as is
+            ("example.com:http", "example.com:http"),
+            ("", ""),
+        ] {
+            assert_eq!(normalize_host(host), expected, "{}", host);
+        }
+    }
+}
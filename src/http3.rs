@@ -0,0 +1,553 @@
+use crate::{
+    acme,
+    body::ResponseBody,
+    certs::{Certificates, KeyPair},
+    client_cert::ClientCert,
+    handler::Handler,
+    host::normalize_host,
+    ocsp::Stapler,
+    settings::{ClientAuthMode, Http3Setting, TimeoutSetting, TlsSetting},
+    ticketer::Ticketer,
+    tls,
+};
+use anyhow::Context;
+use futures::FutureExt;
+use h3::{
+    error::Code,
+    server::{RequestResolver, RequestStream},
+};
+use hyper::{
+    body::{Buf, Bytes, HttpBody},
+    header::{HeaderName, HeaderValue},
+    Body, HeaderMap, Request, Response, Version,
+};
+use quinn::crypto::rustls::QuicServerConfig;
+use rustls_quic::{
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::{
+        ClientHello, NoServerSessionStorage, ResolvesServerCert, ServerSessionMemoryCache,
+        WebPkiClientVerifier,
+    },
+    sign::CertifiedKey,
+    KeyLog, RootCertStore,
+};
+use std::{
+    convert::{TryFrom, TryInto},
+    fmt,
+    future::poll_fn,
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+use tracing::{info, warn};
+
+type SendStream = RequestStream<h3_quinn::SendStream<Bytes>, Bytes>;
+type RecvStream = RequestStream<h3_quinn::RecvStream, Bytes>;
+
+/// `Alt-Svc` header telling clients of the TCP listeners that HTTP/3 is
+/// served on `port`
+pub fn alt_svc(setting: &Http3Setting, port: u16) -> anyhow::Result<HeaderValue> {
+    let alt_svc = format!("h3=\":{}\"; ma={}", port, setting.alt_svc_max_age_ms / 1000);
+    Ok(HeaderValue::try_from(alt_svc)?)
+}
+
+pub fn bind(addr: SocketAddr, config: quinn::ServerConfig) -> anyhow::Result<quinn::Endpoint> {
+    quinn::Endpoint::server(config, addr)
+        .with_context(|| format!("Could not bind HTTP/3 endpoint on {}", addr))
+}
+
+/// Config of the QUIC endpoint with the certificates of the TLS listener
+pub fn server_config(
+    certificates: &Certificates<KeyPair>,
+    acme: Option<Arc<acme::Store>>,
+    stapler: Option<Arc<Stapler>>,
+    setting: &TlsSetting,
+    ticketer: Option<Arc<Ticketer>>,
+    timeouts: &TimeoutSetting,
+) -> anyhow::Result<quinn::ServerConfig> {
+    let resolver = Resolver {
+        certificates: certificates.try_map(certified_key)?,
+        acme,
+        stapler,
+    };
+
+    let mut provider = rustls_quic::crypto::ring::default_provider();
+    if !setting.cipher_suites.is_empty() {
+        // Only the TLS 1.3 suites are available for QUIC
+        provider.cipher_suites = setting
+            .cipher_suites
+            .iter()
+            .filter_map(|name| {
+                provider
+                    .cipher_suites
+                    .iter()
+                    .find(|suite| format!("{:?}", suite.suite()) == *name)
+                    .copied()
+            })
+            .collect();
+        if provider.cipher_suites.is_empty() {
+            anyhow::bail!("HTTP/3 needs a TLS 1.3 cipher suite in tls.cipher_suites");
+        }
+    }
+    let provider = Arc::new(provider);
+    let client_auth = &setting.client_auth;
+    let builder = rustls_quic::ServerConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls_quic::version::TLS13])?;
+    let builder = match client_auth.mode {
+        ClientAuthMode::None => builder.with_no_client_auth(),
+        mode => {
+            let mut roots = RootCertStore::empty();
+            for cert in tls::client_ca_certs(client_auth)? {
+                roots.add(CertificateDer::from(cert.0))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = if mode == ClientAuthMode::Optional {
+                verifier.allow_unauthenticated()
+            } else {
+                verifier
+            };
+            builder.with_client_cert_verifier(verifier.build()?)
+        }
+    };
+    let mut tls = builder.with_cert_resolver(Arc::new(resolver));
+    tls.alpn_protocols = vec![b"h3".to_vec()];
+    if let Some(ticketer) = ticketer {
+        tls.ticketer = ticketer;
+    }
+    tls.session_storage = if setting.session_cache_size > 0 {
+        ServerSessionMemoryCache::new(setting.session_cache_size)
+    } else {
+        Arc::new(NoServerSessionStorage {})
+    };
+    if let Some(path) = &setting.key_log_file {
+        tls.key_log = Arc::new(tls::KeyLogFile::open(path)?);
+    }
+
+    let mut transport = quinn::TransportConfig::default();
+    transport.max_idle_timeout(Some(
+        Duration::from_millis(timeouts.keep_alive_idle_ms).try_into()?,
+    ));
+    // Initial packets use this suite even when it is not allowed for the
+    // handshake
+    let initial = rustls_quic::crypto::ring::cipher_suite::TLS13_AES_128_GCM_SHA256
+        .tls13()
+        .and_then(|suite| suite.quic_suite())
+        .context("No initial QUIC cipher suite")?;
+    let crypto = QuicServerConfig::with_initial(Arc::new(tls), initial)?;
+    let mut config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    config.transport_config(Arc::new(transport));
+    Ok(config)
+}
+
+/// Converts a certificate and key read for the TLS listener to the rustls
+/// version used by quinn
+pub fn certified_key(pair: &KeyPair) -> anyhow::Result<Arc<CertifiedKey>> {
+    let certs = pair
+        .certs
+        .iter()
+        .map(|cert| CertificateDer::from(cert.0.clone()))
+        .collect();
+    let key = PrivateKeyDer::try_from(pair.key.0.clone())
+        .map_err(|e| anyhow::anyhow!("Invalid private key for HTTP/3: {}", e))?;
+    let key = rustls_quic::crypto::ring::sign::any_supported_type(&key)
+        .context("Unsupported private key type for HTTP/3")?;
+    Ok(Arc::new(CertifiedKey::new(certs, key)))
+}
+
+impl KeyLog for tls::KeyLogFile {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        self.write(label, client_random, secret);
+    }
+}
+
+/// Same choice of certificate as the TLS listener
+struct Resolver {
+    certificates: Certificates<Arc<CertifiedKey>>,
+    acme: Option<Arc<acme::Store>>,
+    stapler: Option<Arc<Stapler>>,
+}
+
+impl Resolver {
+    fn staple(&self, cert: Arc<CertifiedKey>) -> Arc<CertifiedKey> {
+        match self
+            .stapler
+            .as_ref()
+            .and_then(|stapler| stapler.get(&cert.cert))
+        {
+            Some(ocsp) => Arc::new(CertifiedKey {
+                ocsp: Some(ocsp),
+                ..(*cert).clone()
+            }),
+            None => cert,
+        }
+    }
+}
+
+impl fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Resolver")
+            .field("certificates", &self.certificates)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let server_name = client_hello.server_name();
+        if let (Some(acme), Some(server_name)) = (&self.acme, server_name) {
+            if let Some(cert) = acme.get_quic(&normalize_host(server_name)) {
+                return Some(self.staple(cert));
+            }
+        }
+        let cert = self.certificates.get(server_name).cloned()?;
+        Some(self.staple(cert))
+    }
+}
+
+/// Serves the connections of `endpoint` until it is closed
+pub async fn serve(endpoint: quinn::Endpoint, handler: Arc<Handler>) {
+    while let Some(incoming) = endpoint.accept().await {
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_connection(incoming, handler).await {
+                warn!("HTTP/3 connection error: {:#}", e);
+            }
+        });
+    }
+}
+
+async fn serve_connection(incoming: quinn::Incoming, handler: Arc<Handler>) -> anyhow::Result<()> {
+    let conn = incoming.await?;
+    let addr = conn.remote_address();
+    let client_cert = conn
+        .peer_identity()
+        .and_then(|identity| identity.downcast::<Vec<CertificateDer>>().ok())
+        .and_then(|certs| certs.first().map(|cert| Arc::new(ClientCert::new(cert))));
+    let quic = conn.clone();
+    let mut conn = h3::server::Connection::new(h3_quinn::Connection::new(conn)).await?;
+
+    loop {
+        let resolver = match conn.accept().await {
+            Ok(Some(resolver)) => resolver,
+            Ok(None) => return Ok(()),
+            Err(e)
+                if e.is_h3_no_error()
+                    || quic.close_reason() == Some(quinn::ConnectionError::TimedOut) =>
+            {
+                info!("HTTP/3 connection from {} closed", addr);
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let handler = handler.clone();
+        let client_cert = client_cert.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_request(resolver, addr, client_cert, handler).await {
+                warn!("HTTP/3 request from {} failed: {:#}", addr, e);
+            }
+        });
+    }
+}
+
+async fn serve_request(
+    resolver: RequestResolver<h3_quinn::Connection, Bytes>,
+    addr: SocketAddr,
+    client_cert: Option<Arc<ClientCert>>,
+    handler: Arc<Handler>,
+) -> anyhow::Result<()> {
+    let (req, stream) = resolver.resolve_request().await?;
+    let (mut send, recv) = stream.split();
+
+    let res = match request(req, recv, client_cert) {
+        Ok(req) => handler.handle_client(addr, req).await,
+        Err(e) => Err(e),
+    };
+    match res {
+        Ok(res) => send_response(&mut send, res).await,
+        Err(e) => {
+            send.stop_stream(Code::H3_INTERNAL_ERROR);
+            Err(e)
+        }
+    }
+}
+
+/// Converts the request to the `http` version used by hyper
+fn request(
+    req: http1::Request<()>,
+    recv: RecvStream,
+    client_cert: Option<Arc<ClientCert>>,
+) -> anyhow::Result<Request<Body>> {
+    let (parts, ()) = req.into_parts();
+    let mut req = Request::builder()
+        .method(parts.method.as_str())
+        .uri(parts.uri.to_string())
+        .version(Version::HTTP_3)
+        .body(request_body(recv))?;
+    *req.headers_mut() = from_http1_headers(&parts.headers)?;
+    if let Some(client_cert) = client_cert {
+        req.extensions_mut().insert(client_cert);
+    }
+    Ok(req)
+}
+
+/// Streams the request body and trailers from the QUIC stream. The stream
+/// end is only seen on reading, so a body which already ended is sent as
+/// empty rather than chunked to the upstream.
+fn request_body(mut recv: RecvStream) -> Body {
+    let first = match poll_fn(|cx| recv.poll_recv_data(cx)).now_or_never() {
+        Some(Ok(None)) => return Body::empty(),
+        Some(Ok(Some(mut data))) => Some(data.copy_to_bytes(data.remaining())),
+        _ => None,
+    };
+
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        if let Some(data) = first {
+            if sender.send_data(data).await.is_err() {
+                return;
+            }
+        }
+        loop {
+            match recv.recv_data().await {
+                Ok(Some(mut data)) => {
+                    let data = data.copy_to_bytes(data.remaining());
+                    if sender.send_data(data).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => break,
+                Err(_) => return sender.abort(),
+            }
+        }
+        match recv.recv_trailers().await {
+            Ok(Some(trailers)) => match from_http1_headers(&trailers) {
+                Ok(trailers) => {
+                    let _ = sender.send_trailers(trailers).await;
+                }
+                Err(_) => sender.abort(),
+            },
+            Ok(None) => {}
+            Err(_) => sender.abort(),
+        }
+    });
+    body
+}
+
+async fn send_response(send: &mut SendStream, res: Response<ResponseBody>) -> anyhow::Result<()> {
+    let (parts, mut body) = res.into_parts();
+    let mut head = http1::Response::builder()
+        .status(parts.status.as_u16())
+        .body(())?;
+    *head.headers_mut() = to_http1_headers(&parts.headers)?;
+    send.send_response(head).await?;
+
+    while let Some(data) = body.data().await {
+        send.send_data(data.map_err(|e| anyhow::anyhow!(e))?)
+            .await?;
+    }
+    if let Some(trailers) = body.trailers().await.map_err(|e| anyhow::anyhow!(e))? {
+        send.send_trailers(to_http1_headers(&trailers)?).await?;
+    }
+    send.finish().await?;
+    Ok(())
+}
+
+fn from_http1_headers(headers: &http1::HeaderMap) -> anyhow::Result<HeaderMap> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            Ok((
+                HeaderName::from_bytes(name.as_str().as_bytes())?,
+                HeaderValue::from_bytes(value.as_bytes())?,
+            ))
+        })
+        .collect()
+}
+
+fn to_http1_headers(headers: &HeaderMap) -> anyhow::Result<http1::HeaderMap> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            Ok((
+                http1::HeaderName::from_bytes(name.as_str().as_bytes())?,
+                http1::HeaderValue::from_bytes(value.as_bytes())?,
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        certs::CertificateFiles,
+        client_cert::CertHeaders,
+        connector,
+        error_page::ErrorPages,
+        handler::UnmatchedResponse,
+        router::Router,
+        settings::{Settings, UpstreamProtocol},
+        upstream::Upstreams,
+    };
+    use hyper::{
+        header,
+        service::{make_service_fn, service_fn},
+        Server, StatusCode,
+    };
+    use quinn::crypto::rustls::QuicClientConfig;
+    use rcgen::Certificate;
+    use std::convert::Infallible;
+
+    /// h2c upstream answering with a greeting followed by the request body,
+    /// and a trailer, telling the authority it was sent
+    fn upstream() -> SocketAddr {
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async {
+                let authority = req.uri().authority().map(ToString::to_string);
+                let body = hyper::body::to_bytes(req.into_body()).await?;
+                let (mut sender, res_body) = Body::channel();
+                tokio::spawn(async move {
+                    sender.send_data(Bytes::from_static(b"hello ")).await?;
+                    sender.send_data(body).await?;
+                    let mut trailers = HeaderMap::new();
+                    trailers.insert("x-checksum", HeaderValue::from_static("abc"));
+                    sender.send_trailers(trailers).await
+                });
+                let mut res = Response::new(res_body);
+                if let Some(authority) = authority {
+                    res.headers_mut()
+                        .insert("x-authority", authority.parse().unwrap());
+                }
+                Ok::<_, hyper::Error>(res)
+            }))
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .http2_only(true)
+            .serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    fn handler(upstream: SocketAddr, alt_svc: HeaderValue) -> Arc<Handler> {
+        let settings: Settings = serde_json::from_value(serde_json::json!({
+            "upstreams": [{
+                "name": "backend",
+                "protocol": "h2c",
+                "servers": [{ "address": format!("http://{}", upstream) }],
+            }],
+            "servers": [{ "host": "localhost", "proxy_pass": "http://backend" }],
+        }))
+        .unwrap();
+        let mut upstreams = Upstreams::new(settings.upstreams, &settings.connection_pool).unwrap();
+        let router =
+            Router::new(settings.servers, settings.default_server, &mut upstreams).unwrap();
+        let client =
+            connector::client(&settings.connection_pool, None, UpstreamProtocol::Http1).unwrap();
+        Arc::new(Handler::new(
+            router,
+            UnmatchedResponse::new(settings.unmatched).unwrap(),
+            ErrorPages::new(&settings.error_pages).unwrap(),
+            client,
+            settings.timeouts,
+            true,
+            Some(alt_svc),
+            CertHeaders::new(&settings.tls.client_auth.headers).unwrap(),
+        ))
+    }
+
+    /// Endpoint serving `handler` with a self-signed certificate for
+    /// localhost, which is returned
+    fn endpoint(handler: Arc<Handler>) -> (quinn::Endpoint, Vec<u8>) {
+        let cert =
+            Certificate::from_params(rcgen::CertificateParams::new(vec!["localhost".to_owned()]))
+                .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let tls = TlsSetting {
+            cert: Some(dir.path().join("cert.pem")),
+            key: Some(dir.path().join("key.pem")),
+            ..Default::default()
+        };
+        std::fs::write(tls.cert.as_ref().unwrap(), cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(tls.key.as_ref().unwrap(), cert.serialize_private_key_pem()).unwrap();
+        let certificates = CertificateFiles::new(&tls, &[]).unwrap().load().unwrap();
+
+        let config = server_config(
+            &certificates,
+            None,
+            None,
+            &tls,
+            None,
+            &TimeoutSetting::default(),
+        )
+        .unwrap();
+        let endpoint = bind(SocketAddr::from(([127, 0, 0, 1], 0)), config).unwrap();
+        tokio::spawn(serve(endpoint.clone(), handler));
+        (endpoint, cert.serialize_der().unwrap())
+    }
+
+    async fn connect(addr: SocketAddr, cert: Vec<u8>) -> quinn::Connection {
+        let mut roots = RootCertStore::empty();
+        roots.add(CertificateDer::from(cert)).unwrap();
+        let provider = Arc::new(rustls_quic::crypto::ring::default_provider());
+        let mut tls = rustls_quic::ClientConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls_quic::version::TLS13])
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![b"h3".to_vec()];
+        let config = QuicClientConfig::try_from(tls).unwrap();
+
+        let mut endpoint = quinn::Endpoint::client(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(config)));
+        endpoint.connect(addr, "localhost").unwrap().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn proxies_request() {
+        let alt_svc = alt_svc(&Http3Setting::default(), 9443).unwrap();
+        let handler = handler(upstream(), alt_svc.clone());
+        let (endpoint, cert) = endpoint(handler.clone());
+        let conn = connect(endpoint.local_addr().unwrap(), cert).await;
+
+        let (mut driver, mut send_request) = h3::client::new(h3_quinn::Connection::new(conn))
+            .await
+            .unwrap();
+        tokio::spawn(async move { poll_fn(|cx| driver.poll_close(cx)).await });
+
+        let req = http1::Request::post("https://localhost/echo")
+            .header("te", "trailers")
+            .body(())
+            .unwrap();
+        let mut stream = send_request.send_request(req).await.unwrap();
+        stream
+            .send_data(Bytes::from_static(b"world"))
+            .await
+            .unwrap();
+        stream.finish().await.unwrap();
+
+        let res = stream.recv_response().await.unwrap();
+        assert_eq!(res.status(), http1::StatusCode::OK);
+        // Only advertised to the clients of the TCP listeners
+        assert!(res.headers().get("alt-svc").is_none());
+        assert_eq!(res.headers()["x-authority"], "localhost");
+        let mut body = vec![];
+        while let Some(mut data) = stream.recv_data().await.unwrap() {
+            body.extend_from_slice(&data.copy_to_bytes(data.remaining()));
+        }
+        assert_eq!(body, b"hello world");
+        let trailers = stream.recv_trailers().await.unwrap().unwrap();
+        assert_eq!(trailers["x-checksum"], "abc");
+
+        let req = Request::get("http://localhost/")
+            .header(header::HOST, "localhost")
+            .body(Body::empty())
+            .unwrap();
+        let res = handler
+            .handle_client(SocketAddr::from(([127, 0, 0, 1], 1)), req)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::ALT_SVC], alt_svc);
+    }
+}
@@ -1,25 +1,42 @@
 macro_rules! create_service {
     ($handler:ident) => {
-        make_service_fn(|conn: &AddrStream| {
+        make_service_fn(|conn: &timeout::TimeoutStream<AddrStream>| {
             let handler = $handler.clone();
-            let addr = conn.remote_addr();
+            let addr = conn.get_ref().remote_addr();
+            let activity = conn.activity();
             async move {
                 Ok::<_, anyhow::Error>(service_fn(move |req| {
+                    let request = activity.start_request();
                     let handle_future = handler.clone().handle_client(addr, req);
-                    async { handle_future.await.context("Failed to handle client") }
+                    async {
+                        let res = handle_future.await.context("Failed to handle client");
+                        drop(request);
+                        res
+                    }
                 }))
             }
         })
     };
 
     (tls: $handler:ident) => {
-        make_service_fn(|conn: &tls::TlsStream| {
+        make_service_fn(|conn: &timeout::TimeoutStream<tls::TlsStream>| {
             let handler = $handler.clone();
-            let addr = conn.remote_addr();
+            let addr = conn.get_ref().remote_addr();
+            let client_cert = conn.get_ref().client_cert();
+            let activity = conn.activity();
             async move {
-                Ok::<_, anyhow::Error>(service_fn(move |req| {
+                Ok::<_, anyhow::Error>(service_fn(move |mut req: hyper::Request<hyper::Body>| {
+                    let request = activity.start_request();
+                    // The handshake completed before any request was read
+                    if let Some(client_cert) = client_cert.get() {
+                        req.extensions_mut().insert(client_cert.clone());
+                    }
                     let handle_future = handler.clone().handle_client(addr, req);
-                    async { handle_future.await.context("Failed to handle client") }
+                    async {
+                        let res = handle_future.await.context("Failed to handle client");
+                        drop(request);
+                        res
+                    }
                 }))
             }
         })
@@ -35,17 +52,19 @@ macro_rules! server_await {
 }
 
 macro_rules! create_server {
-    ($handler:ident, $incoming:expr) => {
+    ($handler:ident, $incoming:expr, $timeouts:expr, $http2:expr) => {
         let make_service = create_service!($handler);
-        let server = Server::builder($incoming).serve(make_service);
+        let incoming = timeout::TimeoutIncoming::new($incoming, $timeouts);
+        let server = server::configure(Server::builder(incoming), $http2).serve(make_service);
 
         server_await!(server);
     };
 
-    (tls: $handler:ident, $incoming:expr, $server_config:expr) => {
+    (tls: $handler:ident, $incoming:expr, $server_config:expr, $timeouts:expr, $http2:expr) => {
         let make_service = create_service!(tls: $handler);
-        let server =
-            Server::builder(tls::TlsAcceptor::new($server_config, $incoming)).serve(make_service);
+        let incoming =
+            timeout::TimeoutIncoming::new(tls::TlsAcceptor::new($server_config, $incoming), $timeouts);
+        let server = server::configure(Server::builder(incoming), $http2).serve(make_service);
 
         server_await!(server);
     };
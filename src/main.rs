@@ -2,30 +2,49 @@
 mod macros;
 
 use anyhow::Context;
-use handler::Handler;
+use certs::CertificateFiles;
+use error_page::ErrorPages;
+use handler::{Handler, UnmatchedResponse};
 use hyper::{
     server::conn::{AddrIncoming, AddrStream},
     service::{make_service_fn, service_fn},
     Server,
 };
 use opt::Opt;
-use std::{fs::File, io::BufReader, net::SocketAddr, sync::Arc};
+use router::Router;
+use settings::{AcmeChallenge, Http2Setting, UpstreamProtocol};
+use std::{net::SocketAddr, sync::Arc};
 use structopt::StructOpt;
-use tokio_rustls::rustls::{
-    internal::pemfile::{certs, pkcs8_private_keys},
-    NoClientAuth, ServerConfig,
-};
+use tokio_rustls::rustls::ServerConfig;
 use tracing::info;
 use tunnel::Tunnel;
+use upstream::Upstreams;
 
+mod acme;
 mod async_ssh;
+mod body;
+mod certs;
 mod client;
+mod client_cert;
+mod connector;
+mod der;
+mod error_page;
 mod handler;
+mod health;
+mod host;
+mod http3;
+mod ocsp;
 mod opt;
+mod pem;
+mod reload;
+mod router;
 mod server;
 mod settings;
+mod ticketer;
+mod timeout;
 mod tls;
 mod tunnel;
+mod upstream;
 mod utils;
 
 #[tokio::main]
@@ -42,7 +61,53 @@ async fn main() -> anyhow::Result<()> {
         }
         Opt::Server { tls, port, config } => {
             let settings = settings::Settings::from_config_file(config);
-            let handler = Handler::new(settings.servers(), tls);
+            let certificates = if tls {
+                let files = CertificateFiles::new(&settings.tls, &settings.servers)?;
+                let certificates = files.load()?;
+                let acme = match settings.tls.acme.clone() {
+                    Some(setting) => Some((
+                        acme::start(setting.clone(), acme::domains(&settings.servers))?,
+                        setting,
+                    )),
+                    None => None,
+                };
+                if certificates.is_empty() && acme.is_none() {
+                    anyhow::bail!(
+                        "--tls needs tls.cert and tls.key, tls.acme or a server with tls"
+                    );
+                }
+                Some((files, certificates, acme))
+            } else {
+                None
+            };
+            let mut upstreams = Upstreams::new(settings.upstreams, &settings.connection_pool)?;
+            let router = Router::new(settings.servers, settings.default_server, &mut upstreams)?;
+            let client =
+                connector::client(&settings.connection_pool, None, UpstreamProtocol::Http1)?;
+            health::start(&upstreams, &client);
+            let unmatched = UnmatchedResponse::new(settings.unmatched)?;
+            let error_pages = ErrorPages::new(&settings.error_pages)?;
+            let timeouts = settings.timeouts;
+            let http2 = settings.http2;
+            let http3 = settings.http3;
+            let http3_port = http3.port.unwrap_or(port);
+            let alt_svc = if tls && http3.enabled {
+                Some(http3::alt_svc(&http3, http3_port)?)
+            } else {
+                None
+            };
+            let tls_setting = settings.tls;
+            let cert_headers = client_cert::CertHeaders::new(&tls_setting.client_auth.headers)?;
+            let handler = Handler::new(
+                router,
+                unmatched,
+                error_pages,
+                client,
+                timeouts.clone(),
+                tls,
+                alt_svc,
+                cert_headers,
+            );
             let handler = Arc::new(handler);
 
             let addr = SocketAddr::from(([127, 0, 0, 1], port));
@@ -50,24 +115,108 @@ async fn main() -> anyhow::Result<()> {
             let mut incoming = AddrIncoming::bind(&addr)?;
             incoming.set_nodelay(true);
 
-            if tls {
-                let cert = certs(&mut BufReader::new(File::open("./cert.pem").unwrap())).unwrap();
-                let mut keys =
-                    pkcs8_private_keys(&mut BufReader::new(File::open("./key.pem").unwrap()))
-                        .unwrap();
+            if let Some((files, certificates, acme)) = certificates {
+                let acme_store = acme.as_ref().map(|(store, _)| store.clone());
+                let mut alpn_protocols = alpn_protocols(&http2);
+                if let Some((store, setting)) = &acme {
+                    match setting.challenge {
+                        AcmeChallenge::Http01 => {
+                            acme::serve_http(store.clone(), setting.http_port)?
+                        }
+                        AcmeChallenge::TlsAlpn01 => {
+                            alpn_protocols.push(acme::ACME_TLS_ALPN.to_vec())
+                        }
+                    }
+                }
+
+                let stapler = if tls_setting.ocsp_stapling.enabled {
+                    let stapler = ocsp::Stapler::new(tls_setting.ocsp_stapling.clone())?;
+                    stapler.configure(certificates.values().map(|pair| &pair.certs[..]));
+                    Some(stapler)
+                } else {
+                    None
+                };
 
-                let mut server_config = ServerConfig::new(NoClientAuth::new());
-                server_config.set_single_cert(cert, keys.remove(0)).unwrap();
+                let mut server_config =
+                    ServerConfig::new(tls::client_verifier(&tls_setting.client_auth)?);
+                server_config.cert_resolver = Arc::new(tls::CertResolver::new(
+                    certificates.try_map(certs::KeyPair::certified_key)?,
+                    acme_store.clone(),
+                    stapler.clone(),
+                ));
+                server_config.set_protocols(&alpn_protocols);
+                tls::configure(&mut server_config, &tls_setting)?;
+                let server_config = tls::SharedConfig::new(server_config);
+
+                // Kept across reloads so the tickets already sent stay valid
+                let quic_ticketer = tls::ticketer(&tls_setting)?;
+                let endpoint = if http3.enabled {
+                    let addr = SocketAddr::from(([127, 0, 0, 1], http3_port));
+                    let config = http3::server_config(
+                        &certificates,
+                        acme_store.clone(),
+                        stapler.clone(),
+                        &tls_setting,
+                        quic_ticketer.clone(),
+                        &timeouts,
+                    )?;
+                    let endpoint = http3::bind(addr, config)?;
+                    info!("Starting http3 server on udp port {}", http3_port);
+                    tokio::spawn(http3::serve(endpoint.clone(), handler.clone()));
+                    Some(endpoint)
+                } else {
+                    None
+                };
+
+                let shared_config = server_config.clone();
+                let reload_timeouts = timeouts.clone();
+                reload::start(files, tls_setting.watch_interval_ms, move |certificates| {
+                    let resolver = tls::CertResolver::new(
+                        certificates.try_map(certs::KeyPair::certified_key)?,
+                        acme_store.clone(),
+                        stapler.clone(),
+                    );
+                    let quic_config = match &endpoint {
+                        Some(_) => Some(http3::server_config(
+                            &certificates,
+                            acme_store.clone(),
+                            stapler.clone(),
+                            &tls_setting,
+                            quic_ticketer.clone(),
+                            &reload_timeouts,
+                        )?),
+                        None => None,
+                    };
+
+                    if let Some(stapler) = &stapler {
+                        stapler.configure(certificates.values().map(|pair| &pair.certs[..]));
+                    }
+                    let mut config = (*shared_config.get()).clone();
+                    config.cert_resolver = Arc::new(resolver);
+                    shared_config.set(config);
+                    if let (Some(endpoint), Some(quic_config)) = (&endpoint, quic_config) {
+                        endpoint.set_server_config(Some(quic_config));
+                    }
+                    Ok(())
+                })?;
 
                 info!("Starting https server on port {}", port);
-                create_server!(tls: handler, incoming, server_config);
+                create_server!(tls: handler, incoming, server_config, &timeouts, &http2);
             } else {
                 async_ssh::main().await?;
                 info!("Starting http server on port {}", port);
-                create_server!(handler, incoming);
+                create_server!(handler, incoming, &timeouts, &http2);
             };
         }
     }
 
     Ok(())
 }
+
+fn alpn_protocols(http2: &Http2Setting) -> Vec<Vec<u8>> {
+    if http2.enabled {
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    } else {
+        vec![b"http/1.1".to_vec()]
+    }
+}
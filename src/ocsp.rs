@@ -0,0 +1,1012 @@
+use crate::{
+    connector::{self, HttpClient},
+    der,
+    settings::{ConnectionPoolSetting, OcspSetting, UpstreamProtocol},
+};
+use anyhow::Context;
+use hyper::{body, header, Body, Request};
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    convert::TryFrom,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, SystemTime},
+};
+use tokio::task::AbortHandle;
+use tracing::{info, warn};
+use x509_parser::{
+    certificate::X509Certificate,
+    der_parser::asn1_rs::BitString,
+    extensions::{GeneralName, ParsedExtension},
+    oid_registry::OID_PKIX_ACCESS_DESCRIPTOR_OCSP,
+    prelude::FromDer,
+    time::ASN1Time,
+    verify::verify_signature,
+    x509::AlgorithmIdentifier,
+};
+
+/// AlgorithmIdentifier of SHA-1, the hash of certificate ids understood by
+/// all responders
+const SHA1_ALGORITHM: &[u8] = b"\x30\x09\x06\x05\x2b\x0e\x03\x02\x1a\x05\x00";
+const OID_SHA1: &[u8] = b"\x2b\x0e\x03\x02\x1a";
+/// id-pkix-ocsp-basic
+const OID_OCSP_BASIC: &[u8] = b"\x2b\x06\x01\x05\x05\x07\x30\x01\x01";
+const MIN_REFRESH: Duration = Duration::from_secs(1);
+
+/// OCSP responses stapled in the handshakes, by leaf certificate
+pub struct Stapler {
+    setting: OcspSetting,
+    client: HttpClient,
+    tracked: RwLock<HashMap<Vec<u8>, Tracked>>,
+}
+
+/// Chain whose responses are fetched by a refresh task
+struct Tracked {
+    /// `None` until the first response is received
+    staple: Option<Staple>,
+    /// Whether the chain is one of the configured certificates. The others
+    /// are tracked once a handshake sends them, until they are no longer
+    /// sent between two refreshes.
+    configured: bool,
+    used: AtomicBool,
+    task: AbortHandle,
+}
+
+struct Staple {
+    response: Vec<u8>,
+    next_update: Option<SystemTime>,
+}
+
+impl Stapler {
+    pub fn new(setting: OcspSetting) -> anyhow::Result<Arc<Self>> {
+        let client = connector::client(
+            &ConnectionPoolSetting::default(),
+            None,
+            UpstreamProtocol::Http1,
+        )?;
+        Ok(Arc::new(Self {
+            setting,
+            client,
+            tracked: RwLock::default(),
+        }))
+    }
+
+    /// Unexpired response for a certificate chain, whose responses start
+    /// being fetched if it is a new one
+    pub fn get<C: AsRef<[u8]>>(self: &Arc<Self>, chain: &[C]) -> Option<Vec<u8>> {
+        let leaf = chain.first()?.as_ref();
+        {
+            let tracked = self.tracked.read().unwrap();
+            if let Some(tracked) = tracked.get(leaf) {
+                tracked.used.store(true, Ordering::Relaxed);
+                let now = SystemTime::now();
+                return tracked
+                    .staple
+                    .as_ref()
+                    .filter(|staple| staple.next_update.is_none_or(|next| next > now))
+                    .map(|staple| staple.response.clone());
+            }
+        }
+        self.track(chain, false);
+        None
+    }
+
+    /// Tracks the configured chains, and stops fetching the responses of
+    /// the ones configured before
+    pub fn configure<'a, C: AsRef<[u8]> + 'a>(
+        self: &Arc<Self>,
+        chains: impl IntoIterator<Item = &'a [C]>,
+    ) {
+        let mut leaves = HashSet::new();
+        for chain in chains {
+            if let Some(leaf) = chain.first() {
+                leaves.insert(leaf.as_ref().to_vec());
+                self.track(chain, true);
+            }
+        }
+        self.tracked.write().unwrap().retain(|leaf, tracked| {
+            let keep = !tracked.configured || leaves.contains(leaf);
+            if !keep {
+                tracked.task.abort();
+            }
+            keep
+        });
+    }
+
+    /// Spawns the task fetching and refreshing the responses of a chain
+    fn track<C: AsRef<[u8]>>(self: &Arc<Self>, chain: &[C], configured: bool) {
+        let chain: Vec<_> = chain.iter().map(|cert| cert.as_ref().to_vec()).collect();
+        let leaf = match chain.first() {
+            Some(leaf) => leaf.clone(),
+            None => return,
+        };
+        let mut tracked = self.tracked.write().unwrap();
+        match tracked.entry(leaf) {
+            Entry::Occupied(mut entry) => entry.get_mut().configured |= configured,
+            Entry::Vacant(entry) => {
+                let task = tokio::spawn(self.clone().refresh(chain));
+                entry.insert(Tracked {
+                    staple: None,
+                    configured,
+                    used: AtomicBool::new(false),
+                    task: task.abort_handle(),
+                });
+            }
+        }
+    }
+
+    async fn refresh(self: Arc<Self>, chain: Vec<Vec<u8>>) {
+        let request = match OcspRequest::new(&chain) {
+            Ok(request) => request,
+            Err(e) => {
+                info!("Not stapling OCSP responses: {:#}", e);
+                return;
+            }
+        };
+
+        loop {
+            if request.not_after <= SystemTime::now() {
+                info!(
+                    "Certificate of {} expired, no longer stapling OCSP responses",
+                    request.subject
+                );
+                break;
+            }
+
+            let wait = match self.fetch(&request).await {
+                Ok(staple) => {
+                    let wait = match staple.next_update {
+                        Some(next_update) => {
+                            next_update
+                                .duration_since(SystemTime::now())
+                                .unwrap_or_default()
+                                / 2
+                        }
+                        None => Duration::from_millis(self.setting.refresh_interval_ms),
+                    };
+                    info!("Got OCSP response for {}", request.subject);
+                    if let Some(tracked) = self.tracked.write().unwrap().get_mut(&chain[0]) {
+                        tracked.staple = Some(staple);
+                    }
+                    wait.max(MIN_REFRESH)
+                }
+                Err(e) => {
+                    warn!(
+                        "Could not get OCSP response for {}: {:#}",
+                        request.subject, e
+                    );
+                    Duration::from_millis(self.setting.retry_interval_ms)
+                }
+            };
+            tokio::time::sleep(wait).await;
+
+            let in_use = self
+                .tracked
+                .read()
+                .unwrap()
+                .get(&chain[0])
+                .is_some_and(|tracked| {
+                    tracked.configured || tracked.used.swap(false, Ordering::Relaxed)
+                });
+            if !in_use {
+                info!(
+                    "Certificate of {} is no longer sent, no longer stapling OCSP responses",
+                    request.subject
+                );
+                break;
+            }
+        }
+        self.tracked.write().unwrap().remove(&chain[0]);
+    }
+
+    async fn fetch(&self, request: &OcspRequest) -> anyhow::Result<Staple> {
+        let req = Request::post(&request.url)
+            .header(header::CONTENT_TYPE, "application/ocsp-request")
+            .body(Body::from(request.body.clone()))?;
+        let response = async {
+            let res = self.client.request(req).await?;
+            if !res.status().is_success() {
+                anyhow::bail!("OCSP responder {} returned {}", request.url, res.status());
+            }
+            Ok(body::to_bytes(res.into_body()).await?)
+        };
+        let timeout = Duration::from_millis(self.setting.timeout_ms);
+        let response = tokio::time::timeout(timeout, response)
+            .await
+            .with_context(|| format!("OCSP responder {} timed out", request.url))??;
+
+        let single = parse_response(&response, request)?;
+        match single.status {
+            CertStatus::Good => {}
+            // Still stapled, as clients would find it anyway
+            CertStatus::Revoked => warn!("Certificate of {} is revoked", request.subject),
+            CertStatus::Unknown => anyhow::bail!("OCSP responder does not know the certificate"),
+        }
+        if single
+            .next_update
+            .is_some_and(|next| next <= SystemTime::now())
+        {
+            anyhow::bail!("OCSP response is expired");
+        }
+        Ok(Staple {
+            response: response.to_vec(),
+            next_update: single.next_update,
+        })
+    }
+}
+
+/// Request for the status of a leaf certificate, sent to the responder of
+/// its authority information access
+struct OcspRequest {
+    url: String,
+    body: Vec<u8>,
+    cert_id: CertId,
+    /// DER certificate of the issuer, which signs the responses or delegates
+    /// that to a responder certificate
+    issuer: Vec<u8>,
+    subject: String,
+    not_after: SystemTime,
+}
+
+impl OcspRequest {
+    fn new(chain: &[Vec<u8>]) -> anyhow::Result<Self> {
+        let (_, leaf) = X509Certificate::from_der(&chain[0])
+            .map_err(|e| anyhow::anyhow!("Invalid certificate: {}", e))?;
+        let subject = leaf.subject().to_string();
+        let issuer_der = chain
+            .get(1)
+            .with_context(|| format!("No issuer certificate after the one of {}", subject))?;
+        let (_, issuer) = X509Certificate::from_der(issuer_der)
+            .map_err(|e| anyhow::anyhow!("Invalid issuer certificate: {}", e))?;
+
+        let url =
+            leaf.extensions()
+                .iter()
+                .find_map(|extension| match extension.parsed_extension() {
+                    ParsedExtension::AuthorityInfoAccess(access) => access
+                        .accessdescs
+                        .iter()
+                        .find_map(|description| match &description.access_location {
+                            GeneralName::URI(uri)
+                                if description.access_method == OID_PKIX_ACCESS_DESCRIPTOR_OCSP =>
+                            {
+                                Some(uri.to_string())
+                            }
+                            _ => None,
+                        }),
+                    _ => None,
+                })
+                .with_context(|| format!("No OCSP responder in the certificate of {}", subject))?;
+
+        let sha1 = |data: &[u8]| {
+            ring::digest::digest(&ring::digest::SHA1_FOR_LEGACY_USE_ONLY, data)
+                .as_ref()
+                .to_vec()
+        };
+        let cert_id = CertId {
+            name_hash: sha1(issuer.subject().as_raw()),
+            key_hash: sha1(&issuer.public_key().subject_public_key.data),
+            serial: leaf.raw_serial().to_vec(),
+        };
+        // OCSPRequest, TBSRequest, requestList, Request
+        let body = [0x30, 0x30, 0x30, 0x30]
+            .iter()
+            .fold(cert_id.encode(), |value, &tag| der::encode(tag, &value));
+
+        let not_after = SystemTime::UNIX_EPOCH
+            + Duration::from_secs(leaf.validity().not_after.timestamp().max(0) as u64);
+        Ok(Self {
+            url,
+            body,
+            cert_id,
+            issuer: issuer_der.clone(),
+            subject,
+            not_after,
+        })
+    }
+}
+
+/// Certificate identified by the SHA-1 hashes of its issuer's name and key
+/// and by its serial
+#[derive(Debug, Clone, PartialEq)]
+struct CertId {
+    name_hash: Vec<u8>,
+    key_hash: Vec<u8>,
+    serial: Vec<u8>,
+}
+
+impl CertId {
+    fn encode(&self) -> Vec<u8> {
+        let mut cert_id = SHA1_ALGORITHM.to_vec();
+        cert_id.extend(der::encode(0x04, &self.name_hash));
+        cert_id.extend(der::encode(0x04, &self.key_hash));
+        cert_id.extend(der::encode(0x02, &self.serial));
+        der::encode(0x30, &cert_id)
+    }
+
+    /// Reads the contents of a CertID, `None` if it is not hashed with SHA-1
+    fn parse(contents: &[u8]) -> Option<Self> {
+        let (algorithm, rest) = expect(contents, 0x30)?;
+        if expect(algorithm, 0x06)?.0 != OID_SHA1 {
+            return None;
+        }
+        let (name_hash, rest) = expect(rest, 0x04)?;
+        let (key_hash, rest) = expect(rest, 0x04)?;
+        let (serial, _) = expect(rest, 0x02)?;
+        Some(Self {
+            name_hash: name_hash.to_vec(),
+            key_hash: key_hash.to_vec(),
+            serial: serial.to_vec(),
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum CertStatus {
+    Good,
+    Revoked,
+    Unknown,
+}
+
+#[derive(Debug)]
+struct SingleResponse {
+    status: CertStatus,
+    next_update: Option<SystemTime>,
+}
+
+/// Status of the certificate of `request` in a DER OCSP response, which
+/// must be signed by the issuer or by a responder it delegated to
+fn parse_response(response: &[u8], request: &OcspRequest) -> anyhow::Result<SingleResponse> {
+    let invalid = || anyhow::anyhow!("Invalid OCSP response");
+
+    let (response, _) = expect(response, 0x30).ok_or_else(invalid)?;
+    let (status, rest) = expect(response, 0x0a).ok_or_else(invalid)?;
+    match status {
+        [0] => {}
+        [1] => anyhow::bail!("OCSP responder rejected the request as malformed"),
+        [2] => anyhow::bail!("OCSP responder had an internal error"),
+        [3] => anyhow::bail!("OCSP responder asked to try later"),
+        [5] => anyhow::bail!("OCSP responder requires signed requests"),
+        [6] => anyhow::bail!("OCSP responder is not authorized for the certificate"),
+        _ => anyhow::bail!("OCSP responder returned status {:?}", status),
+    }
+
+    let basic = (|| {
+        let (bytes, _) = expect(rest, 0xa0)?;
+        let (bytes, _) = expect(bytes, 0x30)?;
+        let (oid, rest) = expect(bytes, 0x06)?;
+        if oid != OID_OCSP_BASIC {
+            return None;
+        }
+        let (basic, _) = expect(rest, 0x04)?;
+        Some(expect(basic, 0x30)?.0)
+    })()
+    .ok_or_else(invalid)?;
+
+    verify_response(basic, &request.issuer)?;
+    single_response(basic, &request.cert_id)
+        .ok_or_else(invalid)?
+        .context("OCSP response is not for the certificate")
+}
+
+/// Checks the signature of the contents of a BasicOCSPResponse with the
+/// key of the issuer, or of one of the included certificates which the
+/// issuer signed for OCSP signing
+fn verify_response(basic: &[u8], issuer: &[u8]) -> anyhow::Result<()> {
+    let invalid = || anyhow::anyhow!("Invalid OCSP response");
+
+    let (_, _, rest) = der::read(basic).ok_or_else(invalid)?;
+    let data = &basic[..basic.len() - rest.len()];
+    let (rest, algorithm) = AlgorithmIdentifier::from_der(rest).map_err(|_| invalid())?;
+    let (rest, signature) = BitString::from_der(rest).map_err(|_| invalid())?;
+    let certs = match expect(rest, 0xa0) {
+        Some((certs, _)) => expect(certs, 0x30).ok_or_else(invalid)?.0,
+        None => &[],
+    };
+
+    let (_, issuer) = X509Certificate::from_der(issuer)
+        .map_err(|e| anyhow::anyhow!("Invalid issuer certificate: {}", e))?;
+    if verify_signature(issuer.public_key(), &algorithm, &signature, data).is_ok() {
+        return Ok(());
+    }
+
+    let mut certs = certs;
+    while let Some((_, _, rest)) = der::read(certs) {
+        let cert = &certs[..certs.len() - rest.len()];
+        certs = rest;
+        let responder = match X509Certificate::from_der(cert) {
+            Ok((_, responder)) => responder,
+            Err(_) => continue,
+        };
+        let delegated = matches!(
+            responder.extended_key_usage(),
+            Ok(Some(usage)) if usage.value.ocsp_signing
+        );
+        if delegated
+            && responder.validity().is_valid()
+            && responder
+                .verify_signature(Some(issuer.public_key()))
+                .is_ok()
+            && verify_signature(responder.public_key(), &algorithm, &signature, data).is_ok()
+        {
+            return Ok(());
+        }
+    }
+    anyhow::bail!("OCSP response is not signed by the issuer or a responder it delegated to")
+}
+
+/// Response for `cert_id` in the contents of a BasicOCSPResponse
+fn single_response(basic: &[u8], cert_id: &CertId) -> Option<Option<SingleResponse>> {
+    let (data, _) = expect(basic, 0x30)?;
+
+    // Optional version, then the responder id and production time
+    let (tag, _, mut rest) = der::read(data)?;
+    if tag == 0xa0 {
+        rest = der::read(rest)?.2;
+    }
+    let (_, rest) = expect(rest, 0x18)?;
+    let (mut responses, _) = expect(rest, 0x30)?;
+
+    while !responses.is_empty() {
+        let (single, next) = expect(responses, 0x30)?;
+        responses = next;
+        let (id, rest) = expect(single, 0x30)?;
+        if CertId::parse(id).as_ref() != Some(cert_id) {
+            continue;
+        }
+
+        let (tag, _, rest) = der::read(rest)?;
+        let status = match tag {
+            0x80 => CertStatus::Good,
+            0xa1 => CertStatus::Revoked,
+            _ => CertStatus::Unknown,
+        };
+        let (_, rest) = expect(rest, 0x18)?;
+        let next_update = match expect(rest, 0xa0) {
+            Some((next_update, _)) => {
+                let (_, next_update) = ASN1Time::from_der(next_update).ok()?;
+                let secs = u64::try_from(next_update.timestamp()).ok()?;
+                Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+            }
+            None => None,
+        };
+        return Some(Some(SingleResponse {
+            status,
+            next_update,
+        }));
+    }
+    Some(None)
+}
+
+/// Contents of the element with `tag` starting `input`, and what follows it
+fn expect(input: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    let (found, contents, rest) = der::read(input)?;
+    Some((contents, rest)).filter(|_| found == tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Response, Server, StatusCode,
+    };
+    use rcgen::{
+        BasicConstraints, Certificate, CertificateParams, CustomExtension, DnType,
+        ExtendedKeyUsagePurpose, IsCa,
+    };
+    use ring::{
+        rand::SystemRandom,
+        signature::{EcdsaKeyPair, ECDSA_P256_SHA256_ASN1_SIGNING},
+    };
+    use std::{convert::Infallible, sync::Mutex};
+
+    const URL: &str = "http://127.0.0.1:1/ocsp";
+    const OID_AD_OCSP: &[u8] = b"\x2b\x06\x01\x05\x05\x07\x30\x01";
+    const OID_ECDSA_SHA256: &[u8] = b"\x2a\x86\x48\xce\x3d\x04\x03\x02";
+    const GOOD: &[u8] = b"\x80\x00";
+    const UNKNOWN: &[u8] = b"\x82\x00";
+
+    fn ca(name: &str) -> Certificate {
+        let mut params = CertificateParams::new(vec![]);
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        Certificate::from_params(params).unwrap()
+    }
+
+    fn leaf(ca: &Certificate, serial: u64, responder: Option<&str>) -> Vec<u8> {
+        let mut params = CertificateParams::new(vec!["localhost".to_owned()]);
+        params.serial_number = Some(serial);
+        if let Some(url) = responder {
+            let access = [
+                der::encode(0x06, OID_AD_OCSP),
+                der::encode(0x86, url.as_bytes()),
+            ];
+            let access = der::encode(0x30, &der::encode(0x30, &access.concat()));
+            params
+                .custom_extensions
+                .push(CustomExtension::from_oid_content(
+                    &[1, 3, 6, 1, 5, 5, 7, 1, 1],
+                    access,
+                ));
+        }
+        let leaf = Certificate::from_params(params).unwrap();
+        leaf.serialize_der_with_signer(ca).unwrap()
+    }
+
+    fn responder(ocsp_signing: bool) -> Certificate {
+        let mut params = CertificateParams::new(vec![]);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "Responder");
+        if ocsp_signing {
+            params.extended_key_usages = vec![ExtendedKeyUsagePurpose::OcspSigning];
+        }
+        Certificate::from_params(params).unwrap()
+    }
+
+    fn request(ca: &Certificate) -> OcspRequest {
+        let chain = [leaf(ca, 42, Some(URL)), ca.serialize_der().unwrap()];
+        OcspRequest::new(&chain).unwrap()
+    }
+
+    fn single(cert_id: &CertId, status: &[u8]) -> Vec<u8> {
+        single_until(cert_id, status, Some(b"20991231000000Z"))
+    }
+
+    fn single_until(cert_id: &CertId, status: &[u8], next_update: Option<&[u8]>) -> Vec<u8> {
+        let mut single = cert_id.encode();
+        single.extend(status);
+        single.extend(der::encode(0x18, b"20260101000000Z"));
+        if let Some(next_update) = next_update {
+            single.extend(der::encode(0xa0, &der::encode(0x18, next_update)));
+        }
+        der::encode(0x30, &single)
+    }
+
+    /// Successful response with `singles`, signed by `signer` and including
+    /// `certs`
+    fn response(singles: &[Vec<u8>], signer: &Certificate, certs: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = der::encode(0xa2, &der::encode(0x04, &[0; 20]));
+        data.extend(der::encode(0x18, b"20260101000000Z"));
+        data.extend(der::encode(0x30, &singles.concat()));
+        let mut basic = der::encode(0x30, &data);
+
+        let key = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_ASN1_SIGNING,
+            &signer.serialize_private_key_der(),
+        )
+        .unwrap();
+        let signature = key.sign(&SystemRandom::new(), &basic).unwrap();
+        basic.extend(der::encode(0x30, &der::encode(0x06, OID_ECDSA_SHA256)));
+        basic.extend(der::encode(0x03, &[&[0], signature.as_ref()].concat()));
+        if !certs.is_empty() {
+            basic.extend(der::encode(0xa0, &der::encode(0x30, &certs.concat())));
+        }
+
+        let bytes = [
+            der::encode(0x06, OID_OCSP_BASIC),
+            der::encode(0x04, &der::encode(0x30, &basic)),
+        ];
+        let response = [
+            der::encode(0x0a, &[0]),
+            der::encode(0xa0, &der::encode(0x30, &bytes.concat())),
+        ];
+        der::encode(0x30, &response.concat())
+    }
+
+    fn parse(response: &[u8], request: &OcspRequest) -> Result<SingleResponse, String> {
+        parse_response(response, request).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn request_for_leaf() {
+        let ca = ca("Test CA");
+        let request = request(&ca);
+        assert_eq!(request.url, URL);
+        assert_eq!(request.cert_id.serial, [42]);
+
+        let ca_der = ca.serialize_der().unwrap();
+        let (_, issuer) = X509Certificate::from_der(&ca_der).unwrap();
+        let sha1 = |data: &[u8]| {
+            ring::digest::digest(&ring::digest::SHA1_FOR_LEGACY_USE_ONLY, data)
+                .as_ref()
+                .to_vec()
+        };
+        assert_eq!(request.cert_id.name_hash, sha1(issuer.subject().as_raw()));
+        assert_eq!(
+            request.cert_id.key_hash,
+            sha1(&issuer.public_key().subject_public_key.data)
+        );
+
+        // OCSPRequest, TBSRequest, requestList, Request and CertID
+        let mut body = &request.body[..];
+        for _ in 0..4 {
+            let (contents, rest) = expect(body, 0x30).unwrap();
+            assert!(rest.is_empty());
+            body = contents;
+        }
+        let (cert_id, _) = expect(body, 0x30).unwrap();
+        assert_eq!(CertId::parse(cert_id), Some(request.cert_id));
+    }
+
+    #[test]
+    fn request_needs_responder_and_issuer() {
+        let ca = ca("Test CA");
+        let chain = [leaf(&ca, 42, None), ca.serialize_der().unwrap()];
+        let e = OcspRequest::new(&chain).err().unwrap().to_string();
+        assert!(e.starts_with("No OCSP responder"), "{}", e);
+
+        let chain = [leaf(&ca, 42, Some(URL))];
+        let e = OcspRequest::new(&chain).err().unwrap().to_string();
+        assert!(e.starts_with("No issuer certificate"), "{}", e);
+    }
+
+    #[test]
+    fn good_revoked_and_unknown() {
+        let ca = ca("Test CA");
+        let request = request(&ca);
+        let next_update = Some(SystemTime::UNIX_EPOCH + Duration::from_secs(4_102_358_400));
+
+        let good = response(&[single(&request.cert_id, GOOD)], &ca, &[]);
+        let single_response = parse(&good, &request).unwrap();
+        assert_eq!(single_response.status, CertStatus::Good);
+        assert_eq!(single_response.next_update, next_update);
+
+        let revoked = der::encode(0xa1, &der::encode(0x18, b"20260101000000Z"));
+        let revoked = response(&[single(&request.cert_id, &revoked)], &ca, &[]);
+        assert_eq!(
+            parse(&revoked, &request).unwrap().status,
+            CertStatus::Revoked
+        );
+
+        let unknown = response(&[single(&request.cert_id, UNKNOWN)], &ca, &[]);
+        assert_eq!(
+            parse(&unknown, &request).unwrap().status,
+            CertStatus::Unknown
+        );
+    }
+
+    #[test]
+    fn without_next_update() {
+        let ca = ca("Test CA");
+        let request = request(&ca);
+        let response = response(&[single_until(&request.cert_id, GOOD, None)], &ca, &[]);
+        assert_eq!(parse(&response, &request).unwrap().next_update, None);
+    }
+
+    #[test]
+    fn invalid_next_update() {
+        let ca = ca("Test CA");
+        let request = request(&ca);
+        for next_update in [
+            &b"20991331000000Z"[..],
+            b"20991231250000Z",
+            b"20990230000000Z",
+            b"20991231000000",
+            b"2099123100Z",
+        ] {
+            let single = single_until(&request.cert_id, GOOD, Some(next_update));
+            let response = response(&[single], &ca, &[]);
+            assert_eq!(
+                parse(&response, &request).err().unwrap(),
+                "Invalid OCSP response",
+                "{:?}",
+                std::str::from_utf8(next_update)
+            );
+        }
+    }
+
+    #[test]
+    fn picks_response_of_certificate() {
+        let ca = ca("Test CA");
+        let request = request(&ca);
+        let other = CertId {
+            serial: vec![43],
+            ..request.cert_id.clone()
+        };
+        let unknown = single(&other, UNKNOWN);
+        let response = response(&[unknown, single(&request.cert_id, GOOD)], &ca, &[]);
+        assert_eq!(parse(&response, &request).unwrap().status, CertStatus::Good);
+    }
+
+    #[test]
+    fn other_certificate() {
+        let ca = ca("Test CA");
+        let request = request(&ca);
+        let other_ids = [
+            CertId {
+                serial: vec![43],
+                ..request.cert_id.clone()
+            },
+            CertId {
+                name_hash: vec![0; 20],
+                ..request.cert_id.clone()
+            },
+            CertId {
+                key_hash: vec![0; 20],
+                ..request.cert_id.clone()
+            },
+        ];
+        for cert_id in &other_ids {
+            let response = response(&[single(cert_id, GOOD)], &ca, &[]);
+            assert_eq!(
+                parse(&response, &request).err().unwrap(),
+                "OCSP response is not for the certificate"
+            );
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        let ca = ca("Test CA");
+        let request = request(&ca);
+        let good = response(&[single(&request.cert_id, GOOD)], &ca, &[]);
+        for len in [0, 1, 5, 20, good.len() / 2, good.len() - 1] {
+            assert_eq!(
+                parse(&good[..len], &request).err().unwrap(),
+                "Invalid OCSP response"
+            );
+        }
+        let not_basic = der::encode(
+            0x30,
+            &[
+                der::encode(0x0a, &[0]),
+                der::encode(
+                    0xa0,
+                    &der::encode(
+                        0x30,
+                        &[der::encode(0x06, OID_SHA1), der::encode(0x04, b"")].concat(),
+                    ),
+                ),
+            ]
+            .concat(),
+        );
+        assert_eq!(
+            parse(&not_basic, &request).err().unwrap(),
+            "Invalid OCSP response"
+        );
+    }
+
+    #[test]
+    fn unsuccessful_status() {
+        let ca = ca("Test CA");
+        let request = request(&ca);
+        let status = |status| der::encode(0x30, &der::encode(0x0a, &[status]));
+        assert_eq!(
+            parse(&status(3), &request).err().unwrap(),
+            "OCSP responder asked to try later"
+        );
+        assert_eq!(
+            parse(&status(6), &request).err().unwrap(),
+            "OCSP responder is not authorized for the certificate"
+        );
+        assert_eq!(
+            parse(&status(9), &request).err().unwrap(),
+            "OCSP responder returned status [9]"
+        );
+    }
+
+    #[test]
+    fn signed_by_other_issuer() {
+        let ca = ca("Test CA");
+        let request = request(&ca);
+        let other = self::ca("Other CA");
+        let response = response(&[single(&request.cert_id, GOOD)], &other, &[]);
+        assert_eq!(
+            parse(&response, &request).err().unwrap(),
+            "OCSP response is not signed by the issuer or a responder it delegated to"
+        );
+    }
+
+    #[test]
+    fn delegated_responder() {
+        let ca = ca("Test CA");
+        let request = request(&ca);
+        let other = self::ca("Other CA");
+        let single = single(&request.cert_id, GOOD);
+
+        let delegated = responder(true);
+        let cert = delegated.serialize_der_with_signer(&ca).unwrap();
+        let signed = response(std::slice::from_ref(&single), &delegated, &[cert]);
+        assert_eq!(parse(&signed, &request).unwrap().status, CertStatus::Good);
+
+        // Not allowed to sign responses, or not by the issuer
+        let not_delegated = responder(false);
+        let cert = not_delegated.serialize_der_with_signer(&ca).unwrap();
+        let signed = response(std::slice::from_ref(&single), &not_delegated, &[cert]);
+        assert!(parse(&signed, &request).is_err());
+
+        let foreign = responder(true);
+        let cert = foreign.serialize_der_with_signer(&other).unwrap();
+        let signed = response(&[single], &foreign, &[cert]);
+        assert!(parse(&signed, &request).is_err());
+    }
+
+    /// Loopback responder answering OCSP requests with the queued responses,
+    /// the last one being repeated, and recording their bodies
+    struct Responder {
+        url: String,
+        requests: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl Responder {
+        fn start(responses: Vec<(StatusCode, Vec<u8>)>) -> Self {
+            let responses = Arc::new(Mutex::new(responses));
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let recorded = requests.clone();
+            let make_service = make_service_fn(move |_| {
+                let (responses, requests) = (responses.clone(), recorded.clone());
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                        let (responses, requests) = (responses.clone(), requests.clone());
+                        async move {
+                            let content_type = req.headers().get(header::CONTENT_TYPE);
+                            if content_type.is_none_or(|v| v != "application/ocsp-request") {
+                                let mut res = Response::new(Body::empty());
+                                *res.status_mut() = StatusCode::UNSUPPORTED_MEDIA_TYPE;
+                                return Ok(res);
+                            }
+                            let body = body::to_bytes(req.into_body()).await.unwrap();
+                            requests.lock().unwrap().push(body.to_vec());
+                            let mut responses = responses.lock().unwrap();
+                            let (status, body) = if responses.len() > 1 {
+                                responses.remove(0)
+                            } else {
+                                responses[0].clone()
+                            };
+                            let mut res = Response::new(Body::from(body));
+                            *res.status_mut() = status;
+                            Ok::<_, Infallible>(res)
+                        }
+                    }))
+                }
+            });
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}/ocsp", listener.local_addr().unwrap());
+            tokio::spawn(Server::from_tcp(listener).unwrap().serve(make_service));
+            Self { url, requests }
+        }
+    }
+
+    fn setting() -> OcspSetting {
+        OcspSetting {
+            enabled: true,
+            timeout_ms: 5000,
+            retry_interval_ms: 10,
+            refresh_interval_ms: 10,
+        }
+    }
+
+    #[tokio::test]
+    async fn fetch_from_responder() {
+        let ca = ca("Test CA");
+        let cert_id = request(&ca).cert_id;
+        let good = response(&[single(&cert_id, GOOD)], &ca, &[]);
+        let expired = single_until(&cert_id, GOOD, Some(b"20200101000000Z"));
+        let responder = Responder::start(vec![
+            (StatusCode::OK, good.clone()),
+            (StatusCode::OK, response(&[expired], &ca, &[])),
+            (
+                StatusCode::OK,
+                response(&[single(&cert_id, UNKNOWN)], &ca, &[]),
+            ),
+            (StatusCode::SERVICE_UNAVAILABLE, vec![]),
+        ]);
+        let stapler = Stapler::new(setting()).unwrap();
+        let chain = [
+            leaf(&ca, 42, Some(&responder.url)),
+            ca.serialize_der().unwrap(),
+        ];
+        let request = OcspRequest::new(&chain).unwrap();
+
+        let staple = stapler.fetch(&request).await.unwrap();
+        assert_eq!(staple.response, good);
+        assert_eq!(
+            staple.next_update,
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(4_102_358_400))
+        );
+        let fetch = |request| {
+            let stapler = stapler.clone();
+            async move { stapler.fetch(request).await.err().unwrap().to_string() }
+        };
+        assert_eq!(fetch(&request).await, "OCSP response is expired");
+        assert_eq!(
+            fetch(&request).await,
+            "OCSP responder does not know the certificate"
+        );
+        assert_eq!(
+            fetch(&request).await,
+            format!(
+                "OCSP responder {} returned 503 Service Unavailable",
+                responder.url
+            )
+        );
+
+        let requests = responder.requests.lock().unwrap();
+        assert_eq!(requests.len(), 4);
+        assert!(requests.iter().all(|body| *body == request.body));
+    }
+
+    #[tokio::test]
+    async fn refresh_responses() {
+        let ca = ca("Test CA");
+        let cert_id = request(&ca).cert_id;
+        let good = response(&[single_until(&cert_id, GOOD, None)], &ca, &[]);
+        let revoked = der::encode(0xa1, &der::encode(0x18, b"20260101000000Z"));
+        let revoked = response(&[single_until(&cert_id, &revoked, None)], &ca, &[]);
+        // Retried after the failure, then refreshed without a next update
+        let responder = Responder::start(vec![
+            (StatusCode::INTERNAL_SERVER_ERROR, vec![]),
+            (StatusCode::OK, good.clone()),
+            (StatusCode::OK, revoked.clone()),
+        ]);
+        let stapler = Stapler::new(setting()).unwrap();
+        let chain = [
+            leaf(&ca, 42, Some(&responder.url)),
+            ca.serialize_der().unwrap(),
+        ];
+        stapler.configure([&chain[..]]);
+        assert_eq!(stapler.get(&chain), None);
+
+        let stapled = |expected: Vec<u8>| {
+            let stapler = stapler.clone();
+            let chain = chain.clone();
+            async move {
+                while stapler.get(&chain) != Some(expected.clone()) {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), stapled(good))
+            .await
+            .unwrap();
+        assert_eq!(responder.requests.lock().unwrap().len(), 2);
+        // Refreshed no sooner than MIN_REFRESH
+        let start = tokio::time::Instant::now();
+        tokio::time::timeout(Duration::from_secs(5), stapled(revoked))
+            .await
+            .unwrap();
+        assert!(start.elapsed() >= MIN_REFRESH - Duration::from_millis(100));
+        assert_eq!(responder.requests.lock().unwrap().len(), 3);
+
+        stapler.configure(Vec::<&[Vec<u8>]>::new());
+        assert!(stapler.tracked.read().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn configure_stops_replaced_chains() {
+        let setting = OcspSetting {
+            retry_interval_ms: 3_600_000,
+            ..OcspSetting::default()
+        };
+        let stapler = Stapler::new(setting).unwrap();
+        let ca = ca("Test CA");
+        let chain = |serial| vec![leaf(&ca, serial, Some(URL)), ca.serialize_der().unwrap()];
+        let (first, second, seen) = (chain(1), chain(2), chain(3));
+
+        stapler.configure([&first[..]]);
+        assert_eq!(stapler.get(&seen), None);
+        // Held by the test and the refresh tasks of both chains
+        assert_eq!(Arc::strong_count(&stapler), 3);
+
+        stapler.configure([&second[..]]);
+        let aborted = async {
+            while Arc::strong_count(&stapler) > 3 {
+                tokio::task::yield_now().await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), aborted)
+            .await
+            .unwrap();
+        let tracked = stapler.tracked.read().unwrap();
+        let mut leaves: Vec<_> = tracked.keys().collect();
+        leaves.sort();
+        // Chains sent by handshakes are kept
+        let mut expected = vec![&second[0], &seen[0]];
+        expected.sort();
+        assert_eq!(leaves, expected);
+    }
+}
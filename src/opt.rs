@@ -7,6 +7,7 @@ pub enum Opt {
     Client {
         /// Config file
         #[structopt(short, long, parse(from_os_str))]
+        #[allow(dead_code)]
         config: PathBuf,
     },
     /// Start server
@@ -0,0 +1,193 @@
+use crate::certs::{CertificateFiles, Certificates, KeyPair};
+use anyhow::Context;
+use std::time::{Duration, SystemTime};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    time::Interval,
+};
+use tracing::{error, info};
+
+/// Spawns the task loading the certificates again when their files change
+/// or on SIGHUP, and handing them to `apply`. The current certificates are
+/// kept when this fails.
+pub fn start<F>(files: CertificateFiles, watch_interval_ms: u64, apply: F) -> anyhow::Result<()>
+where
+    F: Fn(Certificates<KeyPair>) -> anyhow::Result<()> + Send + 'static,
+{
+    let mut hangup = signal(SignalKind::hangup()).context("Could not listen for SIGHUP")?;
+    let mut interval = if watch_interval_ms > 0 {
+        Some(tokio::time::interval(Duration::from_millis(
+            watch_interval_ms,
+        )))
+    } else {
+        None
+    };
+
+    tokio::spawn(async move {
+        let mut modified = modified_times(&files);
+        loop {
+            let reason = tokio::select! {
+                _ = hangup.recv() => "SIGHUP",
+                _ = tick(&mut interval) => "changed files",
+            };
+            // Files being written trigger another reload once complete
+            let current = modified_times(&files);
+            if reason != "SIGHUP" && current == modified {
+                continue;
+            }
+            modified = current;
+
+            info!("Reloading certificates on {}", reason);
+            match files.load().and_then(&apply) {
+                Ok(()) => info!("Reloaded certificates"),
+                Err(e) => error!(
+                    "Could not reload certificates, keeping the current ones: {:#}",
+                    e
+                ),
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+fn modified_times(files: &CertificateFiles) -> Vec<Option<SystemTime>> {
+    files
+        .paths()
+        .map(|path| {
+            std::fs::metadata(path)
+                .and_then(|meta| meta.modified())
+                .ok()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        acme::test_server::handshake,
+        certs::KeyPair,
+        pem::load_certs,
+        settings::TlsSetting,
+        tls::{CertResolver, SharedConfig},
+        utils::write_file,
+    };
+    use rustls::{NoClientAuth, ServerConfig};
+    use std::{path::Path, sync::Arc};
+    use tempfile::TempDir;
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    /// Writes a new self-signed certificate for localhost, returning it
+    fn write_cert(dir: &Path) -> Vec<u8> {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        write_file(dir, "key.pem", cert.serialize_private_key_pem());
+        let path = write_file(dir, "cert.pem", cert.serialize_pem().unwrap());
+        load_certs(&path).unwrap().remove(0).0
+    }
+
+    fn server_config(certificates: Certificates<KeyPair>) -> anyhow::Result<ServerConfig> {
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config.cert_resolver = Arc::new(CertResolver::new(
+            certificates.try_map(KeyPair::certified_key)?,
+            None,
+            None,
+        ));
+        Ok(config)
+    }
+
+    /// Watches the certificate in `dir`, applying it to the returned config
+    fn start_watching(dir: &Path, watch_interval_ms: u64) -> SharedConfig {
+        let tls = TlsSetting {
+            cert: Some(dir.join("cert.pem")),
+            key: Some(dir.join("key.pem")),
+            ..TlsSetting::default()
+        };
+        let files = CertificateFiles::new(&tls, &[]).unwrap();
+        let config = SharedConfig::new(server_config(files.load().unwrap()).unwrap());
+        let shared = config.clone();
+        start(files, watch_interval_ms, move |certificates| {
+            shared.set(server_config(certificates)?);
+            Ok(())
+        })
+        .unwrap();
+        config
+    }
+
+    /// Certificate sent by a handshake with the current config
+    async fn served_cert(config: &SharedConfig) -> Vec<u8> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = TlsAcceptor::from(config.get());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            acceptor.accept(stream).await
+        });
+        handshake(addr, "localhost", None).await.unwrap().0
+    }
+
+    async fn wait_for_cert(config: &SharedConfig, cert: &[u8]) {
+        for _ in 0..250 {
+            if served_cert(config).await == cert {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("certificate was not reloaded");
+    }
+
+    #[tokio::test]
+    async fn reload_changed_files() {
+        let dir = TempDir::new().unwrap();
+        let first = write_cert(dir.path());
+        let config = start_watching(dir.path(), 10);
+        assert_eq!(served_cert(&config).await, first);
+        // Lets the task read the modification times of the first files
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let second = write_cert(dir.path());
+        wait_for_cert(&config, &second).await;
+
+        write_file(dir.path(), "cert.pem", "not a certificate");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(served_cert(&config).await, second);
+
+        // Still watching after the failure
+        let third = write_cert(dir.path());
+        wait_for_cert(&config, &third).await;
+    }
+
+    #[tokio::test]
+    async fn reload_on_sighup() {
+        let dir = TempDir::new().unwrap();
+        let first = write_cert(dir.path());
+        let config = start_watching(dir.path(), 0);
+        let hangup = || {
+            let status = std::process::Command::new("kill")
+                .args(["-HUP", &std::process::id().to_string()])
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+
+        let second = write_cert(dir.path());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(served_cert(&config).await, first);
+        hangup();
+        wait_for_cert(&config, &second).await;
+
+        write_file(dir.path(), "key.pem", "not a key");
+        hangup();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(served_cert(&config).await, second);
+    }
+}
@@ -0,0 +1,467 @@
+use crate::{
+    error_page::ErrorPages,
+    host::{normalize_host, HostMap},
+    settings::{PathMatch, RouteOptions, RoutesSetting, ServerSetting},
+    upstream::{UpstreamPool, Upstreams},
+};
+use regex::{Captures, Regex};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+use tracing::warn;
+
+/// Pools kept per templated target, past which the oldest ones are dropped
+/// so hosts matching a regex cannot grow it without bound
+const MAX_TEMPLATE_POOLS: usize = 1024;
+
+pub struct Router {
+    servers: HostMap<ServerRoutes>,
+    default_server: Option<ServerRoutes>,
+}
+
+struct ServerRoutes {
+    proxy_pass: Option<Target>,
+    exact: HashMap<String, Target>,
+    // Sorted by descending path length so the first match is the longest one
+    prefixes: Vec<PrefixRoute>,
+    regexes: Vec<(Regex, Target)>,
+}
+
+struct PrefixRoute {
+    prefix: String,
+    priority: bool,
+    proxy_pass: Target,
+}
+
+struct Target {
+    proxy_pass: ProxyPass,
+    options: Arc<RouteOptions>,
+    error_pages: Arc<ErrorPages>,
+}
+
+enum ProxyPass {
+    Pool(Arc<UpstreamPool>),
+    /// `proxy_pass` of a regex host containing capture group references,
+    /// with the pools of the addresses it was expanded to
+    Template(String, Mutex<TemplatePools>),
+}
+
+/// Pools by expanded address, oldest first, kept so their balancing and
+/// failure state lasts across requests
+#[derive(Default)]
+struct TemplatePools {
+    pools: HashMap<String, Arc<UpstreamPool>>,
+    order: VecDeque<String>,
+}
+
+/// Upstream pool and options of the server or location matching a request
+pub struct Route {
+    pub pool: Arc<UpstreamPool>,
+    pub options: Arc<RouteOptions>,
+    pub error_pages: Arc<ErrorPages>,
+}
+
+impl Router {
+    /// Hosts are matched as by [`HostMap`], and the capture groups of regex
+    /// hosts can be used as `{1}` or `{name}` in `proxy_pass`.
+    pub fn new(
+        servers: Vec<ServerSetting>,
+        default_server: Option<RoutesSetting>,
+        upstreams: &mut Upstreams,
+    ) -> anyhow::Result<Self> {
+        let mut router = Self {
+            servers: HostMap::default(),
+            default_server: default_server
+                .map(|routes| ServerRoutes::new(routes, upstreams, false))
+                .transpose()?,
+        };
+
+        for server in servers {
+            let templated = server.host.starts_with('~');
+            let routes = ServerRoutes::new(server.routes, upstreams, templated)?;
+            router.servers.insert(&server.host, routes)?;
+        }
+
+        Ok(router)
+    }
+
+    /// Returns the route for the request, or `None` if nothing matches.
+    /// Requests without a known host are routed by the default server.
+    pub fn route(&self, host: Option<&str>, path: &str) -> Option<Route> {
+        let host = host.map(normalize_host);
+
+        if let Some((routes, captures)) = host.as_deref().and_then(|host| self.servers.get(host)) {
+            return routes
+                .route(path)
+                .and_then(|target| target.route(captures.as_ref()));
+        }
+
+        self.default_server
+            .as_ref()
+            .and_then(|routes| routes.route(path))
+            .and_then(|target| target.route(None))
+    }
+}
+
+impl ServerRoutes {
+    fn new(
+        setting: RoutesSetting,
+        upstreams: &mut Upstreams,
+        templated: bool,
+    ) -> anyhow::Result<Self> {
+        let mut target = |proxy_pass: String, options: RouteOptions| -> anyhow::Result<Target> {
+            let proxy_pass = if templated && proxy_pass.contains('{') {
+                ProxyPass::Template(proxy_pass, Mutex::default())
+            } else {
+                ProxyPass::Pool(upstreams.resolve(&proxy_pass)?)
+            };
+            let error_pages = match &options.error_pages {
+                Some(setting) => ErrorPages::new(setting)?,
+                None => ErrorPages::default(),
+            };
+            Ok(Target {
+                proxy_pass,
+                options: Arc::new(options),
+                error_pages: Arc::new(error_pages),
+            })
+        };
+
+        let options = setting.options;
+        let mut routes = Self {
+            proxy_pass: setting
+                .proxy_pass
+                .map(|proxy_pass| target(proxy_pass, options.clone()))
+                .transpose()?,
+            exact: HashMap::new(),
+            prefixes: vec![],
+            regexes: vec![],
+        };
+
+        for location in setting.locations {
+            let options = location.options.inherit(&options);
+            let proxy_pass = target(location.proxy_pass, options)?;
+            match location.matches {
+                PathMatch::Exact => {
+                    routes.exact.insert(location.path, proxy_pass);
+                }
+                PathMatch::Prefix | PathMatch::PriorityPrefix => {
+                    routes.prefixes.push(PrefixRoute {
+                        priority: location.matches == PathMatch::PriorityPrefix,
+                        prefix: location.path,
+                        proxy_pass,
+                    })
+                }
+                PathMatch::Regex => {
+                    let path = location.path;
+                    let regex = Regex::new(&path)
+                        .map_err(|e| anyhow::anyhow!("Invalid location regex {:?}: {}", path, e))?;
+                    routes.regexes.push((regex, proxy_pass));
+                }
+            }
+        }
+
+        routes
+            .prefixes
+            .sort_by_key(|route| std::cmp::Reverse(route.prefix.len()));
+
+        Ok(routes)
+    }
+
+    /// Picks a backend using nginx precedence: exact match, then the longest
+    /// prefix if it is a priority prefix, then the first matching regex in
+    /// declaration order, then the longest prefix, then the server default.
+    fn route(&self, path: &str) -> Option<&Target> {
+        if let Some(proxy_pass) = self.exact.get(path) {
+            return Some(proxy_pass);
+        }
+
+        let prefix = self
+            .prefixes
+            .iter()
+            .find(|route| path.starts_with(&route.prefix));
+
+        if let Some(route) = prefix.filter(|route| route.priority) {
+            return Some(&route.proxy_pass);
+        }
+
+        self.regexes
+            .iter()
+            .find(|(regex, _)| regex.is_match(path))
+            .map(|(_, proxy_pass)| proxy_pass)
+            .or_else(|| prefix.map(|route| &route.proxy_pass))
+            .or(self.proxy_pass.as_ref())
+    }
+}
+
+impl Target {
+    fn route(&self, captures: Option<&Captures>) -> Option<Route> {
+        let pool = match (&self.proxy_pass, captures) {
+            (ProxyPass::Pool(pool), _) => pool.clone(),
+            (ProxyPass::Template(template, pools), Some(captures)) => {
+                let proxy_pass = expand_captures(template, captures)?;
+                pools.lock().unwrap().get_or_insert(proxy_pass)?
+            }
+            (ProxyPass::Template(..), None) => return None,
+        };
+        Some(Route {
+            pool,
+            options: self.options.clone(),
+            error_pages: self.error_pages.clone(),
+        })
+    }
+}
+
+impl TemplatePools {
+    fn get_or_insert(&mut self, proxy_pass: String) -> Option<Arc<UpstreamPool>> {
+        if let Some(pool) = self.pools.get(&proxy_pass) {
+            return Some(pool.clone());
+        }
+        let pool = UpstreamPool::single(&proxy_pass)
+            .map_err(|e| warn!("Invalid proxy_pass {:?}: {}", proxy_pass, e))
+            .ok()
+            .map(Arc::new)?;
+        if self.order.len() >= MAX_TEMPLATE_POOLS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.pools.remove(&oldest);
+            }
+        }
+        self.order.push_back(proxy_pass.clone());
+        self.pools.insert(proxy_pass, pool.clone());
+        Some(pool)
+    }
+}
+
+/// Replaces `{1}` or `{name}` in `proxy_pass` with the matching capture
+/// group, or returns `None` if a group has anything but letters, digits,
+/// dots and dashes, which could make the client pick any upstream address
+fn expand_captures(proxy_pass: &str, captures: &Captures) -> Option<String> {
+    let mut expanded = String::with_capacity(proxy_pass.len());
+    let mut rest = proxy_pass;
+
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let group = rest.find('}').and_then(|end| {
+            let name = &rest[1..end];
+            let value = match name.parse::<usize>() {
+                Ok(index) => captures.get(index),
+                Err(_) => captures.name(name),
+            };
+            value.map(|value| (value.as_str(), end))
+        });
+        match group {
+            Some((value, end)) => {
+                let valid = |b: u8| b.is_ascii_alphanumeric() || b == b'.' || b == b'-';
+                if !value.bytes().all(valid) {
+                    return None;
+                }
+                expanded.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                expanded.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    expanded.push_str(rest);
+    Some(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Settings;
+
+    fn router(settings: serde_json::Value) -> Router {
+        let settings: Settings = serde_json::from_value(settings).unwrap();
+        let mut upstreams = Upstreams::new(settings.upstreams, &settings.connection_pool).unwrap();
+        Router::new(settings.servers, settings.default_server, &mut upstreams).unwrap()
+    }
+
+    /// Uri of the upstream a request is routed to
+    fn upstream(router: &Router, host: Option<&str>, path: &str) -> Option<String> {
+        let route = router.route(host, path)?;
+        Some(
+            route.pool.upstreams()[0]
+                .uri(None, path)
+                .unwrap()
+                .to_string(),
+        )
+    }
+
+    #[test]
+    fn location_precedence() {
+        let router = router(serde_json::json!({
+            "servers": [{
+                "host": "localhost",
+                "proxy_pass": "http://default",
+                "locations": [
+                    { "path": "/", "proxy_pass": "http://root" },
+                    { "path": "/static/", "proxy_pass": "http://prefix" },
+                    { "path": "/static/img/", "match": "priority_prefix", "proxy_pass": "http://priority" },
+                    { "path": "/static/index.html", "match": "exact", "proxy_pass": "http://exact" },
+                    { "path": "\\.png$", "match": "regex", "proxy_pass": "http://png" },
+                    { "path": "^/static/.*\\.(png|jpg)$", "match": "regex", "proxy_pass": "http://image" },
+                    { "path": "/api/v2/", "proxy_pass": "http://v2" },
+                    { "path": "/api/", "proxy_pass": "http://api" },
+                ],
+            }],
+        }));
+
+        for (path, expected) in [
+            // Exact match over every other location
+            ("/static/index.html", "exact"),
+            ("/static/index.html/", "prefix"),
+            // Longest prefix being a priority prefix skips the regexes
+            ("/static/img/logo.png", "priority"),
+            // Regexes in declaration order over the longest prefix
+            ("/static/logo.png", "png"),
+            ("/static/logo.jpg", "image"),
+            ("/logo.png", "png"),
+            // Longest prefix otherwise
+            ("/static/app.js", "prefix"),
+            ("/api/v2/users", "v2"),
+            ("/api/v1/users", "api"),
+            ("/other", "root"),
+        ] {
+            assert_eq!(
+                upstream(&router, Some("localhost"), path),
+                Some(format!("http://{}{}", expected, path)),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn server_proxy_pass_without_matching_location() {
+        let router = router(serde_json::json!({
+            "servers": [{
+                "host": "localhost",
+                "proxy_pass": "http://default",
+                "locations": [{ "path": "/api/", "proxy_pass": "http://api" }],
+            }, {
+                "host": "locations.localhost",
+                "locations": [{ "path": "/api/", "proxy_pass": "http://api" }],
+            }],
+            "default_server": { "proxy_pass": "http://fallback" },
+        }));
+
+        assert_eq!(
+            upstream(&router, Some("localhost"), "/web"),
+            Some("http://default/web".to_owned())
+        );
+        assert_eq!(upstream(&router, Some("locations.localhost"), "/web"), None);
+        assert_eq!(
+            upstream(&router, Some("unknown.localhost"), "/web"),
+            Some("http://fallback/web".to_owned())
+        );
+        assert_eq!(
+            upstream(&router, None, "/web"),
+            Some("http://fallback/web".to_owned())
+        );
+    }
+
+    #[test]
+    fn templated_targets_keep_their_pool() {
+        let router = router(serde_json::json!({
+            "servers": [{
+                "host": "~^(?P<branch>[a-z0-9-]+)\\.preview\\.localhost$",
+                "proxy_pass": "http://{branch}.internal:8080",
+            }],
+        }));
+        let pool = |host| router.route(Some(host), "/").unwrap().pool;
+
+        let first = pool("fix-1.preview.localhost");
+        assert_eq!(
+            first.upstreams()[0].uri(None, "/").unwrap(),
+            "http://fix-1.internal:8080/"
+        );
+        assert!(Arc::ptr_eq(&first, &pool("Fix-1.preview.localhost:443")));
+        assert!(!Arc::ptr_eq(&first, &pool("fix-2.preview.localhost")));
+    }
+
+    #[test]
+    fn template_pools_are_bounded() {
+        let mut pools = TemplatePools::default();
+        let first = pools.get_or_insert("http://0.internal".to_owned()).unwrap();
+        for i in 1..=MAX_TEMPLATE_POOLS {
+            pools
+                .get_or_insert(format!("http://{}.internal", i))
+                .unwrap();
+        }
+        assert_eq!(pools.pools.len(), MAX_TEMPLATE_POOLS);
+        let again = pools.get_or_insert("http://0.internal".to_owned()).unwrap();
+        assert!(!Arc::ptr_eq(&first, &again));
+        assert!(pools.get_or_insert("http://[".to_owned()).is_none());
+    }
+
+    #[test]
+    fn expanded_captures() {
+        let regex = Regex::new(r"^(?P<app>[a-z]+)-(?P<env>[a-z]+)\.localhost$").unwrap();
+        let captures = regex.captures("web-prod.localhost").unwrap();
+
+        for (template, expected) in [
+            ("http://{app}.{env}:8080", "http://web.prod:8080"),
+            ("http://{1}-{2}{0}", "http://web-prodweb-prod.localhost"),
+            // Unknown or out of range groups, and unclosed braces, are kept
+            ("http://{app}.{region}", "http://web.{region}"),
+            ("http://{3}.internal", "http://{3}.internal"),
+            ("http://{app", "http://{app"),
+            ("http://{}{{env}}", "http://{}{prod}"),
+            ("http://static", "http://static"),
+        ] {
+            assert_eq!(
+                expand_captures(template, &captures).as_deref(),
+                Some(expected),
+                "{}",
+                template
+            );
+        }
+
+        // Hosts choosing another upstream than the template allows
+        let regex = Regex::new(r"^(.+)\.preview\.example\.com$").unwrap();
+        for host in [
+            "evil.com/.preview.example.com",
+            "user@evil.com.preview.example.com",
+            "evil.com:80.preview.example.com",
+            "evil.com?.preview.example.com",
+            "evil.com#.preview.example.com",
+        ] {
+            let captures = regex.captures(host).unwrap();
+            assert_eq!(
+                expand_captures("http://{1}.internal:8080", &captures),
+                None,
+                "{}",
+                host
+            );
+        }
+        // Unused groups are not checked
+        let captures = regex.captures("evil/.preview.example.com").unwrap();
+        assert_eq!(
+            expand_captures("http://static", &captures).as_deref(),
+            Some("http://static")
+        );
+    }
+
+    #[test]
+    fn invalid_captures_are_unmatched() {
+        let router = router(serde_json::json!({
+            "servers": [{
+                "host": "~^(.+)\\.preview\\.example\\.com$",
+                "proxy_pass": "http://{1}.internal:8080",
+            }],
+        }));
+        assert_eq!(
+            upstream(&router, Some("fix-1.preview.example.com"), "/"),
+            Some("http://fix-1.internal:8080/".to_owned())
+        );
+        assert_eq!(
+            upstream(&router, Some("evil.com/.preview.example.com"), "/"),
+            None
+        );
+    }
+}
@@ -1,3 +1,18 @@
+use crate::settings::Http2Setting;
+use hyper::server::Builder;
+
 // pub async fn run() -> anyhow::Result<()> {
 //     Ok(())
 // }
+
+/// Applies the HTTP/2 settings to a server builder
+pub fn configure<I>(builder: Builder<I>, http2: &Http2Setting) -> Builder<I> {
+    if !http2.enabled {
+        return builder.http1_only(true);
+    }
+    builder
+        .http2_max_concurrent_streams(http2.max_concurrent_streams)
+        .http2_initial_stream_window_size(http2.initial_stream_window_size)
+        .http2_initial_connection_window_size(http2.initial_connection_window_size)
+        .http2_adaptive_window(http2.adaptive_window)
+}
@@ -1,16 +1,721 @@
 use config::{Config, File};
+use hyper::{Method, StatusCode};
+use rand::Rng;
 use serde::Deserialize;
-use std::{collections::HashMap, path::PathBuf};
+use std::{path::PathBuf, time::Duration};
 
 #[derive(Debug, Deserialize)]
 pub struct Settings {
-    servers: Vec<ServerSetting>,
+    pub servers: Vec<ServerSetting>,
+    /// Upstream pools which can be used in `proxy_pass` as `http://<name>`
+    #[serde(default)]
+    pub upstreams: Vec<UpstreamPoolSetting>,
+    /// Connection pool shared by the upstream pools without their own
+    #[serde(default)]
+    pub connection_pool: ConnectionPoolSetting,
+    #[serde(default)]
+    pub timeouts: TimeoutSetting,
+    #[serde(default)]
+    pub http2: Http2Setting,
+    #[serde(default)]
+    pub http3: Http3Setting,
+    /// TLS listener, used with `--tls`
+    #[serde(default)]
+    pub tls: TlsSetting,
+    /// Routes for requests whose host matches none of the servers
+    pub default_server: Option<RoutesSetting>,
+    #[serde(default)]
+    pub unmatched: UnmatchedSetting,
+    /// Error pages of the routes without their own
+    #[serde(default)]
+    pub error_pages: ErrorPagesSetting,
 }
 
 #[derive(Debug, Deserialize)]
-struct ServerSetting {
-    host: String,
-    proxy_pass: String,
+pub struct ServerSetting {
+    pub host: String,
+    /// Certificate sent to clients asking for the host with SNI
+    pub tls: Option<ServerTlsSetting>,
+    #[serde(flatten)]
+    pub routes: RoutesSetting,
+}
+
+/// TLS listener settings
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TlsSetting {
+    /// PEM certificate chain, leaf first, sent for the hosts without their
+    /// own certificate, with `key`
+    pub cert: Option<PathBuf>,
+    /// PEM private key, either PKCS#8, RSA (PKCS#1) or EC (SEC1)
+    pub key: Option<PathBuf>,
+    /// Obtains certificates for the servers without their own
+    pub acme: Option<AcmeSetting>,
+    /// Interval of the checks for changed certificate files, which are
+    /// reloaded along with SIGHUP, 0 to only reload on SIGHUP
+    pub watch_interval_ms: u64,
+    pub client_auth: ClientAuthSetting,
+    /// Oldest protocol version accepted, HTTP/3 always using TLS 1.3
+    pub min_version: TlsVersion,
+    /// Cipher suites by name, like `TLS13_AES_128_GCM_SHA256`, all the
+    /// supported ones when empty
+    pub cipher_suites: Vec<String>,
+    /// Stateless resumption, with tickets encrypted by a key of the server
+    pub session_tickets: bool,
+    /// Interval of the replacement of the ticket key, the tickets of the
+    /// previous key staying valid for another interval
+    pub ticket_rotation_ms: u64,
+    /// Sessions kept for resumption by id, 0 to disable
+    pub session_cache_size: usize,
+    /// Appends the session secrets in the NSS key log format, to decrypt
+    /// captured traffic with Wireshark. Only for debugging.
+    pub key_log_file: Option<PathBuf>,
+    pub ocsp_stapling: OcspSetting,
+}
+
+impl Default for TlsSetting {
+    fn default() -> Self {
+        Self {
+            cert: None,
+            key: None,
+            acme: None,
+            watch_interval_ms: 5000,
+            client_auth: ClientAuthSetting::default(),
+            min_version: TlsVersion::default(),
+            cipher_suites: vec![],
+            session_tickets: false,
+            ticket_rotation_ms: 6 * 3_600_000,
+            session_cache_size: 256,
+            key_log_file: None,
+            ocsp_stapling: OcspSetting::default(),
+        }
+    }
+}
+
+/// Responses of the OCSP responders named in the certificates, sent in the
+/// handshakes so that clients do not have to ask them
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct OcspSetting {
+    pub enabled: bool,
+    pub timeout_ms: u64,
+    /// Wait before asking again after a failure, the previous response
+    /// being sent until it expires
+    pub retry_interval_ms: u64,
+    /// Refresh interval of the responses without a next update time.
+    /// Others are refreshed halfway to their next update.
+    pub refresh_interval_ms: u64,
+}
+
+impl Default for OcspSetting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            timeout_ms: 5000,
+            retry_interval_ms: 300_000,
+            refresh_interval_ms: 3_600_000,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TlsVersion {
+    #[default]
+    Tls12,
+    Tls13,
+}
+
+/// Client certificates asked for by the TLS listener
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ClientAuthSetting {
+    pub mode: ClientAuthMode,
+    /// PEM bundle of the CAs verifying client certificates
+    pub ca_file: Option<PathBuf>,
+    pub headers: ClientCertHeaders,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientAuthMode {
+    #[default]
+    None,
+    /// Verifies the certificates sent, but also accepts clients without one
+    Optional,
+    /// Fails handshakes without a valid certificate
+    Required,
+}
+
+/// Request headers carrying the verified client certificate to upstreams,
+/// null to not send one. They are removed from all client requests, even
+/// when client certificates are not asked for, so clients cannot set them.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ClientCertHeaders {
+    /// Distinguished name, like `CN=admin, O=Example`
+    pub subject: Option<String>,
+    /// Subject alternative names, like `DNS:admin.example, email:a@example`
+    pub san: Option<String>,
+    /// SHA-256 of the DER certificate in lowercase hex
+    pub fingerprint: Option<String>,
+}
+
+impl Default for ClientCertHeaders {
+    fn default() -> Self {
+        Self {
+            subject: Some("x-client-cert-subject".to_owned()),
+            san: Some("x-client-cert-san".to_owned()),
+            fingerprint: Some("x-client-cert-fingerprint".to_owned()),
+        }
+    }
+}
+
+impl TlsSetting {
+    /// Certificate of the hosts without their own
+    pub fn default_cert(&self) -> anyhow::Result<Option<ServerTlsSetting>> {
+        match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => Ok(Some(ServerTlsSetting {
+                cert: cert.clone(),
+                key: key.clone(),
+            })),
+            (None, None) => Ok(None),
+            _ => anyhow::bail!("Both tls.cert and tls.key are needed for a certificate"),
+        }
+    }
+}
+
+/// Certificates obtained and renewed from an ACME CA for the hosts of the
+/// servers without `tls`, except wildcard and regex hosts
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AcmeSetting {
+    pub directory_url: String,
+    /// Contact URLs of the account, like `mailto:admin@example.com`
+    pub contact: Vec<String>,
+    /// Most CAs only create accounts agreeing to their terms of service
+    pub terms_of_service_agreed: bool,
+    pub challenge: AcmeChallenge,
+    /// Port of the plain listener answering HTTP-01 challenges
+    pub http_port: u16,
+    /// Directory keeping the account key and the certificates
+    pub storage_dir: PathBuf,
+    /// PEM bundle of the CAs trusted for the directory instead of the
+    /// Mozilla roots, for test CAs
+    pub ca_file: Option<PathBuf>,
+    /// Certificates expiring within this are renewed
+    pub renew_before_ms: u64,
+    pub check_interval_ms: u64,
+    /// Wait before trying again after failing to obtain a certificate
+    pub retry_interval_ms: u64,
+}
+
+impl Default for AcmeSetting {
+    fn default() -> Self {
+        Self {
+            directory_url: "https://acme-v02.api.letsencrypt.org/directory".to_owned(),
+            contact: vec![],
+            terms_of_service_agreed: false,
+            challenge: AcmeChallenge::default(),
+            http_port: 80,
+            storage_dir: PathBuf::from("acme"),
+            ca_file: None,
+            renew_before_ms: 30 * 24 * 3_600_000,
+            check_interval_ms: 12 * 3_600_000,
+            retry_interval_ms: 600_000,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AcmeChallenge {
+    /// Token served over http on `http_port`
+    #[default]
+    Http01,
+    /// Certificate served to the `acme-tls/1` ALPN protocol on the TLS
+    /// listener
+    TlsAlpn01,
+}
+
+/// PEM certificate chain, leaf first, and its private key
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServerTlsSetting {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RoutesSetting {
+    /// Backend used when none of the locations match
+    pub proxy_pass: Option<String>,
+    #[serde(default)]
+    pub locations: Vec<LocationSetting>,
+    #[serde(flatten)]
+    pub options: RouteOptions,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LocationSetting {
+    pub path: String,
+    #[serde(default, rename = "match")]
+    pub matches: PathMatch,
+    pub proxy_pass: String,
+    #[serde(flatten)]
+    pub options: RouteOptions,
+}
+
+/// Options set on a server, or on a location to override the server's
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RouteOptions {
+    pub retry: Option<RetrySetting>,
+    /// Read the whole request body before sending it, for upstreams which
+    /// do not support chunked requests
+    pub buffer_request_body: Option<BufferBodySetting>,
+    /// Overrides the global error pages, per format
+    pub error_pages: Option<ErrorPagesSetting>,
+    /// Answers 403 to clients without a verified certificate
+    pub require_client_cert: Option<bool>,
+}
+
+impl RouteOptions {
+    /// Fills the options not set here from `parent`
+    pub fn inherit(self, parent: &RouteOptions) -> RouteOptions {
+        RouteOptions {
+            retry: self.retry.or_else(|| parent.retry.clone()),
+            buffer_request_body: self
+                .buffer_request_body
+                .or_else(|| parent.buffer_request_body.clone()),
+            error_pages: self.error_pages.or_else(|| parent.error_pages.clone()),
+            require_client_cert: self.require_client_cert.or(parent.require_client_cert),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RetrySetting {
+    /// Attempts including the first one
+    pub max_attempts: u32,
+    pub retry_on: Vec<RetryOn>,
+    /// Also retry methods which are not idempotent, like POST
+    pub non_idempotent: bool,
+    pub per_try_timeout_ms: Option<u64>,
+    /// Backoff before the nth retry is random up to
+    /// `min(backoff_base_ms * 2^(n - 1), backoff_max_ms)`
+    pub backoff_base_ms: u64,
+    pub backoff_max_ms: u64,
+    /// Largest body held in memory for retries, requests with larger bodies
+    /// are streamed and not retried unless `buffer_request_body` is set
+    pub max_body_bytes: usize,
+}
+
+impl Default for RetrySetting {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            retry_on: vec![
+                RetryOn::ConnectFailure,
+                RetryOn::BadGateway,
+                RetryOn::ServiceUnavailable,
+                RetryOn::GatewayTimeout,
+            ],
+            non_idempotent: false,
+            per_try_timeout_ms: None,
+            backoff_base_ms: 25,
+            backoff_max_ms: 250,
+            max_body_bytes: 64 * 1024,
+        }
+    }
+}
+
+impl RetrySetting {
+    /// Whether a request with this method may be retried
+    pub fn allows(&self, method: &Method) -> bool {
+        self.non_idempotent
+            || matches!(
+                *method,
+                Method::GET
+                    | Method::HEAD
+                    | Method::OPTIONS
+                    | Method::TRACE
+                    | Method::PUT
+                    | Method::DELETE
+            )
+    }
+
+    pub fn per_try_timeout(&self) -> Option<Duration> {
+        self.per_try_timeout_ms.map(Duration::from_millis)
+    }
+
+    /// Jittered backoff to wait before the `retry`th retry
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let max = self
+            .backoff_base_ms
+            .saturating_mul(1 << (retry - 1).min(32))
+            .min(self.backoff_max_ms);
+        Duration::from_millis(rand::thread_rng().gen_range(0..=max))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BufferBodySetting {
+    /// Larger requests are rejected with 413 Payload Too Large
+    pub max_bytes: u64,
+    /// Larger bodies are written to a temporary file in `spill_dir`
+    pub memory_bytes: usize,
+    pub spill_dir: PathBuf,
+}
+
+impl Default for BufferBodySetting {
+    fn default() -> Self {
+        Self {
+            max_bytes: 100 * 1024 * 1024,
+            memory_bytes: 1024 * 1024,
+            spill_dir: std::env::temp_dir(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOn {
+    ConnectFailure,
+    /// 502 response
+    BadGateway,
+    /// 503 response
+    ServiceUnavailable,
+    /// 504 response or per try timeout
+    GatewayTimeout,
+}
+
+impl RetryOn {
+    pub fn from_status(status: StatusCode) -> Option<RetryOn> {
+        match status {
+            StatusCode::BAD_GATEWAY => Some(RetryOn::BadGateway),
+            StatusCode::SERVICE_UNAVAILABLE => Some(RetryOn::ServiceUnavailable),
+            StatusCode::GATEWAY_TIMEOUT => Some(RetryOn::GatewayTimeout),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PathMatch {
+    /// Path must be equal to the location path
+    Exact,
+    /// Path must start with the location path
+    #[default]
+    Prefix,
+    /// Same as `Prefix`, but regex locations are not checked if this is the
+    /// longest matching prefix (nginx `^~`)
+    PriorityPrefix,
+    /// Location path is a regex matched against the path
+    Regex,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpstreamPoolSetting {
+    pub name: String,
+    #[serde(default)]
+    pub policy: Policy,
+    /// Request property hashed by the `consistent_hash` policy
+    #[serde(default)]
+    pub hash_key: HashKey,
+    pub servers: Vec<UpstreamSetting>,
+    #[serde(default)]
+    pub protocol: UpstreamProtocol,
+    pub health_check: Option<HealthCheckSetting>,
+    pub outlier_detection: Option<OutlierDetectionSetting>,
+    pub circuit_breaker: Option<CircuitBreakerSetting>,
+    /// Gives the pool its own connections instead of the shared ones
+    pub connection_pool: Option<ConnectionPoolSetting>,
+    pub tls: Option<UpstreamTlsSetting>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpstreamSetting {
+    pub address: String,
+    #[serde(default = "UpstreamSetting::default_weight")]
+    pub weight: u32,
+}
+
+impl UpstreamSetting {
+    fn default_weight() -> u32 {
+        1
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ConnectionPoolSetting {
+    /// Idle connections kept open to each upstream
+    pub max_idle_per_host: usize,
+    pub idle_timeout_ms: u64,
+    pub connect_timeout_ms: u64,
+    /// TCP keepalive interval, disabled if not set
+    pub tcp_keepalive_ms: Option<u64>,
+    pub tcp_nodelay: bool,
+}
+
+impl Default for ConnectionPoolSetting {
+    fn default() -> Self {
+        Self {
+            max_idle_per_host: 32,
+            idle_timeout_ms: 90_000,
+            connect_timeout_ms: 5000,
+            tcp_keepalive_ms: None,
+            tcp_nodelay: true,
+        }
+    }
+}
+
+/// HTTP/2 served to clients negotiating it with ALPN on the TLS listener
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Http2Setting {
+    pub enabled: bool,
+    /// Streams a client may open at once on a connection
+    pub max_concurrent_streams: u32,
+    pub initial_stream_window_size: u32,
+    pub initial_connection_window_size: u32,
+    /// Sizes the windows from the measured bandwidth-delay product instead
+    /// of the initial sizes
+    pub adaptive_window: bool,
+}
+
+impl Default for Http2Setting {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_concurrent_streams: 256,
+            initial_stream_window_size: 1024 * 1024,
+            initial_connection_window_size: 1024 * 1024,
+            adaptive_window: false,
+        }
+    }
+}
+
+/// HTTP/3 served over QUIC next to the TLS listener, with the same
+/// certificate and routes
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Http3Setting {
+    pub enabled: bool,
+    /// UDP port, the TLS listener's port if not set
+    pub port: Option<u16>,
+    /// How long clients may remember the `Alt-Svc` header advertising
+    /// HTTP/3 on HTTP/1.1 and HTTP/2 responses
+    pub alt_svc_max_age_ms: u64,
+}
+
+impl Default for Http3Setting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: None,
+            alt_svc_max_age_ms: 86_400_000,
+        }
+    }
+}
+
+/// TLS to the `https://` upstreams of a pool
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct UpstreamTlsSetting {
+    /// PEM bundle of the CAs trusted instead of the Mozilla roots
+    pub ca_file: Option<PathBuf>,
+    /// Name sent as SNI and verified instead of the upstream host, needed
+    /// for upstreams addressed by IP
+    pub server_name: Option<String>,
+    /// Accepts any certificate, only meant for development
+    pub insecure_skip_verify: bool,
+    /// SHA-256 fingerprints in hex of the only certificates accepted
+    pub pinned_fingerprints: Vec<String>,
+    /// PEM certificate chain presented to upstreams asking for a client
+    /// certificate, with `key_file`
+    pub cert_file: Option<PathBuf>,
+    pub key_file: Option<PathBuf>,
+}
+
+/// Timeouts of client connections and upstream requests. The upstream
+/// connect timeout is `connection_pool.connect_timeout_ms`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TimeoutSetting {
+    /// Time to receive a request head once the client started sending it,
+    /// answered with 408
+    pub header_read_ms: u64,
+    /// Longest wait for the next chunk of a request body, answered with 408
+    pub client_body_ms: u64,
+    /// Time for an upstream to send the response headers once it was sent
+    /// the whole request body, answered with 504
+    pub upstream_response_ms: u64,
+    /// Time from a request head to its response headers, answered with 504.
+    /// It covers reading the request body, every upstream attempt and the
+    /// retry backoffs, but not the streaming of the response body.
+    pub response_headers_ms: Option<u64>,
+    /// Time from a request head to the end of its response body. Answered
+    /// with 504 if the response headers are not there by then, else the
+    /// response body is cut off.
+    pub total_ms: Option<u64>,
+    /// Idle keep-alive connections are closed after this
+    pub keep_alive_idle_ms: u64,
+}
+
+impl Default for TimeoutSetting {
+    fn default() -> Self {
+        Self {
+            header_read_ms: 30_000,
+            client_body_ms: 60_000,
+            upstream_response_ms: 60_000,
+            response_headers_ms: None,
+            total_ms: None,
+            keep_alive_idle_ms: 75_000,
+        }
+    }
+}
+
+/// Periodic HTTP probe sent to every upstream of a pool
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HealthCheckSetting {
+    pub path: String,
+    /// Inclusive range of response statuses considered healthy
+    pub status_range: (u16, u16),
+    pub interval_ms: u64,
+    pub timeout_ms: u64,
+    /// Consecutive successful probes needed to mark an upstream healthy
+    pub rise: u32,
+    /// Consecutive failed probes needed to mark an upstream unhealthy
+    pub fall: u32,
+}
+
+impl Default for HealthCheckSetting {
+    fn default() -> Self {
+        Self {
+            path: "/".to_owned(),
+            status_range: (200, 399),
+            interval_ms: 5000,
+            timeout_ms: 2000,
+            rise: 2,
+            fall: 3,
+        }
+    }
+}
+
+/// Ejects an upstream after consecutive connect errors or 5xx responses.
+/// Once the ejection ends a single request tries the upstream, which is
+/// ejected again if it fails.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct OutlierDetectionSetting {
+    pub consecutive_errors: u32,
+    /// Ejection time, doubled for every consecutive ejection
+    pub base_ejection_ms: u64,
+    pub max_ejection_ms: u64,
+}
+
+impl Default for OutlierDetectionSetting {
+    fn default() -> Self {
+        Self {
+            consecutive_errors: 5,
+            base_ejection_ms: 30_000,
+            max_ejection_ms: 300_000,
+        }
+    }
+}
+
+/// Limits on the requests sent to a single upstream
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CircuitBreakerSetting {
+    /// Requests in flight at once, a request being in flight until its
+    /// response body has been sent
+    pub max_requests: usize,
+    /// Requests waiting for one of the in flight requests to finish, any
+    /// more are rejected
+    pub max_pending: usize,
+    /// Longest wait of a pending request, which is then rejected
+    pub pending_timeout_ms: u64,
+}
+
+impl Default for CircuitBreakerSetting {
+    fn default() -> Self {
+        Self {
+            max_requests: 1024,
+            max_pending: 1024,
+            pending_timeout_ms: 5_000,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Policy {
+    #[default]
+    RoundRobin,
+    WeightedRoundRobin,
+    LeastConnections,
+    RandomTwoChoices,
+    ConsistentHash,
+}
+
+/// HTTP version spoken to the upstreams of a pool, whatever the client uses
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamProtocol {
+    #[default]
+    Http1,
+    /// HTTP/2 negotiated with ALPN, for `https://` upstreams
+    H2,
+    /// HTTP/2 with prior knowledge, for `http://` and unix socket upstreams
+    H2c,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HashKey {
+    #[default]
+    ClientIp,
+    Header(String),
+    Cookie(String),
+}
+
+/// Response sent when no server or location matches the request
+#[derive(Debug, Deserialize)]
+pub struct UnmatchedSetting {
+    /// Either 404 (Not Found) or 421 (Misdirected Request)
+    #[serde(default = "UnmatchedSetting::default_status")]
+    pub status: u16,
+    /// File whose contents are sent as the response body
+    pub page: Option<PathBuf>,
+}
+
+impl UnmatchedSetting {
+    fn default_status() -> u16 {
+        404
+    }
+}
+
+impl Default for UnmatchedSetting {
+    fn default() -> Self {
+        Self {
+            status: Self::default_status(),
+            page: None,
+        }
+    }
+}
+
+/// Templates of the 502, 503 and 504 responses, in which `{status}`,
+/// `{reason}` and `{request_id}` are replaced
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ErrorPagesSetting {
+    pub html: Option<PathBuf>,
+    /// Sent when the client's `Accept` header prefers JSON over HTML
+    pub json: Option<PathBuf>,
 }
 
 impl Settings {
@@ -21,11 +726,97 @@ impl Settings {
             .expect("Could not read config file");
         settings.try_into().expect("Could not parse settings")
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retried_methods() {
+        let mut retry = RetrySetting::default();
+        for method in [
+            Method::GET,
+            Method::HEAD,
+            Method::OPTIONS,
+            Method::TRACE,
+            Method::PUT,
+            Method::DELETE,
+        ] {
+            assert!(retry.allows(&method), "{}", method);
+        }
+        for method in [Method::POST, Method::PATCH, Method::CONNECT] {
+            assert!(!retry.allows(&method), "{}", method);
+        }
+
+        retry.non_idempotent = true;
+        assert!(retry.allows(&Method::POST));
+        assert!(retry.allows(&Method::PATCH));
+    }
+
+    #[test]
+    fn retried_statuses() {
+        assert_eq!(
+            RetryOn::from_status(StatusCode::BAD_GATEWAY),
+            Some(RetryOn::BadGateway)
+        );
+        assert_eq!(
+            RetryOn::from_status(StatusCode::SERVICE_UNAVAILABLE),
+            Some(RetryOn::ServiceUnavailable)
+        );
+        assert_eq!(
+            RetryOn::from_status(StatusCode::GATEWAY_TIMEOUT),
+            Some(RetryOn::GatewayTimeout)
+        );
+        for status in [
+            StatusCode::OK,
+            StatusCode::NOT_FOUND,
+            StatusCode::TOO_MANY_REQUESTS,
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ] {
+            assert_eq!(RetryOn::from_status(status), None, "{}", status);
+        }
+    }
+
+    #[test]
+    fn backoff_bounds() {
+        let retry = RetrySetting {
+            backoff_base_ms: 25,
+            backoff_max_ms: 250,
+            ..Default::default()
+        };
+        for (n, max) in [(1, 25), (2, 50), (3, 100), (4, 200), (5, 250), (64, 250)] {
+            let backoffs: Vec<_> = (0..500)
+                .map(|_| retry.backoff(n).as_millis() as u64)
+                .collect();
+            assert!(backoffs.iter().all(|&backoff| backoff <= max), "{}", n);
+            // Jittered over the whole range
+            assert!(backoffs.iter().any(|&backoff| backoff < max / 4), "{}", n);
+            assert!(
+                backoffs.iter().any(|&backoff| backoff > max * 3 / 4),
+                "{}",
+                n
+            );
+        }
+
+        let no_backoff = RetrySetting {
+            backoff_base_ms: 0,
+            ..Default::default()
+        };
+        assert_eq!(no_backoff.backoff(3), Duration::ZERO);
+        let no_max = RetrySetting {
+            backoff_base_ms: u64::MAX,
+            backoff_max_ms: u64::MAX,
+            ..Default::default()
+        };
+        no_max.backoff(40);
+    }
 
-    pub fn servers(self) -> HashMap<String, String> {
-        self.servers
-            .into_iter()
-            .map(|setting| (setting.host, setting.proxy_pass))
-            .collect()
+    #[test]
+    fn per_try_timeout() {
+        let mut retry = RetrySetting::default();
+        assert_eq!(retry.per_try_timeout(), None);
+        retry.per_try_timeout_ms = Some(1500);
+        assert_eq!(retry.per_try_timeout(), Some(Duration::from_millis(1500)));
     }
 }
@@ -0,0 +1,171 @@
+use ring::{
+    aead::{self, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+use std::{
+    convert::TryFrom,
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio_rustls::rustls::ProducesTickets;
+
+/// Session tickets encrypted with a random key replaced every `rotation`.
+/// Tickets of the previous key are still accepted, so a ticket is valid for
+/// at least one interval. Used by both the TCP and the QUIC listeners.
+pub struct Ticketer {
+    rotation: Duration,
+    rng: SystemRandom,
+    keys: Mutex<Keys>,
+}
+
+struct Keys {
+    current: LessSafeKey,
+    previous: Option<LessSafeKey>,
+    rotated: Instant,
+}
+
+impl Ticketer {
+    pub fn new(rotation: Duration) -> anyhow::Result<Self> {
+        let rng = SystemRandom::new();
+        let keys = Keys {
+            current: new_key(&rng)?,
+            previous: None,
+            rotated: Instant::now(),
+        };
+        Ok(Self {
+            rotation,
+            rng,
+            keys: Mutex::new(keys),
+        })
+    }
+
+    /// Current and previous keys, rotated if the interval elapsed
+    fn keys(&self) -> Option<std::sync::MutexGuard<'_, Keys>> {
+        let mut keys = self.keys.lock().unwrap();
+        let elapsed = keys.rotated.elapsed();
+        if elapsed >= self.rotation {
+            let current = new_key(&self.rng).ok()?;
+            let previous = std::mem::replace(&mut keys.current, current);
+            // Both keys expired when no ticket was handled for a while
+            keys.previous = Some(previous).filter(|_| elapsed < self.rotation * 2);
+            keys.rotated = Instant::now();
+        }
+        Some(keys)
+    }
+}
+
+fn new_key(rng: &SystemRandom) -> anyhow::Result<LessSafeKey> {
+    let mut key = [0; 32];
+    rng.fill(&mut key)
+        .map_err(|_| anyhow::anyhow!("Could not generate session ticket key"))?;
+    let key = UnboundKey::new(&CHACHA20_POLY1305, &key)
+        .map_err(|_| anyhow::anyhow!("Invalid session ticket key"))?;
+    Ok(LessSafeKey::new(key))
+}
+
+impl ProducesTickets for Ticketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn get_lifetime(&self) -> u32 {
+        u32::try_from(self.rotation.as_secs()).unwrap_or(u32::MAX)
+    }
+
+    /// Random nonce followed by the sealed session
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let mut nonce = [0; NONCE_LEN];
+        self.rng.fill(&mut nonce).ok()?;
+        let mut ticket = plain.to_vec();
+        self.keys()?
+            .current
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                aead::Aad::empty(),
+                &mut ticket,
+            )
+            .ok()?;
+        let mut out = nonce.to_vec();
+        out.append(&mut ticket);
+        Some(out)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        if cipher.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, sealed) = cipher.split_at(NONCE_LEN);
+        let keys = self.keys()?;
+        for key in std::iter::once(&keys.current).chain(&keys.previous) {
+            let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+            let mut plain = sealed.to_vec();
+            if let Ok(plain) = key.open_in_place(nonce, aead::Aad::empty(), &mut plain) {
+                return Some(plain.to_vec());
+            }
+        }
+        None
+    }
+}
+
+impl fmt::Debug for Ticketer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Ticketer")
+            .field("rotation", &self.rotation)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Same tickets for the rustls version of the QUIC listener
+impl rustls_quic::server::ProducesTickets for Ticketer {
+    fn enabled(&self) -> bool {
+        ProducesTickets::enabled(self)
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.get_lifetime()
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        ProducesTickets::encrypt(self, plain)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        ProducesTickets::decrypt(self, cipher)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls_quic::server::ProducesTickets as QuicProducesTickets;
+
+    #[test]
+    fn quic_tickets() {
+        let ticketer = Ticketer::new(Duration::from_secs(3600)).unwrap();
+        assert!(QuicProducesTickets::enabled(&ticketer));
+        assert_eq!(QuicProducesTickets::lifetime(&ticketer), 3600);
+
+        let ticket = QuicProducesTickets::encrypt(&ticketer, b"session").unwrap();
+        assert_ne!(&ticket[NONCE_LEN..], b"session");
+        assert_eq!(
+            QuicProducesTickets::decrypt(&ticketer, &ticket).unwrap(),
+            b"session"
+        );
+        assert_eq!(QuicProducesTickets::decrypt(&ticketer, &ticket[1..]), None);
+
+        let other = Ticketer::new(Duration::from_secs(3600)).unwrap();
+        assert_eq!(QuicProducesTickets::decrypt(&other, &ticket), None);
+    }
+
+    #[test]
+    fn tickets_of_previous_key() {
+        let ticketer = Ticketer::new(Duration::from_millis(100)).unwrap();
+        let ticket = ProducesTickets::encrypt(&ticketer, b"session").unwrap();
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(ProducesTickets::decrypt(&ticketer, &ticket).is_some());
+        std::thread::sleep(Duration::from_millis(150));
+        let _ = ProducesTickets::encrypt(&ticketer, b"other");
+        assert_eq!(ProducesTickets::decrypt(&ticketer, &ticket), None);
+    }
+}
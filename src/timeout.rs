@@ -0,0 +1,322 @@
+use crate::settings::TimeoutSetting;
+use futures::{ready, stream, FutureExt};
+use hyper::{body::HttpBody, server::accept::Accept, Body};
+use std::{
+    error::Error,
+    fmt,
+    future::Future,
+    io::{self, IoSlice},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::oneshot,
+    time::{Instant, Sleep},
+};
+
+/// Start of the preface of HTTP/2 connections
+const HTTP2_PREFACE: &[u8] = b"PRI * HTTP/2.0";
+
+const REQUEST_TIMEOUT_RESPONSE: &[u8] =
+    b"HTTP/1.1 408 Request Timeout\r\nconnection: close\r\ncontent-length: 0\r\n\r\n";
+
+/// Requests of a client connection, shared between the connection and its
+/// service so the connection knows when it is waiting for a request
+#[derive(Default)]
+pub struct ConnActivity {
+    in_flight: AtomicUsize,
+    dispatched: AtomicUsize,
+}
+
+impl ConnActivity {
+    /// Marks a request as being handled until the returned guard is dropped
+    pub fn start_request(self: &Arc<Self>) -> RequestGuard {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        self.dispatched.fetch_add(1, Ordering::Relaxed);
+        RequestGuard {
+            activity: self.clone(),
+        }
+    }
+}
+
+pub struct RequestGuard {
+    activity: Arc<ConnActivity>,
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.activity.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Client connection which answers 408 if a request head is not received
+/// within the header read timeout, and is closed after being idle for the
+/// keep-alive timeout. HTTP/2 connections only get the idle timeout as their
+/// frames cannot be told apart from request heads here.
+pub struct TimeoutStream<S> {
+    inner: S,
+    activity: Arc<ConnActivity>,
+    header_read: Duration,
+    keep_alive_idle: Duration,
+    timer: Pin<Box<Sleep>>,
+    last_active: Instant,
+    /// When the first bytes of a request head were read, and the number of
+    /// requests dispatched at that time
+    partial_head: Option<(Instant, usize)>,
+    /// Whether the connection is HTTP/2, known after the first read
+    http2: Option<bool>,
+}
+
+impl<S> TimeoutStream<S> {
+    fn new(inner: S, header_read: Duration, keep_alive_idle: Duration) -> Self {
+        let now = Instant::now();
+        Self {
+            inner,
+            activity: Arc::default(),
+            header_read,
+            keep_alive_idle,
+            timer: Box::pin(tokio::time::sleep_until(now + keep_alive_idle)),
+            last_active: now,
+            partial_head: None,
+            http2: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn activity(&self) -> Arc<ConnActivity> {
+        self.activity.clone()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> TimeoutStream<S> {
+    fn on_read(&mut self, data: &[u8]) {
+        self.last_active = Instant::now();
+        let http2 = *self
+            .http2
+            .get_or_insert_with(|| data.starts_with(HTTP2_PREFACE));
+        if !http2
+            && self.partial_head.is_none()
+            && self.activity.in_flight.load(Ordering::Relaxed) == 0
+        {
+            let dispatched = self.activity.dispatched.load(Ordering::Relaxed);
+            self.partial_head = Some((self.last_active, dispatched));
+        }
+    }
+
+    /// Returns `Ready` once the connection should be closed
+    fn poll_timeout(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.activity.in_flight.load(Ordering::Relaxed) > 0 {
+            return Poll::Pending;
+        }
+        if let Some((_, dispatched)) = self.partial_head {
+            if dispatched != self.activity.dispatched.load(Ordering::Relaxed) {
+                self.partial_head = None;
+            }
+        }
+
+        let deadline = match self.partial_head {
+            Some((started, _)) => started + self.header_read,
+            None => self.last_active + self.keep_alive_idle,
+        };
+        if self.timer.deadline() != deadline {
+            self.timer.as_mut().reset(deadline);
+        }
+        ready!(self.timer.as_mut().poll(cx));
+
+        if self.partial_head.take().is_some() {
+            // Best effort, the connection is closed right after
+            let _ = Pin::new(&mut self.inner).poll_write(cx, REQUEST_TIMEOUT_RESPONSE);
+            let _ = Pin::new(&mut self.inner).poll_flush(cx);
+        }
+        Poll::Ready(())
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for TimeoutStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let pin = self.get_mut();
+        let filled = buf.filled().len();
+        match Pin::new(&mut pin.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {
+                if buf.filled().len() > filled {
+                    pin.on_read(&buf.filled()[filled..]);
+                }
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            // Reading nothing tells hyper the client closed the connection
+            Poll::Pending => pin.poll_timeout(cx).map(Ok),
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TimeoutStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let pin = self.get_mut();
+        let result = ready!(Pin::new(&mut pin.inner).poll_write(cx, buf));
+        pin.last_active = Instant::now();
+        Poll::Ready(result)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let pin = self.get_mut();
+        let result = ready!(Pin::new(&mut pin.inner).poll_write_vectored(cx, bufs));
+        pin.last_active = Instant::now();
+        Poll::Ready(result)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+pub struct TimeoutIncoming<A> {
+    inner: A,
+    header_read: Duration,
+    keep_alive_idle: Duration,
+}
+
+impl<A> TimeoutIncoming<A> {
+    pub fn new(inner: A, setting: &TimeoutSetting) -> Self {
+        Self {
+            inner,
+            header_read: Duration::from_millis(setting.header_read_ms),
+            keep_alive_idle: Duration::from_millis(setting.keep_alive_idle_ms),
+        }
+    }
+}
+
+impl<A: Accept + Unpin> Accept for TimeoutIncoming<A> {
+    type Conn = TimeoutStream<A::Conn>;
+    type Error = A::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let pin = self.get_mut();
+        let conn = ready!(Pin::new(&mut pin.inner).poll_accept(cx));
+        Poll::Ready(conn.map(|conn| {
+            conn.map(|conn| TimeoutStream::new(conn, pin.header_read, pin.keep_alive_idle))
+        }))
+    }
+}
+
+/// Error of a request body whose client sent nothing for too long
+#[derive(Debug)]
+pub struct ClientBodyTimeout;
+
+impl fmt::Display for ClientBodyTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Timed out reading request body")
+    }
+}
+
+impl Error for ClientBodyTimeout {}
+
+/// Error of a response body still being sent when its request timed out
+#[derive(Debug)]
+pub struct RequestTimeout;
+
+impl fmt::Display for RequestTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Timed out sending response body")
+    }
+}
+
+impl Error for RequestTimeout {}
+
+/// Fails the body with `ClientBodyTimeout` if the client does not send the
+/// next chunk within `timeout`
+pub fn body(body: Body, timeout: Duration) -> Body {
+    if body.is_end_stream() {
+        return body;
+    }
+    Body::wrap_stream(stream::try_unfold(body, move |mut body| async move {
+        match tokio::time::timeout(timeout, body.data()).await {
+            Ok(Some(chunk)) => Ok(Some((chunk?, body))),
+            Ok(None) => Ok(None),
+            Err(_) => Err(Box::new(ClientBodyTimeout) as Box<dyn Error + Send + Sync>),
+        }
+    }))
+}
+
+/// Body with a future completing once it has been read to the end, or
+/// dropped
+pub fn body_sent(body: Body) -> (Body, impl Future<Output = ()>) {
+    let (sender, receiver) = oneshot::channel::<()>();
+    let body = if body.is_end_stream() {
+        body
+    } else {
+        // The sender is dropped with the stream state
+        Body::wrap_stream(stream::unfold(Some((body, sender)), |state| async {
+            let (mut body, sender) = state?;
+            let chunk = body.data().await?;
+            Some((chunk, Some((body, sender))))
+        }))
+    };
+    (body, receiver.map(drop))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn empty_body_is_sent() {
+        let (_body, sent) = body_sent(Body::empty());
+        assert!(sent.now_or_never().is_some());
+    }
+
+    #[tokio::test]
+    async fn body_is_sent_at_its_end() {
+        let (mut sender, body) = Body::channel();
+        let (mut body, sent) = body_sent(body);
+        futures::pin_mut!(sent);
+
+        sender.send_data("chunk".into()).await.unwrap();
+        assert_eq!(body.data().await.unwrap().unwrap(), "chunk");
+        assert!(sent.as_mut().now_or_never().is_none());
+
+        drop(sender);
+        assert!(body.data().await.is_none());
+        assert!(sent.now_or_never().is_some());
+    }
+
+    #[tokio::test]
+    async fn dropped_body_is_sent() {
+        let (_sender, body) = Body::channel();
+        let (body, sent) = body_sent(body);
+        drop(body);
+        assert!(sent.now_or_never().is_some());
+    }
+}
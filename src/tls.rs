@@ -2,21 +2,42 @@
 // LICENSE: MIT
 // Copyright (c) 2018-2020 Sean McArthur
 
+use crate::{
+    acme::{self, ACME_TLS_ALPN},
+    certs::Certificates,
+    client_cert::ClientCert,
+    host::normalize_host,
+    ocsp::Stapler,
+    pem::load_certs,
+    settings::{ClientAuthMode, ClientAuthSetting, TlsSetting, TlsVersion},
+    ticketer::Ticketer,
+    utils::hex,
+};
+use anyhow::Context as _;
 use futures::ready;
 use hyper::server::{
     accept::Accept,
     conn::{AddrIncoming, AddrStream},
 };
 use std::{
+    fs::File,
     future::Future,
-    io,
+    io::{self, Write},
     net::SocketAddr,
+    path::Path,
     pin::Pin,
-    sync::Arc,
+    sync::{Arc, Mutex, OnceLock, RwLock},
     task::{Context, Poll},
+    time::Duration,
 };
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
-use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::{
+    sign::CertifiedKey, AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient,
+    Certificate, ClientCertVerifier, ClientHello, KeyLog, NoClientAuth, NoServerSessionStorage,
+    ProtocolVersion, ResolvesServerCert, RootCertStore, ServerConfig, ServerSessionMemoryCache,
+    Session, ALL_CIPHERSUITES,
+};
+use tracing::warn;
 
 enum State {
     Handshaking(tokio_rustls::Accept<AddrStream>),
@@ -26,6 +47,7 @@ enum State {
 pub struct TlsStream {
     state: State,
     remote_addr: SocketAddr,
+    client_cert: Arc<OnceLock<Arc<ClientCert>>>,
 }
 
 impl TlsStream {
@@ -35,12 +57,26 @@ impl TlsStream {
         TlsStream {
             state: State::Handshaking(accept),
             remote_addr,
+            client_cert: Arc::default(),
         }
     }
 
     pub fn remote_addr(&self) -> SocketAddr {
         self.remote_addr
     }
+
+    /// Verified certificate of the client, set once the handshake completes
+    pub fn client_cert(&self) -> Arc<OnceLock<Arc<ClientCert>>> {
+        self.client_cert.clone()
+    }
+
+    fn streaming(&mut self, stream: tokio_rustls::server::TlsStream<AddrStream>) {
+        let certs = stream.get_ref().1.get_peer_certificates();
+        if let Some(cert) = certs.as_ref().and_then(|certs| certs.first()) {
+            let _ = self.client_cert.set(Arc::new(ClientCert::new(&cert.0)));
+        }
+        self.state = State::Streaming(stream);
+    }
 }
 
 impl AsyncRead for TlsStream {
@@ -54,7 +90,7 @@ impl AsyncRead for TlsStream {
             State::Handshaking(ref mut accept) => match ready!(Pin::new(accept).poll(cx)) {
                 Ok(mut stream) => {
                     let result = Pin::new(&mut stream).poll_read(cx, buf);
-                    pin.state = State::Streaming(stream);
+                    pin.streaming(stream);
                     result
                 }
                 Err(err) => Poll::Ready(Err(err)),
@@ -75,7 +111,7 @@ impl AsyncWrite for TlsStream {
             State::Handshaking(ref mut accept) => match ready!(Pin::new(accept).poll(cx)) {
                 Ok(mut stream) => {
                     let result = Pin::new(&mut stream).poll_write(cx, buf);
-                    pin.state = State::Streaming(stream);
+                    pin.streaming(stream);
                     result
                 }
                 Err(err) => Poll::Ready(Err(err)),
@@ -99,17 +135,191 @@ impl AsyncWrite for TlsStream {
     }
 }
 
+/// Picks the certificate of a handshake, from ACME for the hosts it manages
+/// and from the configured ones otherwise, with its OCSP response if any
+pub struct CertResolver {
+    certificates: Certificates<CertifiedKey>,
+    acme: Option<Arc<acme::Store>>,
+    stapler: Option<Arc<Stapler>>,
+}
+
+impl CertResolver {
+    pub fn new(
+        certificates: Certificates<CertifiedKey>,
+        acme: Option<Arc<acme::Store>>,
+        stapler: Option<Arc<Stapler>>,
+    ) -> Self {
+        Self {
+            certificates,
+            acme,
+            stapler,
+        }
+    }
+
+    fn staple(&self, mut cert: CertifiedKey) -> CertifiedKey {
+        if let Some(stapler) = &self.stapler {
+            cert.ocsp = stapler.get(&cert.cert);
+        }
+        cert
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
+        let server_name = client_hello.server_name().map(<&str>::from);
+        if let (Some(acme), Some(server_name)) = (&self.acme, server_name) {
+            let domain = normalize_host(server_name);
+            // TLS-ALPN-01 validation, which must only get the challenge
+            let alpn = client_hello.alpn().unwrap_or_default();
+            if alpn.contains(&ACME_TLS_ALPN) {
+                return acme.challenge(&domain);
+            }
+            if let Some(cert) = acme.get(&domain) {
+                return Some(self.staple(cert));
+            }
+        }
+        let cert = self.certificates.get(server_name).cloned()?;
+        Some(self.staple(cert))
+    }
+}
+
+/// Encrypts the session tickets when they are enabled
+pub fn ticketer(setting: &TlsSetting) -> anyhow::Result<Option<Arc<Ticketer>>> {
+    if !setting.session_tickets {
+        return Ok(None);
+    }
+    if setting.ticket_rotation_ms == 0 {
+        anyhow::bail!("tls.ticket_rotation_ms must not be 0");
+    }
+    let rotation = Duration::from_millis(setting.ticket_rotation_ms);
+    Ok(Some(Arc::new(Ticketer::new(rotation)?)))
+}
+
+/// Applies the protocol version, cipher suite and session settings
+pub fn configure(config: &mut ServerConfig, setting: &TlsSetting) -> anyhow::Result<()> {
+    config.versions = match setting.min_version {
+        TlsVersion::Tls12 => vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2],
+        TlsVersion::Tls13 => vec![ProtocolVersion::TLSv1_3],
+    };
+    if !setting.cipher_suites.is_empty() {
+        config.ciphersuites = setting
+            .cipher_suites
+            .iter()
+            .map(|name| {
+                ALL_CIPHERSUITES
+                    .iter()
+                    .find(|suite| format!("{:?}", suite.suite) == *name)
+                    .copied()
+                    .with_context(|| format!("Unsupported cipher suite {}", name))
+            })
+            .collect::<anyhow::Result<_>>()?;
+    }
+    let usable = config.ciphersuites.iter().any(|suite| {
+        config
+            .versions
+            .iter()
+            .any(|&version| suite.usable_for_version(version))
+    });
+    if !usable {
+        anyhow::bail!("None of tls.cipher_suites is usable with tls.min_version");
+    }
+
+    if let Some(ticketer) = ticketer(setting)? {
+        config.ticketer = ticketer;
+    }
+    config.session_storage = if setting.session_cache_size > 0 {
+        ServerSessionMemoryCache::new(setting.session_cache_size)
+    } else {
+        Arc::new(NoServerSessionStorage {})
+    };
+    if let Some(path) = &setting.key_log_file {
+        config.key_log = Arc::new(KeyLogFile::open(path)?);
+    }
+    Ok(())
+}
+
+/// Session secrets appended to a file, in the NSS key log format
+#[derive(Debug)]
+pub struct KeyLogFile(Mutex<File>);
+
+impl KeyLogFile {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .with_context(|| format!("Could not open key log file {}", path.display()))?;
+        warn!("Writing TLS secrets to {}", path.display());
+        Ok(Self(Mutex::new(file)))
+    }
+
+    pub fn write(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let line = format!("{} {} {}\n", label, hex(client_random), hex(secret));
+        if let Err(e) = self.0.lock().unwrap().write_all(line.as_bytes()) {
+            warn!("Could not write key log: {}", e);
+        }
+    }
+}
+
+impl KeyLog for KeyLogFile {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        self.write(label, client_random, secret);
+    }
+}
+
+pub fn client_verifier(setting: &ClientAuthSetting) -> anyhow::Result<Arc<dyn ClientCertVerifier>> {
+    let roots = || -> anyhow::Result<RootCertStore> {
+        let mut roots = RootCertStore::empty();
+        for cert in client_ca_certs(setting)? {
+            roots
+                .add(&cert)
+                .map_err(|e| anyhow::anyhow!("Invalid client CA certificate: {:?}", e))?;
+        }
+        Ok(roots)
+    };
+    Ok(match setting.mode {
+        ClientAuthMode::None => NoClientAuth::new(),
+        ClientAuthMode::Optional => AllowAnyAnonymousOrAuthenticatedClient::new(roots()?),
+        ClientAuthMode::Required => AllowAnyAuthenticatedClient::new(roots()?),
+    })
+}
+
+/// CAs verifying the client certificates
+pub fn client_ca_certs(setting: &ClientAuthSetting) -> anyhow::Result<Vec<Certificate>> {
+    let ca_file = setting
+        .ca_file
+        .as_ref()
+        .context("tls.client_auth.ca_file is needed to verify client certificates")?;
+    load_certs(ca_file)
+}
+
+/// Config of the listener, replaced when certificates are reloaded while
+/// established connections keep the one of their handshake
+#[derive(Clone)]
+pub struct SharedConfig(Arc<RwLock<Arc<ServerConfig>>>);
+
+impl SharedConfig {
+    pub fn new(config: ServerConfig) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(config))))
+    }
+
+    pub fn get(&self) -> Arc<ServerConfig> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn set(&self, config: ServerConfig) {
+        *self.0.write().unwrap() = Arc::new(config);
+    }
+}
+
 pub struct TlsAcceptor {
-    config: Arc<ServerConfig>,
+    config: SharedConfig,
     incoming: AddrIncoming,
 }
 
 impl TlsAcceptor {
-    pub fn new(config: ServerConfig, incoming: AddrIncoming) -> TlsAcceptor {
-        TlsAcceptor {
-            config: Arc::new(config),
-            incoming,
-        }
+    pub fn new(config: SharedConfig, incoming: AddrIncoming) -> TlsAcceptor {
+        TlsAcceptor { config, incoming }
     }
 }
 
@@ -123,7 +333,7 @@ impl Accept for TlsAcceptor {
     ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
         let pin = self.get_mut();
         match ready!(Pin::new(&mut pin.incoming).poll_accept(cx)) {
-            Some(Ok(sock)) => Poll::Ready(Some(Ok(TlsStream::new(sock, pin.config.clone())))),
+            Some(Ok(sock)) => Poll::Ready(Some(Ok(TlsStream::new(sock, pin.config.get())))),
             Some(Err(e)) => Poll::Ready(Some(Err(e))),
             None => Poll::Ready(None),
         }
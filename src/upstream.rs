@@ -0,0 +1,1022 @@
+use crate::{
+    connector::{self, HttpClient},
+    settings::{
+        CircuitBreakerSetting, ConnectionPoolSetting, HashKey, HealthCheckSetting,
+        OutlierDetectionSetting, Policy, UpstreamPoolSetting, UpstreamProtocol, UpstreamSetting,
+    },
+};
+use anyhow::Context;
+use hyper::{
+    header,
+    http::uri::{Authority, Scheme},
+    HeaderMap, Uri, Version,
+};
+use rand::Rng;
+use std::{
+    cmp::Ordering,
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt,
+    hash::{Hash, Hasher},
+    net::IpAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{self, AtomicBool, AtomicUsize},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{info, warn};
+
+/// Prefix of unix socket upstream addresses, e.g. `unix:/run/app.sock` or
+/// `unix:/run/app.sock:/prefix`
+const UNIX_PREFIX: &str = "unix:";
+
+/// Points placed on the hash ring for every unit of upstream weight
+const RING_POINTS_PER_WEIGHT: u32 = 100;
+
+/// Named upstream pools, plus the implicit single upstream pools created for
+/// `proxy_pass` addresses that do not name a pool
+pub struct Upstreams {
+    pools: HashMap<String, Arc<UpstreamPool>>,
+}
+
+impl Upstreams {
+    /// Pools with their own TLS settings but no connection pool use
+    /// `connection_pool` for their connections
+    pub fn new(
+        settings: Vec<UpstreamPoolSetting>,
+        connection_pool: &ConnectionPoolSetting,
+    ) -> anyhow::Result<Self> {
+        let pools = settings
+            .into_iter()
+            .map(|setting| {
+                let name = setting.name.clone();
+                let pool = UpstreamPool::new(setting, connection_pool)
+                    .with_context(|| format!("Invalid upstream pool {:?}", name))?;
+                Ok((name, Arc::new(pool)))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { pools })
+    }
+
+    /// Returns the pool named by the host of `proxy_pass` (e.g. `http://api`),
+    /// or a pool with `proxy_pass` as its only upstream.
+    pub fn resolve(&mut self, proxy_pass: &str) -> anyhow::Result<Arc<UpstreamPool>> {
+        if !proxy_pass.starts_with(UNIX_PREFIX) {
+            let uri: Uri = proxy_pass
+                .parse()
+                .with_context(|| format!("Invalid proxy_pass {:?}", proxy_pass))?;
+            if let Some(pool) = uri
+                .authority()
+                .filter(|authority| authority.port().is_none())
+                .and_then(|authority| self.pools.get(authority.host()))
+            {
+                return Ok(pool.clone());
+            }
+        }
+
+        if let Some(pool) = self.pools.get(proxy_pass) {
+            return Ok(pool.clone());
+        }
+        let pool = Arc::new(UpstreamPool::single(proxy_pass)?);
+        self.pools.insert(proxy_pass.to_owned(), pool.clone());
+        Ok(pool)
+    }
+
+    pub fn pools(&self) -> impl Iterator<Item = &Arc<UpstreamPool>> {
+        self.pools.values()
+    }
+}
+
+pub struct UpstreamPool {
+    upstreams: Vec<Arc<Upstream>>,
+    balancer: Balancer,
+    health_check: Option<HealthCheckSetting>,
+    protocol: UpstreamProtocol,
+    /// Client with connections used only by this pool
+    client: Option<HttpClient>,
+}
+
+impl UpstreamPool {
+    fn new(
+        setting: UpstreamPoolSetting,
+        connection_pool: &ConnectionPoolSetting,
+    ) -> anyhow::Result<Self> {
+        if setting.servers.is_empty() {
+            anyhow::bail!("Upstream pool has no servers");
+        }
+        let outlier_detection = setting.outlier_detection;
+        let circuit_breaker = setting.circuit_breaker;
+        let connection_pool = setting.connection_pool.as_ref().unwrap_or(connection_pool);
+        let tls = setting.tls.as_ref();
+        let protocol = setting.protocol;
+        let upstreams = setting
+            .servers
+            .into_iter()
+            .map(|server| {
+                let mut upstream =
+                    Upstream::new(server, outlier_detection.clone(), circuit_breaker.as_ref())?;
+                match protocol {
+                    UpstreamProtocol::H2 if !upstream.is_https() => {
+                        anyhow::bail!("Upstream {} must be https:// to use h2", upstream)
+                    }
+                    UpstreamProtocol::H2c if upstream.is_https() => {
+                        anyhow::bail!("Upstream {} must not be https:// to use h2c", upstream)
+                    }
+                    UpstreamProtocol::Http1 => {}
+                    // Requests carry the client's host as authority, so
+                    // they cannot tell the client which upstream to connect to
+                    protocol => {
+                        upstream.client = Some(connector::upstream_client(
+                            connection_pool,
+                            tls,
+                            protocol,
+                            upstream.base_uri()?,
+                        )?)
+                    }
+                }
+                Ok(Arc::new(upstream))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let balancer = Balancer::new(setting.policy, setting.hash_key, &upstreams);
+        let client = match (&setting.connection_pool, tls, protocol) {
+            (None, None, UpstreamProtocol::Http1) => None,
+            (_, tls, UpstreamProtocol::Http1) => Some(connector::client(
+                connection_pool,
+                tls,
+                UpstreamProtocol::Http1,
+            )?),
+            _ => None,
+        };
+        Ok(Self {
+            upstreams,
+            balancer,
+            health_check: setting.health_check,
+            protocol: setting.protocol,
+            client,
+        })
+    }
+
+    /// Pool with a single upstream, used for plain `proxy_pass` addresses
+    pub fn single(address: &str) -> anyhow::Result<Self> {
+        let setting = UpstreamPoolSetting {
+            name: address.to_owned(),
+            policy: Policy::default(),
+            hash_key: HashKey::default(),
+            servers: vec![UpstreamSetting {
+                address: address.to_owned(),
+                weight: 1,
+            }],
+            protocol: UpstreamProtocol::default(),
+            health_check: None,
+            outlier_detection: None,
+            circuit_breaker: None,
+            connection_pool: None,
+            tls: None,
+        };
+        Self::new(setting, &ConnectionPoolSetting::default())
+    }
+
+    /// HTTP version of the requests sent to this pool
+    pub fn version(&self) -> Version {
+        match self.protocol {
+            UpstreamProtocol::Http1 => Version::HTTP_11,
+            UpstreamProtocol::H2 | UpstreamProtocol::H2c => Version::HTTP_2,
+        }
+    }
+
+    pub fn upstreams(&self) -> &[Arc<Upstream>] {
+        &self.upstreams
+    }
+
+    pub fn health_check(&self) -> Option<&HealthCheckSetting> {
+        self.health_check.as_ref()
+    }
+
+    /// The pool's own client, if it does not use the shared one nor clients
+    /// of its upstreams
+    pub fn client(&self) -> Option<&HttpClient> {
+        self.client.as_ref()
+    }
+
+    /// Picks an available upstream for a request according to the pool's
+    /// policy, preferring the ones not in `tried`. Returns `None` if every
+    /// upstream is unavailable.
+    pub fn select(
+        &self,
+        client_ip: IpAddr,
+        headers: &HeaderMap,
+        tried: &[Arc<Upstream>],
+    ) -> Option<Arc<Upstream>> {
+        self.select_untried(client_ip, headers, tried).or_else(|| {
+            if tried.is_empty() {
+                None
+            } else {
+                self.select_untried(client_ip, headers, &[])
+            }
+        })
+    }
+
+    fn select_untried(
+        &self,
+        client_ip: IpAddr,
+        headers: &HeaderMap,
+        tried: &[Arc<Upstream>],
+    ) -> Option<Arc<Upstream>> {
+        let len = self.upstreams.len();
+        let available = |&i: &usize| {
+            let upstream = &self.upstreams[i];
+            upstream.is_available() && !tried.iter().any(|tried| Arc::ptr_eq(tried, upstream))
+        };
+
+        let index = match &self.balancer {
+            Balancer::RoundRobin { next } => {
+                let start = next.fetch_add(1, atomic::Ordering::Relaxed);
+                (0..len)
+                    .map(|offset| (start + offset) % len)
+                    .find(available)
+            }
+            Balancer::WeightedRoundRobin { current } => {
+                // Smooth weighted round robin as done by nginx
+                let mut current = current.lock().unwrap();
+                let mut total = 0;
+                let mut best = None;
+                for i in (0..len).filter(available) {
+                    current[i] += self.upstreams[i].weight as i64;
+                    total += self.upstreams[i].weight as i64;
+                    if best.is_none_or(|best| current[i] > current[best]) {
+                        best = Some(i);
+                    }
+                }
+                if let Some(best) = best {
+                    current[best] -= total;
+                }
+                best
+            }
+            Balancer::LeastConnections => (0..len)
+                .filter(available)
+                .min_by(|&a, &b| self.upstreams[a].compare_load(&self.upstreams[b])),
+            Balancer::RandomTwoChoices => {
+                let candidates = (0..len).filter(available).collect::<Vec<_>>();
+                match candidates.len() {
+                    0 => None,
+                    1 => Some(candidates[0]),
+                    n => {
+                        let mut rng = rand::thread_rng();
+                        let a = rng.gen_range(0..n);
+                        let b = (a + rng.gen_range(1..n)) % n;
+                        let (a, b) = (candidates[a], candidates[b]);
+                        match self.upstreams[a].compare_load(&self.upstreams[b]) {
+                            Ordering::Greater => Some(b),
+                            _ => Some(a),
+                        }
+                    }
+                }
+            }
+            Balancer::ConsistentHash { key, ring } => {
+                let mut hasher = DefaultHasher::new();
+                match key.value(headers) {
+                    Some(value) => value.hash(&mut hasher),
+                    None => client_ip.hash(&mut hasher),
+                }
+                let hash = hasher.finish();
+                // Walk the ring clockwise to the first available upstream
+                let point = ring.partition_point(|&(point, _)| point < hash);
+                (0..ring.len())
+                    .map(|offset| ring[(point + offset) % ring.len()].1)
+                    .find(available)
+            }
+        };
+        index.map(|index| self.upstreams[index].clone())
+    }
+}
+
+enum Balancer {
+    RoundRobin {
+        next: AtomicUsize,
+    },
+    WeightedRoundRobin {
+        current: Mutex<Vec<i64>>,
+    },
+    LeastConnections,
+    RandomTwoChoices,
+    ConsistentHash {
+        key: HashKey,
+        ring: Vec<(u64, usize)>,
+    },
+}
+
+impl Balancer {
+    fn new(policy: Policy, key: HashKey, upstreams: &[Arc<Upstream>]) -> Self {
+        match policy {
+            Policy::RoundRobin => Balancer::RoundRobin {
+                next: AtomicUsize::new(0),
+            },
+            Policy::WeightedRoundRobin => Balancer::WeightedRoundRobin {
+                current: Mutex::new(vec![0; upstreams.len()]),
+            },
+            Policy::LeastConnections => Balancer::LeastConnections,
+            Policy::RandomTwoChoices => Balancer::RandomTwoChoices,
+            Policy::ConsistentHash => {
+                let mut ring = vec![];
+                for (i, upstream) in upstreams.iter().enumerate() {
+                    for point in 0..upstream.weight * RING_POINTS_PER_WEIGHT {
+                        let mut hasher = DefaultHasher::new();
+                        (upstream.authority.as_str(), point).hash(&mut hasher);
+                        ring.push((hasher.finish(), i));
+                    }
+                }
+                ring.sort_unstable();
+                Balancer::ConsistentHash { key, ring }
+            }
+        }
+    }
+}
+
+impl HashKey {
+    /// Value of the request header or cookie used as hash key, `None` if
+    /// the key is the client ip or the request does not have it
+    fn value<'a>(&self, headers: &'a HeaderMap) -> Option<&'a [u8]> {
+        match self {
+            HashKey::ClientIp => None,
+            HashKey::Header(name) => headers.get(name.as_str()).map(|value| value.as_bytes()),
+            HashKey::Cookie(name) => headers
+                .get_all(header::COOKIE)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|cookies| cookies.split(';'))
+                .filter_map(|cookie| cookie.trim().split_once('='))
+                .find(|(cookie_name, _)| cookie_name == name)
+                .map(|(_, value)| value.as_bytes()),
+        }
+    }
+}
+
+pub struct Upstream {
+    scheme: Scheme,
+    authority: Authority,
+    /// Prepended to the request paths
+    path_prefix: String,
+    /// Socket of a unix socket upstream, whose authority encodes it
+    unix_socket: Option<PathBuf>,
+    weight: u32,
+    in_flight: AtomicUsize,
+    healthy: AtomicBool,
+    outlier: Option<Outlier>,
+    circuit_breaker: Option<CircuitBreaker>,
+    /// Client connected only to this upstream, for HTTP/2
+    client: Option<HttpClient>,
+}
+
+struct Outlier {
+    setting: OutlierDetectionSetting,
+    state: Mutex<OutlierState>,
+}
+
+#[derive(Default)]
+struct OutlierState {
+    consecutive_errors: u32,
+    ejections: u32,
+    /// Set while ejected, and after that while half open until a request
+    /// succeeds
+    ejected_until: Option<Instant>,
+    /// Whether the single request let through while half open is in flight
+    probing: bool,
+}
+
+struct CircuitBreaker {
+    semaphore: Arc<Semaphore>,
+    pending: AtomicUsize,
+    max_pending: usize,
+    pending_timeout: Duration,
+}
+
+impl Upstream {
+    fn new(
+        setting: UpstreamSetting,
+        outlier_detection: Option<OutlierDetectionSetting>,
+        circuit_breaker: Option<&CircuitBreakerSetting>,
+    ) -> anyhow::Result<Self> {
+        if setting.weight == 0 {
+            anyhow::bail!("Upstream {:?} has zero weight", setting.address);
+        }
+
+        let (scheme, authority, path_prefix, unix_socket) =
+            match setting.address.strip_prefix(UNIX_PREFIX) {
+                Some(address) => {
+                    let (socket, prefix) = match address.find(":/") {
+                        Some(end) => (&address[..end], &address[end + 1..]),
+                        None => (address, ""),
+                    };
+                    if socket.is_empty() {
+                        anyhow::bail!("Upstream address {:?} has no socket", setting.address);
+                    }
+                    let socket = PathBuf::from(socket);
+                    (
+                        connector::UNIX_SCHEME.parse()?,
+                        connector::unix_authority(&socket).parse()?,
+                        prefix.trim_end_matches('/').to_owned(),
+                        Some(socket),
+                    )
+                }
+                None => {
+                    let parts = setting
+                        .address
+                        .parse::<Uri>()
+                        .with_context(|| format!("Invalid upstream address {:?}", setting.address))?
+                        .into_parts();
+                    let authority = parts.authority.with_context(|| {
+                        format!("Upstream address {:?} has no host", setting.address)
+                    })?;
+                    (
+                        parts.scheme.unwrap_or(Scheme::HTTP),
+                        authority,
+                        String::new(),
+                        None,
+                    )
+                }
+            };
+
+        Ok(Self {
+            scheme,
+            authority,
+            path_prefix,
+            unix_socket,
+            weight: setting.weight,
+            in_flight: AtomicUsize::new(0),
+            healthy: AtomicBool::new(true),
+            outlier: outlier_detection.map(|setting| Outlier {
+                setting,
+                state: Mutex::new(OutlierState::default()),
+            }),
+            circuit_breaker: circuit_breaker.map(|setting| CircuitBreaker {
+                semaphore: Arc::new(Semaphore::new(setting.max_requests)),
+                pending: AtomicUsize::new(0),
+                max_pending: setting.max_pending,
+                pending_timeout: Duration::from_millis(setting.pending_timeout_ms),
+            }),
+            client: None,
+        })
+    }
+
+    /// Uri of `path_and_query` on this upstream. Upstreams with their own
+    /// client get `host` as authority, or else their own authority, which
+    /// is `localhost` for unix sockets.
+    pub fn uri(&self, host: Option<&str>, path_and_query: &str) -> anyhow::Result<Uri> {
+        let (scheme, authority) = match &self.client {
+            Some(_) => {
+                let scheme = if self.is_https() {
+                    Scheme::HTTPS
+                } else {
+                    Scheme::HTTP
+                };
+                let authority = match host.and_then(|host| host.parse().ok()) {
+                    Some(host) => host,
+                    None if self.is_unix() => Authority::from_static("localhost"),
+                    None => self.authority.clone(),
+                };
+                (scheme, authority)
+            }
+            None => (self.scheme.clone(), self.authority.clone()),
+        };
+        Ok(Uri::builder()
+            .scheme(scheme)
+            .authority(authority)
+            .path_and_query(format!("{}{}", self.path_prefix, path_and_query))
+            .build()?)
+    }
+
+    /// Uri connected to for this upstream
+    fn base_uri(&self) -> anyhow::Result<Uri> {
+        Ok(Uri::builder()
+            .scheme(self.scheme.clone())
+            .authority(self.authority.clone())
+            .path_and_query("/")
+            .build()?)
+    }
+
+    /// The upstream's own client, if it does not use the one of its pool
+    pub fn client(&self) -> Option<&HttpClient> {
+        self.client.as_ref()
+    }
+
+    pub fn is_https(&self) -> bool {
+        self.scheme == Scheme::HTTPS
+    }
+
+    pub fn is_unix(&self) -> bool {
+        self.unix_socket.is_some()
+    }
+
+    /// Whether requests can be sent to the upstream
+    pub fn is_available(&self) -> bool {
+        self.is_healthy() && !self.is_ejected()
+    }
+
+    /// Whether the upstream is ejected, or half open with its trial request
+    /// in flight
+    fn is_ejected(&self) -> bool {
+        self.outlier.as_ref().is_some_and(|outlier| {
+            let state = outlier.state.lock().unwrap();
+            state
+                .ejected_until
+                .is_some_and(|ejected_until| Instant::now() < ejected_until || state.probing)
+        })
+    }
+
+    /// Claims the trial request of a half open upstream, returning the end
+    /// of the ejection it follows. `Err` if the upstream is ejected or
+    /// another request has the trial.
+    fn start_probe(&self) -> Result<Option<Instant>, ()> {
+        let outlier = match &self.outlier {
+            Some(outlier) => outlier,
+            None => return Ok(None),
+        };
+        let mut state = outlier.state.lock().unwrap();
+        match state.ejected_until {
+            None => Ok(None),
+            Some(ejected_until) if Instant::now() < ejected_until || state.probing => Err(()),
+            Some(ejected_until) => {
+                state.probing = true;
+                Ok(Some(ejected_until))
+            }
+        }
+    }
+
+    /// Lets another request try the upstream if it is still half open after
+    /// the same ejection, the trial having ended without a result
+    fn end_probe(&self, ejected_until: Instant) {
+        if let Some(outlier) = &self.outlier {
+            let mut state = outlier.state.lock().unwrap();
+            if state.ejected_until == Some(ejected_until) {
+                state.probing = false;
+            }
+        }
+    }
+
+    /// Records a response which was not a 5xx for outlier detection
+    pub fn record_success(&self) {
+        if let Some(outlier) = &self.outlier {
+            let mut state = outlier.state.lock().unwrap();
+            state.consecutive_errors = 0;
+            if let Some(ejected_until) = state.ejected_until {
+                if Instant::now() >= ejected_until {
+                    state.ejected_until = None;
+                    state.ejections = 0;
+                    state.probing = false;
+                    info!("Upstream {} is no longer ejected", self);
+                }
+            }
+        }
+    }
+
+    /// Records a connect error or 5xx response for outlier detection
+    pub fn record_failure(&self) {
+        if let Some(outlier) = &self.outlier {
+            let mut state = outlier.state.lock().unwrap();
+            let now = Instant::now();
+            let half_open = match state.ejected_until {
+                // Requests sent before the ejection are not counted
+                Some(ejected_until) if now < ejected_until => return,
+                Some(_) => true,
+                None => false,
+            };
+
+            state.consecutive_errors += 1;
+            if half_open || state.consecutive_errors >= outlier.setting.consecutive_errors {
+                let ejection = Duration::from_millis(
+                    outlier
+                        .setting
+                        .base_ejection_ms
+                        .saturating_mul(1 << state.ejections.min(32))
+                        .min(outlier.setting.max_ejection_ms),
+                );
+                state.consecutive_errors = 0;
+                state.ejections += 1;
+                state.ejected_until = Some(now + ejection);
+                state.probing = false;
+                warn!("Ejecting upstream {} for {:?}", self, ejection);
+            }
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Records the result of an active health check
+    pub fn set_healthy(&self, healthy: bool) {
+        self.healthy.store(healthy, atomic::Ordering::Relaxed);
+    }
+
+    /// Counts a request as in flight until the returned guard is dropped.
+    /// Waits while the circuit breaker's request limit is reached, and
+    /// returns `None` if too many requests are already waiting, the wait
+    /// times out, or the upstream is half open and another request is
+    /// trying it.
+    pub async fn start_request(self: &Arc<Self>) -> Option<InFlightGuard> {
+        let permit = match &self.circuit_breaker {
+            Some(breaker) => match breaker.semaphore.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    if breaker.pending.fetch_add(1, atomic::Ordering::Relaxed)
+                        >= breaker.max_pending
+                    {
+                        breaker.pending.fetch_sub(1, atomic::Ordering::Relaxed);
+                        return None;
+                    }
+                    // Also left when the request is dropped while waiting
+                    let _pending = PendingGuard(&breaker.pending);
+                    let permit = breaker.semaphore.clone().acquire_owned();
+                    let permit = tokio::time::timeout(breaker.pending_timeout, permit).await;
+                    Some(permit.ok()?.ok()?)
+                }
+            },
+            None => None,
+        };
+        let probe = self.start_probe().ok()?;
+
+        self.in_flight.fetch_add(1, atomic::Ordering::Relaxed);
+        Some(InFlightGuard {
+            upstream: self.clone(),
+            probe,
+            _permit: permit,
+        })
+    }
+
+    /// Compares in flight requests relative to weight
+    fn compare_load(&self, other: &Upstream) -> Ordering {
+        let load = self.in_flight.load(atomic::Ordering::Relaxed) as u64 * other.weight as u64;
+        let other_load =
+            other.in_flight.load(atomic::Ordering::Relaxed) as u64 * self.weight as u64;
+        load.cmp(&other_load)
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.unix_socket {
+            Some(socket) => write!(f, "{}{}{}", UNIX_PREFIX, socket.display(), self.path_prefix),
+            None => write!(f, "{}://{}", self.scheme, self.authority),
+        }
+    }
+}
+
+struct PendingGuard<'a>(&'a AtomicUsize);
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, atomic::Ordering::Relaxed);
+    }
+}
+
+pub struct InFlightGuard {
+    upstream: Arc<Upstream>,
+    /// End of the ejection before this trial request of a half open
+    /// upstream
+    probe: Option<Instant>,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.upstream
+            .in_flight
+            .fetch_sub(1, atomic::Ordering::Relaxed);
+        if let Some(ejected_until) = self.probe {
+            self.upstream.end_probe(ejected_until);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::body::ResponseBody;
+    use hyper::{body::HttpBody, Body};
+
+    fn pool(policy: Policy, hash_key: HashKey, servers: &[(&str, u32)]) -> UpstreamPool {
+        let setting = UpstreamPoolSetting {
+            name: "test".to_owned(),
+            policy,
+            hash_key,
+            servers: servers
+                .iter()
+                .map(|&(address, weight)| UpstreamSetting {
+                    address: format!("http://{}", address),
+                    weight,
+                })
+                .collect(),
+            protocol: UpstreamProtocol::default(),
+            health_check: None,
+            outlier_detection: None,
+            circuit_breaker: None,
+            connection_pool: None,
+            tls: None,
+        };
+        UpstreamPool::new(setting, &ConnectionPoolSetting::default()).unwrap()
+    }
+
+    fn select(pool: &UpstreamPool, headers: &HeaderMap) -> String {
+        let upstream = pool
+            .select(IpAddr::from([127, 0, 0, 1]), headers, &[])
+            .unwrap();
+        upstream.authority.to_string()
+    }
+
+    fn picks(pool: &UpstreamPool, n: usize) -> Vec<String> {
+        (0..n).map(|_| select(pool, &HeaderMap::new())).collect()
+    }
+
+    #[test]
+    fn round_robin() {
+        let pool = pool(
+            Policy::RoundRobin,
+            HashKey::default(),
+            &[("a", 1), ("b", 1), ("c", 1)],
+        );
+        assert_eq!(picks(&pool, 6), ["a", "b", "c", "a", "b", "c"]);
+
+        // The turn of an unavailable upstream goes to the next one
+        pool.upstreams[1].set_healthy(false);
+        assert_eq!(picks(&pool, 6), ["a", "c", "c", "a", "c", "c"]);
+    }
+
+    #[test]
+    fn smooth_weighted_round_robin() {
+        let pool = pool(
+            Policy::WeightedRoundRobin,
+            HashKey::default(),
+            &[("a", 5), ("b", 1), ("c", 1)],
+        );
+        let expected = ["a", "a", "b", "a", "c", "a", "a"];
+        assert_eq!(picks(&pool, 7), expected);
+        assert_eq!(picks(&pool, 7), expected);
+    }
+
+    #[tokio::test]
+    async fn least_connections() {
+        let pool = pool(
+            Policy::LeastConnections,
+            HashKey::default(),
+            &[("a", 2), ("b", 1), ("c", 1)],
+        );
+        let _a = pool.upstreams[0].start_request().await.unwrap();
+        let c = pool.upstreams[2].start_request().await.unwrap();
+        assert_eq!(picks(&pool, 3), ["b", "b", "b"]);
+
+        // Half as loaded as b relative to its weight
+        let _b = pool.upstreams[1].start_request().await.unwrap();
+        assert_eq!(select(&pool, &HeaderMap::new()), "a");
+
+        let _a = pool.upstreams[0].start_request().await.unwrap();
+        drop(c);
+        assert_eq!(select(&pool, &HeaderMap::new()), "c");
+    }
+
+    #[tokio::test]
+    async fn random_two_choices() {
+        let pool = pool(
+            Policy::RandomTwoChoices,
+            HashKey::default(),
+            &[("a", 1), ("b", 1), ("c", 1)],
+        );
+        let _a = pool.upstreams[0].start_request().await.unwrap();
+        let _a = pool.upstreams[0].start_request().await.unwrap();
+        let _b = pool.upstreams[1].start_request().await.unwrap();
+        let picks = picks(&pool, 50);
+        assert!(picks.iter().all(|pick| pick != "a"));
+        assert!(picks.iter().any(|pick| pick == "c"));
+    }
+
+    #[test]
+    fn consistent_hash_keeps_keys_of_remaining_servers() {
+        let key = || HashKey::Header("x-user".to_owned());
+        let three = pool(
+            Policy::ConsistentHash,
+            key(),
+            &[("a", 1), ("b", 1), ("c", 1)],
+        );
+        let two = pool(Policy::ConsistentHash, key(), &[("a", 1), ("c", 1)]);
+
+        let mut moved = 0;
+        for user in 0..1000 {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-user", user.into());
+            let before = select(&three, &headers);
+            assert_eq!(select(&three, &headers), before);
+            let after = select(&two, &headers);
+            if before == "b" {
+                moved += 1;
+            } else {
+                assert_eq!(after, before);
+            }
+
+            // An unavailable server is skipped the same way
+            three.upstreams[1].set_healthy(false);
+            assert_eq!(select(&three, &headers), after);
+            three.upstreams[1].set_healthy(true);
+        }
+        // Each server gets about a third of the keys
+        assert!((200..500).contains(&moved), "{} keys moved", moved);
+    }
+
+    #[tokio::test]
+    async fn response_body_keeps_request_in_flight() {
+        let pool = pool(Policy::default(), HashKey::default(), &[("a", 1)]);
+        let upstream = &pool.upstreams[0];
+        let in_flight = || upstream.in_flight.load(atomic::Ordering::Relaxed);
+
+        let guard = upstream.start_request().await.unwrap();
+        let mut body = ResponseBody::new(Body::from("response"), guard);
+        assert_eq!(in_flight(), 1);
+        assert_eq!(body.data().await.unwrap().unwrap(), "response");
+        assert_eq!(in_flight(), 1);
+
+        drop(body);
+        assert_eq!(in_flight(), 0);
+    }
+
+    fn upstream(
+        outlier_detection: Option<OutlierDetectionSetting>,
+        circuit_breaker: Option<&CircuitBreakerSetting>,
+    ) -> Arc<Upstream> {
+        let setting = UpstreamSetting {
+            address: "http://a".to_owned(),
+            weight: 1,
+        };
+        Arc::new(Upstream::new(setting, outlier_detection, circuit_breaker).unwrap())
+    }
+
+    #[tokio::test]
+    async fn circuit_breaker() {
+        let upstream = upstream(
+            None,
+            Some(&CircuitBreakerSetting {
+                max_requests: 1,
+                max_pending: 1,
+                pending_timeout_ms: 200,
+            }),
+        );
+        let first = upstream.start_request().await.unwrap();
+
+        // Waits for the first request, and is rejected after the timeout
+        let pending = upstream.start_request();
+        futures::pin_mut!(pending);
+        assert!(futures::poll!(pending.as_mut()).is_pending());
+        assert!(upstream.start_request().await.is_none());
+        assert!(pending.await.is_none());
+
+        let pending = tokio::spawn({
+            let upstream = upstream.clone();
+            async move { upstream.start_request().await.is_some() }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        drop(first);
+        assert!(pending.await.unwrap());
+
+        // Dropped while waiting
+        let _second = upstream.start_request().await.unwrap();
+        let mut pending = Box::pin(upstream.start_request());
+        assert!(futures::poll!(pending.as_mut()).is_pending());
+        assert!(upstream.start_request().await.is_none());
+        drop(pending);
+        let mut pending = Box::pin(upstream.start_request());
+        assert!(futures::poll!(pending.as_mut()).is_pending());
+    }
+
+    #[tokio::test]
+    async fn outlier_ejection() {
+        let upstream = upstream(
+            Some(OutlierDetectionSetting {
+                consecutive_errors: 2,
+                base_ejection_ms: 50,
+                max_ejection_ms: 80,
+            }),
+            None,
+        );
+        upstream.record_failure();
+        upstream.record_success();
+        upstream.record_failure();
+        assert!(upstream.is_available());
+        upstream.record_failure();
+        assert!(!upstream.is_available());
+        assert!(upstream.start_request().await.is_none());
+
+        // Half open after the ejection, with a single trial request
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(upstream.is_available());
+        let trial = upstream.start_request().await.unwrap();
+        assert!(!upstream.is_available());
+        assert!(upstream.start_request().await.is_none());
+
+        // Ejected again by its failure for twice as long, bounded by the
+        // maximum
+        upstream.record_failure();
+        drop(trial);
+        assert!(!upstream.is_available());
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        assert!(!upstream.is_available());
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        assert!(upstream.is_available());
+
+        // A trial ending without a result lets another request try
+        drop(upstream.start_request().await.unwrap());
+        assert!(upstream.is_available());
+
+        // Restored by a successful trial
+        let trial = upstream.start_request().await.unwrap();
+        upstream.record_success();
+        assert!(upstream.is_available());
+        let other = upstream.start_request().await.unwrap();
+        drop((trial, other));
+        assert!(upstream.is_available());
+        upstream.record_failure();
+        assert!(upstream.is_available());
+    }
+
+    #[test]
+    fn http2_upstreams_get_client_host() {
+        let pool = |address: &str, protocol| {
+            let setting = UpstreamPoolSetting {
+                name: "test".to_owned(),
+                policy: Policy::default(),
+                hash_key: HashKey::default(),
+                servers: vec![UpstreamSetting {
+                    address: address.to_owned(),
+                    weight: 1,
+                }],
+                protocol,
+                health_check: None,
+                outlier_detection: None,
+                circuit_breaker: None,
+                connection_pool: None,
+                tls: None,
+            };
+            UpstreamPool::new(setting, &ConnectionPoolSetting::default()).unwrap()
+        };
+        let uri = |pool: &UpstreamPool, host| {
+            let upstream = &pool.upstreams[0];
+            upstream.uri(host, "/path?query").unwrap().to_string()
+        };
+
+        let http1 = pool("http://127.0.0.1:8000", UpstreamProtocol::Http1);
+        assert!(http1.upstreams[0].client().is_none());
+        assert_eq!(
+            uri(&http1, Some("a.localhost")),
+            "http://127.0.0.1:8000/path?query"
+        );
+
+        let h2c = pool("http://127.0.0.1:8000", UpstreamProtocol::H2c);
+        assert!(h2c.client().is_none());
+        assert!(h2c.upstreams[0].client().is_some());
+        assert_eq!(
+            uri(&h2c, Some("a.localhost:9443")),
+            "http://a.localhost:9443/path?query"
+        );
+        assert_eq!(uri(&h2c, None), "http://127.0.0.1:8000/path?query");
+        assert_eq!(
+            uri(&h2c, Some("not a host")),
+            "http://127.0.0.1:8000/path?query"
+        );
+
+        let unix = pool("unix:/run/app.sock:/prefix", UpstreamProtocol::H2c);
+        assert_eq!(
+            uri(&unix, Some("a.localhost")),
+            "http://a.localhost/prefix/path?query"
+        );
+        assert_eq!(uri(&unix, None), "http://localhost/prefix/path?query");
+
+        let h2 = pool("https://127.0.0.1:8443", UpstreamProtocol::H2);
+        assert_eq!(
+            uri(&h2, Some("a.localhost")),
+            "https://a.localhost/path?query"
+        );
+    }
+
+    #[test]
+    fn select_prefers_untried() {
+        let pool = pool(
+            Policy::RoundRobin,
+            HashKey::default(),
+            &[("a", 1), ("b", 1)],
+        );
+        let client_ip = IpAddr::from([127, 0, 0, 1]);
+        let headers = HeaderMap::new();
+        let tried = [pool.upstreams[0].clone()];
+        for _ in 0..4 {
+            let upstream = pool.select(client_ip, &headers, &tried).unwrap();
+            assert!(Arc::ptr_eq(&upstream, &pool.upstreams[1]));
+        }
+
+        // Tried again once every upstream was tried
+        let tried = [pool.upstreams[0].clone(), pool.upstreams[1].clone()];
+        assert!(pool.select(client_ip, &headers, &tried).is_some());
+
+        pool.upstreams[0].set_healthy(false);
+        pool.upstreams[1].set_healthy(false);
+        assert!(pool.select(client_ip, &headers, &[]).is_none());
+    }
+}
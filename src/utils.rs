@@ -12,3 +12,31 @@ where
     tokio::try_join!(stream_a_to_b, stream_b_to_a)?;
     Ok(())
 }
+
+/// Lowercase hex encoding of `bytes`
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decodes hex of either case, `None` if it is not valid
+pub fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+        })
+        .collect()
+}
+
+/// Writes `contents` to the file `name` of `dir`, returning its path
+#[cfg(test)]
+pub fn write_file(
+    dir: &std::path::Path,
+    name: &str,
+    contents: impl AsRef<[u8]>,
+) -> std::path::PathBuf {
+    let path = dir.join(name);
+    std::fs::write(&path, contents).unwrap();
+    path
+}